@@ -0,0 +1,550 @@
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    effect::Effect,
+    hid::topology::Topology,
+    thread::capellix::Colors,
+    zone::{zone_bytes, zone_str, Zone},
+};
+
+/// Name of the layer shared by clients that haven't selected one
+pub const DEFAULT_LAYER: &str = "default";
+
+/// Change to the LED colors of a layer
+#[derive(Debug, Clone)]
+pub enum Lighting {
+    /// A frame covering every LED, held until replaced
+    Colors(Colors),
+    /// Colors repeated across a zone and merged into the layer
+    Zone(Zone, Colors),
+    /// An effect rendered each color tick
+    Effect(Effect),
+}
+
+/// Compositor layer, as selected by the client drawing on it
+#[derive(Debug, Clone)]
+pub struct Layer {
+    pub name: String,
+    /// Layers with a higher priority are drawn over lower ones,
+    /// and newer layers over older ones of the same priority
+    pub priority: i16,
+    /// Zone the layer is restricted to, or None to cover every LED
+    pub mask: Option<Zone>,
+    /// Duration after the last change before the layer is removed,
+    /// or None to keep it until its connection closes
+    pub timeout: Option<Duration>,
+}
+
+impl Default for Layer {
+    fn default() -> Self {
+        Layer {
+            name: DEFAULT_LAYER.to_string(),
+            priority: 0,
+            mask: None,
+            timeout: None,
+        }
+    }
+}
+
+impl Layer {
+    /// Whether this is the shared layer, which is never removed when a connection closes
+    pub fn is_default(&self) -> bool {
+        self.name == DEFAULT_LAYER
+    }
+
+    /// Encode as `[name length, name..., priority (LE i16), mask, timeout (LE u32)]`,
+    /// where a mask of 0 covers every LED and 1 is followed by a zone,
+    /// and the timeout is in milliseconds with 0 meaning none
+    pub fn encode(&self) -> Vec<u8> {
+        [
+            &[self.name.len() as u8][..],
+            self.name.as_bytes(),
+            &self.priority.to_le_bytes()[..],
+            &match self.mask {
+                Some(zone) => [&[1][..], &zone.encode()[..]].concat(),
+                None => vec![0],
+            }[..],
+            &(self
+                .timeout
+                .map(|timeout| timeout.as_millis() as u32)
+                .unwrap_or_default())
+            .to_le_bytes()[..],
+        ]
+        .concat()
+    }
+}
+
+impl Display for Layer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{} priority {}", self.name, self.priority))?;
+
+        if let Some(mask) = self.mask {
+            f.write_fmt(format_args!(" mask {mask:}"))?;
+        }
+
+        if let Some(timeout) = self.timeout {
+            f.write_fmt(format_args!(" timeout {}", timeout.as_millis()))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Change sent to a controller's compositor
+#[derive(Debug, Clone)]
+pub enum LayerUpdate {
+    /// Draw on a layer, creating it if needed
+    Draw {
+        layer: Layer,
+        /// ID of the connection drawing, if any
+        owner: Option<u64>,
+        lighting: Lighting,
+    },
+    /// Remove the named layer
+    Clear(String),
+    /// Remove the layers held by a closed connection, except those waiting on a timeout
+    Release(u64),
+}
+
+#[derive(Debug)]
+struct LayerState {
+    layer: Layer,
+    owner: Option<u64>,
+    /// Colors set directly or by zone, or None where the layer is transparent
+    pixels: Vec<Option<[u8; 3]>>,
+    effect: Option<Effect>,
+    updated: Instant,
+}
+
+/// Stack of lighting layers owned by the main loop for a single controller,
+/// flattened into one frame before it's sent to the device
+#[derive(Debug)]
+pub struct Compositor {
+    /// Layers ordered from bottom to top
+    layers: Vec<LayerState>,
+    /// Time base for effects
+    started: Instant,
+}
+
+impl Default for Compositor {
+    fn default() -> Self {
+        Compositor {
+            layers: vec![],
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Compositor {
+    /// Apply a change, returning true if the composited frame may have changed
+    ///
+    /// `coolant_temp` is in degrees celsius.
+    pub fn apply(
+        &mut self,
+        update: LayerUpdate,
+        topology: &Topology,
+        coolant_temp: f32,
+    ) -> Result<bool> {
+        match update {
+            LayerUpdate::Draw {
+                layer,
+                owner,
+                lighting,
+            } => {
+                self.draw(layer, owner, lighting, topology, coolant_temp)?;
+                Ok(true)
+            }
+            LayerUpdate::Clear(name) => {
+                let count = self.layers.len();
+                self.layers.retain(|state| state.layer.name != name);
+                Ok(self.layers.len() != count)
+            }
+            LayerUpdate::Release(owner) => {
+                let count = self.layers.len();
+                self.layers
+                    .retain(|state| state.owner != Some(owner) || state.layer.timeout.is_some());
+                Ok(self.layers.len() != count)
+            }
+        }
+    }
+
+    fn draw(
+        &mut self,
+        layer: Layer,
+        owner: Option<u64>,
+        lighting: Lighting,
+        topology: &Topology,
+        coolant_temp: f32,
+    ) -> Result<()> {
+        let led_count = topology.led_count();
+        let time = self.time();
+
+        let range = match &lighting {
+            Lighting::Zone(zone, _) => Some(
+                zone.led_range(topology)
+                    .ok_or_else(|| anyhow!("Zone {zone:} is not present"))?,
+            ),
+            _ => None,
+        };
+
+        let index = match self
+            .layers
+            .iter()
+            .position(|state| state.layer.name == layer.name)
+        {
+            Some(index) => index,
+            None => {
+                self.layers.push(LayerState {
+                    layer: layer.clone(),
+                    owner: None,
+                    pixels: vec![None; led_count],
+                    effect: None,
+                    updated: Instant::now(),
+                });
+                self.layers.len() - 1
+            }
+        };
+
+        let state = &mut self.layers[index];
+        state.owner = if layer.is_default() { None } else { owner };
+        state.layer = layer;
+        state.updated = Instant::now();
+
+        match lighting {
+            Lighting::Colors(colors) => {
+                state.effect = None;
+                state.pixels = colors.into_iter().map(Some).collect();
+                state.pixels.resize(led_count, Some([0; 3]));
+            }
+            Lighting::Zone(_, colors) => {
+                // Zones are merged into the layer as shown, freezing a running effect
+                if let Some(effect) = state.effect.take() {
+                    state.pixels = effect
+                        .render(topology, time, coolant_temp)
+                        .into_iter()
+                        .map(Some)
+                        .collect();
+                }
+                state.pixels.resize(led_count, None);
+
+                let range = range.unwrap_or_default();
+                for (pixel, color) in state.pixels[range].iter_mut().zip(colors.iter().cycle()) {
+                    *pixel = Some(*color);
+                }
+            }
+            Lighting::Effect(effect) => state.effect = Some(effect),
+        }
+
+        // Stable, so layers of the same priority keep their creation order
+        self.layers.sort_by_key(|state| state.layer.priority);
+
+        Ok(())
+    }
+
+    /// Remove layers whose timeout has passed since they were last drawn on,
+    /// returning true if any were removed
+    pub fn expire(&mut self) -> bool {
+        let count = self.layers.len();
+        self.layers.retain(|state| match state.layer.timeout {
+            Some(timeout) => state.updated.elapsed() < timeout,
+            None => true,
+        });
+        self.layers.len() != count
+    }
+
+    /// Whether any layer is running an effect, and so needs a new frame each tick
+    pub fn animated(&self) -> bool {
+        self.layers.iter().any(|state| state.effect.is_some())
+    }
+
+    /// Flatten the layers into a frame, leaving LEDs no layer covers unlit
+    pub fn render(&self, topology: &Topology, coolant_temp: f32) -> Colors {
+        let time = self.time();
+        let mut frame = vec![[0; 3]; topology.led_count()];
+
+        for state in &self.layers {
+            let range = match state.layer.mask {
+                Some(zone) => match zone.led_range(topology) {
+                    Some(range) => range,
+                    None => continue,
+                },
+                None => 0..frame.len(),
+            };
+
+            let pixels = match &state.effect {
+                Some(effect) => effect
+                    .render(topology, time, coolant_temp)
+                    .into_iter()
+                    .map(Some)
+                    .collect(),
+                None => state.pixels.clone(),
+            };
+
+            for i in range {
+                if let Some(Some(pixel)) = pixels.get(i) {
+                    frame[i] = *pixel;
+                }
+            }
+        }
+
+        frame
+    }
+
+    fn time(&self) -> f32 {
+        self.started.elapsed().as_secs_f32()
+    }
+}
+
+/// Parse a layer name, limited to 255 bytes as its length is encoded in a byte
+pub fn layer_name_str(input: &str) -> nom::IResult<&str, &str> {
+    nom::combinator::verify(
+        nom::bytes::complete::take_while_m_n(1, u8::MAX as usize, |c: char| {
+            c.is_alphanumeric() || c == '-' || c == '_'
+        }),
+        |name: &str| name.len() <= u8::MAX as usize,
+    )(input)
+}
+
+/// Parse a layer in the form `<name> [priority <n>] [mask <zone>] [timeout <ms>]`
+pub fn layer_str(input: &str) -> nom::IResult<&str, Layer> {
+    let (input, name) = layer_name_str(input)?;
+    let (input, priority) = nom::combinator::opt(nom::sequence::preceded(
+        nom::sequence::tuple((
+            nom::character::complete::space1,
+            nom::bytes::complete::tag("priority"),
+            nom::character::complete::space1,
+        )),
+        nom::character::complete::i16,
+    ))(input)?;
+    let (input, mask) = nom::combinator::opt(nom::sequence::preceded(
+        nom::sequence::tuple((
+            nom::character::complete::space1,
+            nom::bytes::complete::tag("mask"),
+            nom::character::complete::space1,
+        )),
+        zone_str,
+    ))(input)?;
+    let (input, timeout) = nom::combinator::opt(nom::sequence::preceded(
+        nom::sequence::tuple((
+            nom::character::complete::space1,
+            nom::bytes::complete::tag("timeout"),
+            nom::character::complete::space1,
+        )),
+        nom::character::complete::u32,
+    ))(input)?;
+
+    Ok((
+        input,
+        Layer {
+            name: name.to_string(),
+            priority: priority.unwrap_or_default(),
+            mask,
+            timeout: timeout
+                .filter(|timeout| *timeout > 0)
+                .map(|timeout| Duration::from_millis(timeout as u64)),
+        },
+    ))
+}
+
+pub fn layer_bytes(input: &[u8]) -> nom::IResult<&[u8], Layer> {
+    let (input, name) = nom::combinator::map_res(
+        nom::multi::length_data(nom::number::complete::u8),
+        std::str::from_utf8,
+    )(input)?;
+    let (input, priority) = nom::number::complete::le_i16(input)?;
+    let (input, mask) = nom::branch::alt((
+        nom::combinator::value(None, nom::bytes::complete::tag([0])),
+        nom::combinator::map(
+            nom::sequence::preceded(nom::bytes::complete::tag([1]), zone_bytes),
+            Some,
+        ),
+    ))(input)?;
+    let (input, timeout) = nom::number::complete::le_u32(input)?;
+
+    Ok((
+        input,
+        Layer {
+            name: name.to_string(),
+            priority,
+            mask,
+            timeout: match timeout {
+                0 => None,
+                timeout => Some(Duration::from_millis(timeout as u64)),
+            },
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thread::pump_target::Fan;
+
+    const RED: [u8; 3] = [255, 0, 0];
+    const GREEN: [u8; 3] = [0, 255, 0];
+    const BLUE: [u8; 3] = [0, 0, 255];
+    const OFF: [u8; 3] = [0; 3];
+
+    fn draw(compositor: &mut Compositor, layer: Layer, lighting: Lighting) {
+        compositor
+            .apply(
+                LayerUpdate::Draw {
+                    layer,
+                    owner: None,
+                    lighting,
+                },
+                &Topology::with_led_counts(&[4, 2]),
+                30.0,
+            )
+            .unwrap();
+    }
+
+    fn layer(name: &str, priority: i16) -> Layer {
+        Layer {
+            name: name.to_string(),
+            priority,
+            ..Layer::default()
+        }
+    }
+
+    #[test]
+    fn draws_higher_priority_layers_on_top() {
+        let mut compositor = Compositor::default();
+        draw(
+            &mut compositor,
+            layer("alert", 10),
+            Lighting::Zone(Zone::Led(0), vec![RED]),
+        );
+        draw(
+            &mut compositor,
+            Layer::default(),
+            Lighting::Colors(vec![BLUE; 6]),
+        );
+        // Same priority as the default layer, but newer
+        draw(
+            &mut compositor,
+            layer("ambient", 0),
+            Lighting::Zone(Zone::Range { first: 0, last: 1 }, vec![GREEN]),
+        );
+
+        assert_eq!(
+            compositor.render(&Topology::with_led_counts(&[4, 2]), 30.0),
+            vec![RED, GREEN, BLUE, BLUE, BLUE, BLUE]
+        );
+    }
+
+    #[test]
+    fn restricts_layers_to_their_mask() {
+        let mut compositor = Compositor::default();
+        draw(
+            &mut compositor,
+            Layer {
+                mask: Some(Zone::Channel(Fan::Pump)),
+                ..layer("pump", 1)
+            },
+            Lighting::Colors(vec![RED; 6]),
+        );
+
+        assert_eq!(
+            compositor.render(&Topology::with_led_counts(&[4, 2]), 30.0),
+            vec![RED, RED, RED, RED, OFF, OFF]
+        );
+    }
+
+    #[test]
+    fn expires_layers_after_timeout() {
+        let mut compositor = Compositor::default();
+        draw(
+            &mut compositor,
+            Layer::default(),
+            Lighting::Colors(vec![BLUE; 6]),
+        );
+        draw(
+            &mut compositor,
+            Layer {
+                timeout: Some(Duration::from_millis(1)),
+                ..layer("flash", 1)
+            },
+            Lighting::Colors(vec![RED; 6]),
+        );
+        assert_eq!(
+            compositor.render(&Topology::with_led_counts(&[4, 2]), 30.0),
+            vec![RED; 6]
+        );
+
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(compositor.expire());
+        assert!(!compositor.expire());
+        assert_eq!(
+            compositor.render(&Topology::with_led_counts(&[4, 2]), 30.0),
+            vec![BLUE; 6]
+        );
+    }
+
+    #[test]
+    fn releases_layers_of_closed_connections() {
+        let mut compositor = Compositor::default();
+        for (layer, owner) in [
+            (Layer::default(), Some(1)),
+            (layer("owned", 1), Some(1)),
+            (
+                Layer {
+                    timeout: Some(Duration::from_secs(60)),
+                    ..layer("lingering", 2)
+                },
+                Some(1),
+            ),
+            (layer("other", 3), Some(2)),
+        ] {
+            let update = LayerUpdate::Draw {
+                layer,
+                owner,
+                lighting: Lighting::Colors(vec![RED; 6]),
+            };
+            compositor
+                .apply(update, &Topology::with_led_counts(&[4, 2]), 30.0)
+                .unwrap();
+        }
+
+        assert!(compositor
+            .apply(
+                LayerUpdate::Release(1),
+                &Topology::with_led_counts(&[4, 2]),
+                30.0
+            )
+            .unwrap());
+        let names = compositor
+            .layers
+            .iter()
+            .map(|state| state.layer.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![DEFAULT_LAYER, "lingering", "other"]);
+    }
+
+    #[test]
+    fn parses_layers() {
+        let (rest, layer) = layer_str("flash priority 5 mask pump timeout 500").unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(layer.name, "flash");
+        assert_eq!(layer.priority, 5);
+        assert_eq!(layer.mask, Some(Zone::Channel(Fan::Pump)));
+        assert_eq!(layer.timeout, Some(Duration::from_millis(500)));
+
+        let encoded = layer.encode();
+        let (rest, decoded) = layer_bytes(&encoded).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded.encode(), encoded);
+    }
+
+    #[test]
+    fn limits_layer_names_to_255_bytes() {
+        assert!(layer_name_str(&"a".repeat(255)).is_ok());
+        assert!(matches!(layer_name_str(&"a".repeat(256)), Ok((rest, _)) if rest == "a"));
+        assert!(layer_name_str(&"é".repeat(128)).is_err());
+    }
+}
@@ -0,0 +1,291 @@
+use std::{collections::BTreeMap, path::Path, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use pid_controller::PidParameters;
+use serde::Deserialize;
+
+use crate::{
+    curve::FanCurve,
+    effect::Effect,
+    hid::hardware::{HardwareProfile, HardwareSpeed, CURVE_POINTS_MAX},
+    output::Correction,
+    thread::pump_target::Fan,
+    zone::Zone,
+};
+
+/// Daemon configuration, loaded from a TOML file
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Config {
+    /// Behavior to program into the controller for use while the daemon isn't running
+    pub hardware: Option<HardwareConfig>,
+
+    /// Fan curves evaluated by the daemon against coolant temperature
+    #[serde(default)]
+    pub curves: Vec<CurveConfig>,
+
+    /// Gains used by channels following an RPM target
+    #[serde(default)]
+    pub rpm: RpmConfig,
+
+    /// Lighting effects rendered by the daemon at startup
+    #[serde(default)]
+    pub effects: Vec<EffectConfig>,
+
+    /// Color correction applied to LED output
+    #[serde(default)]
+    pub outputs: Vec<OutputConfig>,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let config = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&config)?)
+    }
+}
+
+/// Highest coolant temperature (°C) accepted in a hardware curve
+const CURVE_TEMP_MAX: f32 = 100.0;
+
+/// Hardware-mode fallback profile
+///
+/// ```toml
+/// [hardware]
+/// fixed_percent = 60
+/// color = [255, 0, 0]
+///
+/// [hardware.curves]
+/// pump = [[30.0, 70], [40.0, 100]]
+/// fan1 = [[25.0, 20], [35.0, 60], [40.0, 100]]
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct HardwareConfig {
+    /// Duty percentage for channels without a curve
+    #[serde(default = "HardwareConfig::default_fixed_percent")]
+    pub fixed_percent: u16,
+
+    /// Coolant temperature (°C) to duty percentage curves, keyed by channel name
+    #[serde(default)]
+    pub curves: BTreeMap<String, Vec<(f32, u16)>>,
+
+    /// Static lighting color
+    pub color: Option<[u8; 3]>,
+}
+
+impl HardwareConfig {
+    fn default_fixed_percent() -> u16 {
+        100
+    }
+
+    /// Build a hardware profile covering the given number of channels
+    pub fn profile(&self, channel_count: usize) -> Result<HardwareProfile> {
+        let mut speeds = vec![HardwareSpeed::Fixed(self.fixed_percent.min(100)); channel_count];
+
+        for (name, points) in &self.curves {
+            let channel = u8::from(Fan::from_str(name)?) as usize;
+            let speed = speeds
+                .get_mut(channel)
+                .ok_or_else(|| anyhow!("Curve for {name:} targets a missing channel"))?;
+
+            if points.len() < 2 || points.len() > CURVE_POINTS_MAX {
+                return Err(anyhow!(
+                    "Curve for {name:} must have between 2 and {CURVE_POINTS_MAX:} points"
+                ));
+            }
+
+            // Points are programmed as given, so they're checked rather than sorted or clamped
+            if let Some((temp, duty)) = points
+                .iter()
+                .find(|(temp, duty)| !(0.0..=CURVE_TEMP_MAX).contains(temp) || *duty > 100)
+            {
+                return Err(anyhow!(
+                    "Curve for {name:} has point ({temp:}, {duty:}) outside 0-{CURVE_TEMP_MAX:}°C or 0-100%"
+                ));
+            }
+            if points.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+                return Err(anyhow!(
+                    "Curve for {name:} must have strictly increasing temperatures"
+                ));
+            }
+
+            *speed = HardwareSpeed::Curve(
+                points
+                    .iter()
+                    .map(|(temp, duty)| ((temp * 10.0).round() as u16, *duty))
+                    .collect(),
+            );
+        }
+
+        Ok(HardwareProfile {
+            speeds,
+            color: self.color,
+        })
+    }
+}
+
+/// Software fan curve for a single channel
+///
+/// ```toml
+/// [[curves]]
+/// channel = "fan1"
+/// points = [[30.0, 20], [34.0, 60], [38.0, 100]]
+/// hysteresis = 1.0
+///
+/// [[curves]]
+/// controller = 1
+/// channel = "fan2"
+/// points = [[30.0, 30], [36.0, 100]]
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct CurveConfig {
+    /// Index of the controller the channel belongs to
+    #[serde(default)]
+    pub controller: usize,
+
+    /// Channel name, ex. pump or fan1
+    pub channel: String,
+
+    /// Coolant temperature (°C) to duty percentage points
+    pub points: Vec<(f32, u16)>,
+
+    /// Temperature drop (°C) required before lowering the duty
+    #[serde(default = "CurveConfig::default_hysteresis")]
+    pub hysteresis: f32,
+}
+
+impl CurveConfig {
+    fn default_hysteresis() -> f32 {
+        1.0
+    }
+
+    pub fn fan(&self) -> Result<Fan> {
+        Fan::from_str(&self.channel)
+    }
+
+    pub fn curve(&self) -> Result<FanCurve> {
+        if self.points.is_empty() {
+            return Err(anyhow!("Curve for {} has no points", self.channel));
+        }
+
+        Ok(FanCurve::new(self.points.clone(), self.hysteresis))
+    }
+}
+
+/// Lighting effect shown on a controller until a client sets colors or another effect
+///
+/// See [`Effect`] for the available effects and their parameters.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EffectConfig {
+    /// Index of the controller to light
+    #[serde(default)]
+    pub controller: usize,
+
+    #[serde(flatten)]
+    pub effect: Effect,
+}
+
+/// Color correction for a controller, or for one of its zones
+///
+/// ```toml
+/// [[outputs]]
+/// gamma = 2.2
+/// white_balance = [1.0, 0.9, 0.8]
+/// power_cap = 0.6
+///
+/// [[outputs]]
+/// zone = "fan1"
+/// brightness = 0.5
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct OutputConfig {
+    /// Index of the controller to correct
+    #[serde(default)]
+    pub controller: usize,
+
+    /// Zone to correct, ex. pump, fan1 or 0-28, or unset to correct the whole device
+    pub zone: Option<String>,
+
+    #[serde(flatten)]
+    pub correction: Correction,
+}
+
+impl OutputConfig {
+    pub fn zone(&self) -> Result<Option<Zone>> {
+        self.zone.as_deref().map(Zone::from_str).transpose()
+    }
+}
+
+/// PID gains for RPM targets, in duty percent per RPM of error
+///
+/// ```toml
+/// [rpm]
+/// proportional = 0.02
+/// integral = 0.05
+/// derivative = 0.0
+/// ```
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(default)]
+pub struct RpmConfig {
+    pub proportional: f32,
+    pub integral: f32,
+    pub derivative: f32,
+}
+
+impl Default for RpmConfig {
+    fn default() -> Self {
+        RpmConfig {
+            proportional: 0.02,
+            integral: 0.05,
+            derivative: 0.0,
+        }
+    }
+}
+
+impl From<RpmConfig> for PidParameters<f32> {
+    fn from(config: RpmConfig) -> Self {
+        PidParameters {
+            proportional_factor: config.proportional,
+            integral_factor: config.integral,
+            derivative_factor: config.derivative,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hardware(points: Vec<(f32, u16)>) -> HardwareConfig {
+        HardwareConfig {
+            fixed_percent: 60,
+            curves: BTreeMap::from([("fan1".to_string(), points)]),
+            color: None,
+        }
+    }
+
+    #[test]
+    fn converts_curves_to_tenths() {
+        let profile = hardware(vec![(25.0, 20), (35.5, 100)]).profile(3).unwrap();
+        assert_eq!(
+            profile.speeds,
+            vec![
+                HardwareSpeed::Fixed(60),
+                HardwareSpeed::Curve(vec![(250, 20), (355, 100)]),
+                HardwareSpeed::Fixed(60),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_curve_points() {
+        for points in [
+            vec![(35.0, 60), (25.0, 20)],
+            vec![(30.0, 20), (30.0, 60)],
+            vec![(-5.0, 20), (30.0, 60)],
+            vec![(30.0, 20), (150.0, 100)],
+            vec![(30.0, 20), (f32::NAN, 100)],
+            vec![(30.0, 20), (40.0, 120)],
+        ] {
+            assert!(hardware(points).profile(3).is_err());
+        }
+    }
+}
@@ -0,0 +1,111 @@
+/// Piecewise-linear coolant temperature to duty curve
+///
+/// Rising temperatures are followed immediately,
+/// while falling temperatures only take effect once they drop by more than the hysteresis,
+/// to avoid fans hunting around a curve point.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FanCurve {
+    /// (temperature in degrees celsius, duty percentage) points, sorted by temperature
+    points: Vec<(f32, u16)>,
+    /// Temperature drop in degrees celsius required before lowering the duty
+    hysteresis: f32,
+    /// Temperature the current duty was evaluated at
+    applied_temp: Option<f32>,
+}
+
+impl FanCurve {
+    pub fn new(mut points: Vec<(f32, u16)>, hysteresis: f32) -> Self {
+        points.sort_by(|(lhs, _), (rhs, _)| lhs.total_cmp(rhs));
+        FanCurve {
+            points,
+            hysteresis: hysteresis.max(0.0),
+            applied_temp: None,
+        }
+    }
+
+    /// Duty percentage for the given temperature, applying hysteresis
+    pub fn evaluate(&mut self, temp: f32) -> u16 {
+        let temp = match self.applied_temp {
+            Some(applied) if temp < applied && temp > applied - self.hysteresis => applied,
+            _ => temp,
+        };
+
+        self.applied_temp = Some(temp);
+        self.interpolate(temp)
+    }
+
+    /// Duty percentage for the given temperature, clamped to the first and last points
+    pub fn interpolate(&self, temp: f32) -> u16 {
+        let (first, last) = match (self.points.first(), self.points.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return 100,
+        };
+
+        if temp <= first.0 {
+            return first.1.min(100);
+        }
+
+        if temp >= last.0 {
+            return last.1.min(100);
+        }
+
+        let (lo, hi) = self
+            .points
+            .windows(2)
+            .map(|window| (window[0], window[1]))
+            .find(|((_, _), (hi_temp, _))| temp < *hi_temp)
+            .unwrap_or((*last, *last));
+
+        let t = if hi.0 > lo.0 {
+            (temp - lo.0) / (hi.0 - lo.0)
+        } else {
+            1.0
+        };
+
+        let duty = lo.1 as f32 + (hi.1 as f32 - lo.1 as f32) * t;
+        (duty.round() as u16).min(100)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn curve() -> FanCurve {
+        FanCurve::new(vec![(40.0, 100), (30.0, 40)], 2.0)
+    }
+
+    #[test]
+    fn interpolates_between_points() {
+        let curve = curve();
+        assert_eq!(curve.interpolate(25.0), 40);
+        assert_eq!(curve.interpolate(30.0), 40);
+        assert_eq!(curve.interpolate(35.0), 70);
+        assert_eq!(curve.interpolate(45.0), 100);
+    }
+
+    #[test]
+    fn empty_curve_runs_at_full_speed() {
+        assert_eq!(FanCurve::new(vec![], 0.0).interpolate(30.0), 100);
+    }
+
+    #[test]
+    fn follows_rising_temperatures() {
+        let mut curve = curve();
+        assert_eq!(curve.evaluate(34.0), 64);
+        assert_eq!(curve.evaluate(35.0), 70);
+    }
+
+    #[test]
+    fn holds_duty_within_hysteresis() {
+        let mut curve = curve();
+        assert_eq!(curve.evaluate(35.0), 70);
+        assert_eq!(curve.evaluate(33.5), 70);
+        assert_eq!(curve.evaluate(34.5), 70);
+
+        // A drop beyond the hysteresis is applied, and becomes the new reference
+        assert_eq!(curve.evaluate(33.0), 58);
+        assert_eq!(curve.evaluate(31.5), 58);
+        assert_eq!(curve.evaluate(30.0), 40);
+    }
+}
@@ -0,0 +1,471 @@
+use std::{f32::consts::TAU, fmt::Display, str::FromStr};
+
+use anyhow::{anyhow, Error, Result};
+use serde::Deserialize;
+
+use crate::{hid::topology::Topology, thread::capellix::Colors};
+
+pub const EFFECT_STATIC: u8 = 0;
+pub const EFFECT_BREATHING: u8 = 1;
+pub const EFFECT_RAINBOW: u8 = 2;
+pub const EFFECT_CHASE: u8 = 3;
+pub const EFFECT_COOLANT: u8 = 4;
+
+/// Longest effect period in seconds, as periods are encoded in milliseconds in a u16
+pub const MAX_PERIOD: f32 = u16::MAX as f32 / 1000.0;
+
+/// Lighting pattern rendered by the daemon each color tick
+///
+/// ```toml
+/// [[effects]]
+/// effect = "chase"
+/// color = [0, 255, 255]
+/// period = 1.5
+/// length = 8
+///
+/// [[effects]]
+/// controller = 1
+/// effect = "coolant"
+/// cold = [0, 0, 255]
+/// hot = [255, 0, 0]
+/// min = 28.0
+/// max = 38.0
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "effect", rename_all = "kebab-case")]
+pub enum Effect {
+    /// A single color on every LED
+    Static { color: [u8; 3] },
+    /// A single color fading in and out
+    Breathing {
+        color: [u8; 3],
+        /// Duration of a full cycle in seconds
+        #[serde(
+            default = "Effect::default_period",
+            deserialize_with = "period_deserialize"
+        )]
+        period: f32,
+    },
+    /// Hues spread around each channel and rotating over time
+    Rainbow {
+        /// Duration of a full rotation in seconds
+        #[serde(
+            default = "Effect::default_period",
+            deserialize_with = "period_deserialize"
+        )]
+        period: f32,
+    },
+    /// A lit segment with a fading tail running around each channel
+    Chase {
+        color: [u8; 3],
+        /// Duration of a full lap in seconds
+        #[serde(
+            default = "Effect::default_period",
+            deserialize_with = "period_deserialize"
+        )]
+        period: f32,
+        /// Number of LEDs in the segment
+        #[serde(default = "Effect::default_length")]
+        length: u8,
+    },
+    /// A blend between two colors following coolant temperature
+    Coolant {
+        #[serde(default = "Effect::default_cold")]
+        cold: [u8; 3],
+        #[serde(default = "Effect::default_hot")]
+        hot: [u8; 3],
+        /// Temperature in degrees celsius at which the cold color is shown
+        #[serde(default = "Effect::default_min")]
+        min: f32,
+        /// Temperature in degrees celsius at which the hot color is shown
+        #[serde(default = "Effect::default_max")]
+        max: f32,
+    },
+}
+
+impl Effect {
+    fn default_period() -> f32 {
+        4.0
+    }
+
+    fn default_length() -> u8 {
+        6
+    }
+
+    fn default_cold() -> [u8; 3] {
+        [0, 0, 255]
+    }
+
+    fn default_hot() -> [u8; 3] {
+        [255, 0, 0]
+    }
+
+    fn default_min() -> f32 {
+        30.0
+    }
+
+    fn default_max() -> f32 {
+        40.0
+    }
+
+    /// Render a frame covering every LED in the topology
+    ///
+    /// `time` is in seconds since the daemon started, and `coolant_temp` in degrees celsius.
+    pub fn render(&self, topology: &Topology, time: f32, coolant_temp: f32) -> Colors {
+        let mut colors = vec![[0; 3]; topology.led_count()];
+
+        for channel in 0..topology.channel_count() {
+            let leds = &mut colors[topology.led_range(channel)];
+            let count = leds.len() as f32;
+
+            for (i, led) in leds.iter_mut().enumerate() {
+                *led = match *self {
+                    Effect::Static { color } => color,
+                    Effect::Breathing { color, period } => {
+                        scale(color, 0.5 - 0.5 * (TAU * time / period).cos())
+                    }
+                    Effect::Rainbow { period } => hue(time / period + i as f32 / count),
+                    Effect::Chase {
+                        color,
+                        period,
+                        length,
+                    } => {
+                        let head = (time / period).fract() * count;
+                        let distance = (head - i as f32).rem_euclid(count);
+                        scale(color, 1.0 - distance / length.max(1) as f32)
+                    }
+                    Effect::Coolant {
+                        cold,
+                        hot,
+                        min,
+                        max,
+                    } => {
+                        let t = if max > min {
+                            (coolant_temp - min) / (max - min)
+                        } else {
+                            0.0
+                        };
+                        lerp(cold, hot, t)
+                    }
+                };
+            }
+        }
+
+        colors
+    }
+
+    /// Encode as `[kind, parameters...]`, with periods in milliseconds
+    /// and temperatures in tenths of a degree celsius
+    pub fn encode(&self) -> Vec<u8> {
+        let millis = |period: f32| ((period * 1000.0) as u16).to_le_bytes();
+        let tenths = |temp: f32| ((temp * 10.0) as u16).to_le_bytes();
+
+        match *self {
+            Effect::Static { color } => [&[EFFECT_STATIC][..], &color[..]].concat(),
+            Effect::Breathing { color, period } => {
+                [&[EFFECT_BREATHING][..], &color[..], &millis(period)[..]].concat()
+            }
+            Effect::Rainbow { period } => [&[EFFECT_RAINBOW][..], &millis(period)[..]].concat(),
+            Effect::Chase {
+                color,
+                period,
+                length,
+            } => [
+                &[EFFECT_CHASE][..],
+                &color[..],
+                &millis(period)[..],
+                &[length],
+            ]
+            .concat(),
+            Effect::Coolant {
+                cold,
+                hot,
+                min,
+                max,
+            } => [
+                &[EFFECT_COOLANT][..],
+                &cold[..],
+                &hot[..],
+                &tenths(min)[..],
+                &tenths(max)[..],
+            ]
+            .concat(),
+        }
+    }
+}
+
+impl Display for Effect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let hex = |[r, g, b]: [u8; 3]| format!("{r:02x}{g:02x}{b:02x}");
+
+        match *self {
+            Effect::Static { color } => f.write_fmt(format_args!("static {}", hex(color))),
+            Effect::Breathing { color, period } => {
+                f.write_fmt(format_args!("breathing {} {period:}", hex(color)))
+            }
+            Effect::Rainbow { period } => f.write_fmt(format_args!("rainbow {period:}")),
+            Effect::Chase {
+                color,
+                period,
+                length,
+            } => f.write_fmt(format_args!("chase {} {period:} {length:}", hex(color))),
+            Effect::Coolant {
+                cold,
+                hot,
+                min,
+                max,
+            } => f.write_fmt(format_args!(
+                "coolant {} {} {min:} {max:}",
+                hex(cold),
+                hex(hot)
+            )),
+        }
+    }
+}
+
+impl FromStr for Effect {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match effect_str(s.trim()) {
+            Ok((rest, effect)) if rest.trim().is_empty() => Ok(effect),
+            _ => Err(anyhow!("Invalid effect {s:?}")),
+        }
+    }
+}
+
+/// Parse a hex or comma-separated decimal color, ex. ff8000, #ff8000 or 255,128,0
+pub fn color_from_str(s: &str) -> Result<[u8; 3]> {
+    let components = match s.split(',').collect::<Vec<_>>()[..] {
+        [r, g, b] => [r.trim().parse()?, g.trim().parse()?, b.trim().parse()?],
+        [hex] => {
+            let hex = hex.trim_start_matches('#');
+            if hex.len() != 6 {
+                return Err(anyhow!("Invalid color {s:}"));
+            }
+            let value = u32::from_str_radix(hex, 16)?;
+            [(value >> 16) as u8, (value >> 8) as u8, value as u8]
+        }
+        _ => return Err(anyhow!("Invalid color {s:}")),
+    };
+
+    Ok(components)
+}
+
+/// Multiply each component by a factor between 0 and 1
+fn scale(color: [u8; 3], factor: f32) -> [u8; 3] {
+    let factor = factor.clamp(0.0, 1.0);
+    color.map(|c| (c as f32 * factor) as u8)
+}
+
+/// Blend between two colors by a factor between 0 and 1
+fn lerp(from: [u8; 3], to: [u8; 3], t: f32) -> [u8; 3] {
+    let t = t.clamp(0.0, 1.0);
+    [0, 1, 2].map(|i| (from[i] as f32 + (to[i] as f32 - from[i] as f32) * t) as u8)
+}
+
+/// Fully saturated color for a hue, where 0 and 1 are red
+fn hue(hue: f32) -> [u8; 3] {
+    let h = hue.rem_euclid(1.0) * 6.0;
+    let x = 1.0 - (h % 2.0 - 1.0).abs();
+    let (r, g, b) = match h as u8 {
+        0 => (1.0, x, 0.0),
+        1 => (x, 1.0, 0.0),
+        2 => (0.0, 1.0, x),
+        3 => (0.0, x, 1.0),
+        4 => (x, 0.0, 1.0),
+        _ => (1.0, 0.0, x),
+    };
+    [r, g, b].map(|c: f32| (c * 255.0) as u8)
+}
+
+pub fn color_param_str(input: &str) -> nom::IResult<&str, [u8; 3]> {
+    nom::combinator::map_res(
+        nom::sequence::preceded(
+            nom::character::complete::space1,
+            nom::bytes::complete::is_not(" \t\r\n"),
+        ),
+        color_from_str,
+    )(input)
+}
+
+pub fn float_param_str(input: &str) -> nom::IResult<&str, f32> {
+    nom::sequence::preceded(
+        nom::character::complete::space1,
+        nom::number::complete::float,
+    )(input)
+}
+
+/// Check that a period in seconds can be rendered and encoded
+pub fn validate_period(period: f32) -> Result<f32> {
+    if period > 0.0 && period <= MAX_PERIOD {
+        Ok(period)
+    } else {
+        Err(anyhow!(
+            "Effect period {period:} must be above 0 and at most {MAX_PERIOD:} seconds"
+        ))
+    }
+}
+
+fn period_deserialize<'de, D>(deserializer: D) -> Result<f32, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    validate_period(f32::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+fn period_param_str(input: &str) -> nom::IResult<&str, f32> {
+    nom::combinator::map_res(float_param_str, validate_period)(input)
+}
+
+/// Parse an effect in the form `<kind> [parameters...]`, ex. `chase 00ffff 1.5 8`
+///
+/// Trailing parameters may be omitted to use their defaults:
+///
+/// ```text
+/// static <color>
+/// breathing <color> [period]
+/// rainbow [period]
+/// chase <color> [period [length]]
+/// coolant [<cold> <hot> [<min> <max>]]
+/// ```
+pub fn effect_str(input: &str) -> nom::IResult<&str, Effect> {
+    nom::branch::alt((
+        effect_static_str,
+        effect_breathing_str,
+        effect_rainbow_str,
+        effect_chase_str,
+        effect_coolant_str,
+    ))(input)
+}
+
+fn effect_static_str(input: &str) -> nom::IResult<&str, Effect> {
+    let (input, _) = nom::bytes::complete::tag("static")(input)?;
+    let (input, color) = color_param_str(input)?;
+    Ok((input, Effect::Static { color }))
+}
+
+fn effect_breathing_str(input: &str) -> nom::IResult<&str, Effect> {
+    let (input, _) = nom::bytes::complete::tag("breathing")(input)?;
+    let (input, color) = color_param_str(input)?;
+    let (input, period) = nom::combinator::opt(period_param_str)(input)?;
+    Ok((
+        input,
+        Effect::Breathing {
+            color,
+            period: period.unwrap_or_else(Effect::default_period),
+        },
+    ))
+}
+
+fn effect_rainbow_str(input: &str) -> nom::IResult<&str, Effect> {
+    let (input, _) = nom::bytes::complete::tag("rainbow")(input)?;
+    let (input, period) = nom::combinator::opt(period_param_str)(input)?;
+    Ok((
+        input,
+        Effect::Rainbow {
+            period: period.unwrap_or_else(Effect::default_period),
+        },
+    ))
+}
+
+fn effect_chase_str(input: &str) -> nom::IResult<&str, Effect> {
+    let (input, _) = nom::bytes::complete::tag("chase")(input)?;
+    let (input, color) = color_param_str(input)?;
+    let (input, period) = nom::combinator::opt(period_param_str)(input)?;
+    let (input, length) = match period {
+        Some(_) => nom::combinator::opt(nom::combinator::map_res(
+            nom::sequence::preceded(
+                nom::character::complete::space1,
+                nom::character::complete::digit1,
+            ),
+            str::parse,
+        ))(input)?,
+        None => (input, None),
+    };
+    Ok((
+        input,
+        Effect::Chase {
+            color,
+            period: period.unwrap_or_else(Effect::default_period),
+            length: length.unwrap_or_else(Effect::default_length),
+        },
+    ))
+}
+
+fn effect_coolant_str(input: &str) -> nom::IResult<&str, Effect> {
+    let (input, _) = nom::bytes::complete::tag("coolant")(input)?;
+    let (input, colors) =
+        nom::combinator::opt(nom::sequence::pair(color_param_str, color_param_str))(input)?;
+    let (input, temps) = match colors {
+        Some(_) => {
+            nom::combinator::opt(nom::sequence::pair(float_param_str, float_param_str))(input)?
+        }
+        None => (input, None),
+    };
+
+    let (cold, hot) = colors.unwrap_or_else(|| (Effect::default_cold(), Effect::default_hot()));
+    let (min, max) = temps.unwrap_or_else(|| (Effect::default_min(), Effect::default_max()));
+    Ok((
+        input,
+        Effect::Coolant {
+            cold,
+            hot,
+            min,
+            max,
+        },
+    ))
+}
+
+fn color_bytes(input: &[u8]) -> nom::IResult<&[u8], [u8; 3]> {
+    nom::combinator::map(nom::multi::count(nom::number::complete::u8, 3), |color| {
+        [color[0], color[1], color[2]]
+    })(input)
+}
+
+/// Parse a period in milliseconds, which can't be 0
+fn seconds_bytes(input: &[u8]) -> nom::IResult<&[u8], f32> {
+    nom::combinator::map(
+        nom::combinator::verify(nom::number::complete::le_u16, |millis| *millis > 0),
+        |millis| millis as f32 / 1000.0,
+    )(input)
+}
+
+fn celsius_bytes(input: &[u8]) -> nom::IResult<&[u8], f32> {
+    nom::combinator::map(nom::number::complete::le_u16, |tenths| tenths as f32 / 10.0)(input)
+}
+
+pub fn effect_bytes(input: &[u8]) -> nom::IResult<&[u8], Effect> {
+    let (input, kind) = nom::number::complete::u8(input)?;
+    match kind {
+        EFFECT_STATIC => nom::combinator::map(color_bytes, |color| Effect::Static { color })(input),
+        EFFECT_BREATHING => nom::combinator::map(
+            nom::sequence::pair(color_bytes, seconds_bytes),
+            |(color, period)| Effect::Breathing { color, period },
+        )(input),
+        EFFECT_RAINBOW => {
+            nom::combinator::map(seconds_bytes, |period| Effect::Rainbow { period })(input)
+        }
+        EFFECT_CHASE => nom::combinator::map(
+            nom::sequence::tuple((color_bytes, seconds_bytes, nom::number::complete::u8)),
+            |(color, period, length)| Effect::Chase {
+                color,
+                period,
+                length,
+            },
+        )(input),
+        EFFECT_COOLANT => nom::combinator::map(
+            nom::sequence::tuple((color_bytes, color_bytes, celsius_bytes, celsius_bytes)),
+            |(cold, hot, min, max)| Effect::Coolant {
+                cold,
+                hot,
+                min,
+                max,
+            },
+        )(input),
+        _ => Err(nom::Err::Error(nom::error::Error {
+            input,
+            code: nom::error::ErrorKind::Tag,
+        })),
+    }
+}
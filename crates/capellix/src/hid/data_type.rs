@@ -0,0 +1,12 @@
+// Endpoint data types, prefixed to endpoint reads and writes
+pub const HW_SPEED_MODE: &[u8] = &[0x03, 0x00];
+pub const HW_SPEED_FIXED_PERCENT: &[u8] = &[0x04, 0x00];
+pub const HW_SPEED_CURVE_PERCENT: &[u8] = &[0x05, 0x00];
+pub const SPEEDS: &[u8] = &[0x06, 0x00];
+pub const SET_SPEEDS: &[u8] = &[0x07, 0x00];
+pub const CONNECTED_FANS: &[u8] = &[0x09, 0x00];
+pub const FAN_TYPES: &[u8] = &[0x0d, 0x00];
+pub const LED_COUNT: &[u8] = &[0x0f, 0x00];
+pub const TEMPS: &[u8] = &[0x10, 0x00];
+pub const DIRECT: &[u8] = &[0x12, 0x00];
+pub const HW_LIGHTING_MODE: &[u8] = &[0x7e, 0x20];
@@ -0,0 +1,70 @@
+use super::protocol::Request;
+
+/// Speed mode byte selecting a fixed duty percentage
+pub const SPEED_MODE_FIXED_PERCENT: u8 = 0x00;
+
+/// Speed mode byte selecting a temperature curve
+pub const SPEED_MODE_CURVE_PERCENT: u8 = 0x02;
+
+/// Maximum number of points the controller accepts per curve
+pub const CURVE_POINTS_MAX: usize = 7;
+
+/// Fan control used by a channel while the controller is in hardware mode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HardwareSpeed {
+    /// Fixed duty percentage
+    Fixed(u16),
+    /// Duty curve of (temperature in tenths of a degree, duty percentage) points
+    Curve(Vec<(u16, u16)>),
+}
+
+impl HardwareSpeed {
+    pub fn mode(&self) -> u8 {
+        match self {
+            HardwareSpeed::Fixed(_) => SPEED_MODE_FIXED_PERCENT,
+            HardwareSpeed::Curve(_) => SPEED_MODE_CURVE_PERCENT,
+        }
+    }
+
+    /// Duty written to the fixed-percent endpoint,
+    /// falling back to full speed for curve channels
+    pub fn fixed_percent(&self) -> u16 {
+        match self {
+            HardwareSpeed::Fixed(duty) => *duty,
+            HardwareSpeed::Curve(_) => 100,
+        }
+    }
+
+    /// Points written to the curve endpoint,
+    /// flattened to the fixed duty for fixed-percent channels
+    pub fn curve(&self) -> Vec<(u16, u16)> {
+        match self {
+            HardwareSpeed::Fixed(duty) => vec![(0, *duty), (1000, *duty)],
+            HardwareSpeed::Curve(points) => points.clone(),
+        }
+    }
+}
+
+/// Standalone behavior stored on the controller,
+/// used whenever it is in hardware mode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HardwareProfile {
+    pub speeds: Vec<HardwareSpeed>,
+    pub color: Option<[u8; 3]>,
+}
+
+impl HardwareProfile {
+    /// Requests needed to program this profile into the controller
+    pub fn requests(&self) -> Vec<Request> {
+        let mut requests = vec![Request::SetHardwareSpeeds(self.speeds.clone())];
+
+        if let Some(color) = self.color {
+            requests.push(Request::SetHardwareLighting {
+                color,
+                channel_count: self.speeds.len(),
+            });
+        }
+
+        requests
+    }
+}
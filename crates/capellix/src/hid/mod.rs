@@ -1,72 +1,123 @@
 pub mod command;
+pub mod data_type;
+pub mod hardware;
 pub mod mode;
+pub mod profile;
+pub mod protocol;
 pub mod request;
+pub mod selector;
+pub mod simulator;
 pub mod state;
+pub mod topology;
+pub mod transport;
+
+use std::sync::Arc;
 
 use anyhow::{anyhow, Result};
-use hidapi::{HidApi, HidDevice};
+use hidapi::HidApi;
 use log::{debug, info, warn};
+use parking_lot::Mutex;
+
+use self::{
+    profile::Profile,
+    protocol::{Request, Response, STATUS_OK},
+    selector::DeviceSelector,
+    simulator::Simulator,
+    transport::{ClosedTransport, HidapiTransport, Transport},
+};
 
 const REPORT_LENGTH: usize = 96;
 
 const HEADER_WRITE: &[u8] = &[0x08];
 
 pub const LED_COUNT_PUMP: usize = 29;
-pub const LED_COUNT_FAN: usize = 34;
-pub const LED_COUNT_TOTAL: usize = LED_COUNT_PUMP + LED_COUNT_FAN * 6;
+pub const LED_COUNT_FAN_QL: usize = 34;
+pub const LED_COUNT_FAN_LL: usize = 16;
+pub const LED_COUNT_FAN_SP: usize = 8;
+pub const LED_COUNT_FAN_ML: usize = 4;
 
 pub const VID: u16 = 0x1b1c;
 pub const PID: u16 = 0x0c1c;
 pub const INTERFACE_NUMBER: i32 = 0;
 
-/// Fixed-length report buffer, plus one byte for the report ID
-type Report = [u8; 1 + REPORT_LENGTH];
+/// Time in milliseconds to wait for the device to answer a command,
+/// so a device that stops responding never holds its handle indefinitely
+pub const READ_TIMEOUT_MS: i32 = 1000;
+
+/// Handle shared by the main loop with the watchdog and panic hook
+pub type SharedHid = Arc<Mutex<Hid>>;
 
 pub struct Hid {
-    pub api: HidApi,
-    pub device: HidDevice,
-    pub buffer: Report,
+    pub transport: Box<dyn Transport>,
+    /// Protocol profile used to encode requests and decode responses
+    pub profile: &'static Profile,
+    /// Report buffer sized to the profile's report length, plus one byte for the report ID
+    pub buffer: Vec<u8>,
+}
+
+impl Default for Hid {
+    fn default() -> Self {
+        Hid::with_transport(ClosedTransport)
+    }
 }
 
 impl Hid {
+    /// Open the first physical Commander Core found through hidapi
     pub fn new() -> Result<Self> {
-        let api = HidApi::new()?;
-
-        let device_info = api
-            .device_list()
-            .find(|device_info| {
-                device_info.vendor_id() == VID
-                    && device_info.product_id() == PID
-                    && device_info.interface_number() == INTERFACE_NUMBER
+        let mut hids = Hid::open_all(&[DeviceSelector::First])?;
+        Ok(hids.remove(0))
+    }
+
+    /// Open one physical Commander Core per selector, in order
+    ///
+    /// Devices are opened through a single shared hidapi context,
+    /// since hidapi only allows one to exist at a time.
+    pub fn open_all(selectors: &[DeviceSelector]) -> Result<Vec<Self>> {
+        let api = Arc::new(Mutex::new(HidApi::new()?));
+        let mut opened = Vec::<String>::new();
+
+        selectors
+            .iter()
+            .map(|selector| {
+                let transport = HidapiTransport::open(api.clone(), selector, &opened)?;
+                opened.push(transport.path().to_string());
+                Ok(Hid::with_transport(transport))
             })
-            .ok_or_else(|| anyhow!("Failed to find device"))?;
+            .collect()
+    }
 
-        info!(
-            "Found {} at {}",
-            device_info
-                .product_string()
-                .ok_or_else(|| anyhow!("Failed to fetch product string"))?,
-            device_info.path().to_string_lossy()
-        );
+    /// Create a handle backed by an in-process simulated Commander Core
+    pub fn simulated(simulator: Simulator) -> Self {
+        info!("Using simulated Commander Core");
+        Hid::with_transport(simulator)
+    }
 
-        let device = device_info
-            .open_device(&api)
-            .map_err(|_| anyhow!("Failed to open device"))?;
-        device.set_blocking_mode(true)?;
+    pub fn with_transport(transport: impl Transport + 'static) -> Self {
+        let profile = Profile::latest();
+        Hid {
+            transport: Box::new(transport),
+            profile,
+            buffer: vec![0x00; 1 + profile.report.length],
+        }
+    }
 
-        let buffer = [0x00; 1 + REPORT_LENGTH];
+    /// Switch to the given protocol profile, resizing the report buffer to match
+    pub fn set_profile(&mut self, profile: &'static Profile) {
+        self.profile = profile;
+        self.buffer = vec![0x00; 1 + profile.report.length];
+    }
 
-        Ok(Hid {
-            api,
-            device,
-            buffer,
-        })
+    /// Reopen the device after a disconnect, resetting the report buffer
+    pub fn reopen(&mut self) -> Result<()> {
+        self.transport.reopen()?;
+        self.buffer.fill(0);
+        Ok(())
     }
 
     /// Discard any pending read packets to ensure the write-read cycle syncs up
     pub fn flush_read(&mut self, timeout: i32) -> Result<()> {
         info!("Flushing HID read buffer");
-        self.device.read_timeout(&mut self.buffer, timeout)?;
+        self.transport.read_timeout(&mut self.buffer, timeout)?;
         debug!("Flushed {:02x?}", &self.buffer);
         Ok(())
     }
@@ -84,7 +135,14 @@ impl Hid {
     /// Read from the HID device into the report buffer
     pub fn read(&mut self) -> Result<()> {
         // Receive response
-        self.device.read(&mut self.buffer)?;
+        let len = self
+            .transport
+            .read_timeout(&mut self.buffer, READ_TIMEOUT_MS)?;
+        if len == 0 {
+            return Err(anyhow!(
+                "No response from the device within {READ_TIMEOUT_MS:}ms"
+            ));
+        }
         debug!("Received {:02x?}", &self.buffer);
         Ok(())
     }
@@ -92,7 +150,7 @@ impl Hid {
     /// Populate the report buffer and send to the HID device
     pub fn write(&mut self, command: &[u8]) -> Result<()> {
         self.buffer(HEADER_WRITE, command);
-        self.device.write(&self.buffer)?;
+        self.transport.write(&self.buffer)?;
         debug!("Sent {:02x?}", &self.buffer);
         Ok(())
     }
@@ -127,6 +185,42 @@ impl Hid {
         }
         Ok(())
     }
+
+    /// Send a typed request without reading back any responses
+    ///
+    /// Used when restoring hardware mode after a stall or panic,
+    /// where waiting on a device that may never answer would hang the restore.
+    pub fn send_unacknowledged(&mut self, request: &Request) -> Result<()> {
+        for command in request.encode(self.profile) {
+            self.buffer.fill(0);
+            self.write(&command)?;
+        }
+        Ok(())
+    }
+
+    /// Send a typed request, decoding the response to its final command
+    pub fn send(&mut self, request: &Request) -> Result<Response> {
+        let commands = request.encode(self.profile);
+        let last = commands.len() - 1;
+
+        for (i, command) in commands.iter().enumerate() {
+            self.command(command)?;
+
+            if i < last && self.buffer[2] != STATUS_OK {
+                warn!(
+                    "Command {:02x} returned status {:02x}",
+                    command[0], self.buffer[2]
+                );
+            }
+        }
+
+        match request.decode_response(self.profile, &self.buffer)? {
+            Response::Error { command, status } => Err(anyhow!(
+                "{request:} failed: command {command:02x} returned status {status:02x}"
+            )),
+            response => Ok(response),
+        }
+    }
 }
 
 /// Parse the given number of windows into u16s in both big and little endian,
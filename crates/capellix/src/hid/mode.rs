@@ -5,6 +5,7 @@ pub const FAN_TYPES: &[u8] = &[0x1e];
 pub const LED_COUNT: &[u8] = &[0x20];
 pub const GET_TEMP: &[u8] = &[0x21];
 pub const DIRECT: &[u8] = &[0x22];
-pub const HW_SPEED_MODE: &[u8] = &[0x60];
-pub const HW_SPEED_FIXED_PERCENT: &[u8] = &[0x61];
+pub const HW_SPEED_MODE: &[u8] = &[0x60, 0x6d];
+pub const HW_SPEED_FIXED_PERCENT: &[u8] = &[0x61, 0x6d];
+pub const HW_SPEED_CURVE_PERCENT: &[u8] = &[0x62, 0x6d];
 pub const HW_LIGHTING_MODE: &[u8] = &[0x64, 0x6d];
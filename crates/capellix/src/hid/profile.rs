@@ -0,0 +1,216 @@
+use anyhow::{anyhow, Result};
+use log::warn;
+use semver::{Version, VersionReq};
+
+use super::{data_type, mode, protocol::FirmwareVersion, protocol::Request, REPORT_LENGTH};
+
+/// Controller endpoint, as opened by the set mode commands
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    /// Endpoint ID passed when opening the endpoint
+    pub id: &'static [u8],
+    /// Data type prefixed to reads and writes of the endpoint
+    pub data_type: &'static [u8],
+}
+
+/// Endpoints used by the daemon
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Endpoints {
+    pub speeds: Endpoint,
+    pub set_speeds: Endpoint,
+    pub connected_fans: Endpoint,
+    pub fan_types: Endpoint,
+    pub led_count: Endpoint,
+    pub temps: Endpoint,
+    pub direct: Endpoint,
+    pub hw_speed_mode: Endpoint,
+    pub hw_speed_fixed_percent: Endpoint,
+    pub hw_speed_curve_percent: Endpoint,
+    pub hw_lighting_mode: Endpoint,
+}
+
+/// Size and framing of HID reports
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ReportLayout {
+    /// Report length, excluding the report ID
+    pub length: usize,
+}
+
+impl ReportLayout {
+    /// Bytes available for data in the first report of a write, after the command and length header
+    pub const fn write_chunk_first(&self) -> usize {
+        self.length - 1 - 8
+    }
+
+    /// Bytes available for data in each continuation report of a write
+    pub const fn write_chunk_continue(&self) -> usize {
+        self.length - 1 - 2
+    }
+}
+
+/// Protocol description for a range of controller firmware versions
+#[derive(Debug)]
+pub struct Profile {
+    /// Name used when logging the selected profile
+    pub name: &'static str,
+    /// Semantic version requirement matched against the reported firmware version
+    pub versions: &'static str,
+    /// Requests sent once the profile is selected, before probing connected fans
+    pub init: &'static [Request],
+    pub endpoints: Endpoints,
+    pub report: ReportLayout,
+}
+
+/// Firmware 2.10.219, as captured from iCue
+///
+/// Assumed compatible with later 2.x releases.
+pub const PROFILE_2_10_219: Profile = Profile {
+    name: "2.10.219",
+    versions: "^2.10.219",
+    init: &[Request::EnableDirectLighting],
+    endpoints: Endpoints {
+        speeds: Endpoint {
+            id: mode::GET_SPEEDS,
+            data_type: data_type::SPEEDS,
+        },
+        set_speeds: Endpoint {
+            id: mode::SET_SPEEDS,
+            data_type: data_type::SET_SPEEDS,
+        },
+        connected_fans: Endpoint {
+            id: mode::CONNECTED_FANS,
+            data_type: data_type::CONNECTED_FANS,
+        },
+        fan_types: Endpoint {
+            id: mode::FAN_TYPES,
+            data_type: data_type::FAN_TYPES,
+        },
+        led_count: Endpoint {
+            id: mode::LED_COUNT,
+            data_type: data_type::LED_COUNT,
+        },
+        temps: Endpoint {
+            id: mode::GET_TEMP,
+            data_type: data_type::TEMPS,
+        },
+        direct: Endpoint {
+            id: mode::DIRECT,
+            data_type: data_type::DIRECT,
+        },
+        hw_speed_mode: Endpoint {
+            id: mode::HW_SPEED_MODE,
+            data_type: data_type::HW_SPEED_MODE,
+        },
+        hw_speed_fixed_percent: Endpoint {
+            id: mode::HW_SPEED_FIXED_PERCENT,
+            data_type: data_type::HW_SPEED_FIXED_PERCENT,
+        },
+        hw_speed_curve_percent: Endpoint {
+            id: mode::HW_SPEED_CURVE_PERCENT,
+            data_type: data_type::HW_SPEED_CURVE_PERCENT,
+        },
+        hw_lighting_mode: Endpoint {
+            id: mode::HW_LIGHTING_MODE,
+            data_type: data_type::HW_LIGHTING_MODE,
+        },
+    },
+    report: ReportLayout {
+        length: REPORT_LENGTH,
+    },
+};
+
+/// Known protocol profiles, ordered oldest to newest
+pub static PROFILES: &[Profile] = &[PROFILE_2_10_219];
+
+impl From<FirmwareVersion> for Version {
+    fn from(firmware: FirmwareVersion) -> Self {
+        Version::new(
+            firmware.major as u64,
+            firmware.minor as u64,
+            firmware.patch as u64,
+        )
+    }
+}
+
+impl Profile {
+    /// Newest known profile, used before the firmware version is known
+    pub fn latest() -> &'static Profile {
+        PROFILES.last().expect("No protocol profiles defined")
+    }
+
+    pub fn matches(&self, firmware: FirmwareVersion) -> bool {
+        VersionReq::parse(self.versions)
+            .expect("Invalid profile version requirement")
+            .matches(&firmware.into())
+    }
+
+    /// Find the profile matching the given firmware version
+    ///
+    /// If `allow_unrecognized` is set, unmatched versions fall back to the newest profile.
+    pub fn detect(firmware: FirmwareVersion, allow_unrecognized: bool) -> Result<&'static Profile> {
+        if let Some(profile) = PROFILES
+            .iter()
+            .rev()
+            .find(|profile| profile.matches(firmware))
+        {
+            return Ok(profile);
+        }
+
+        let supported = PROFILES
+            .iter()
+            .map(|profile| profile.versions)
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        if allow_unrecognized {
+            let profile = Profile::latest();
+            warn!(
+                "Firmware version {firmware:} does not match any supported range ({supported:}), falling back to profile {}",
+                profile.name
+            );
+            Ok(profile)
+        } else {
+            Err(anyhow!("Firmware version {firmware:} does not match any supported range ({supported:}), stopping.\nTo fall back to the newest protocol profile, pass the --unrecognized-firmware flag."))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(major: u8, minor: u8, patch: u8) -> FirmwareVersion {
+        FirmwareVersion {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    #[test]
+    fn matches_compatible_versions() {
+        assert!(PROFILE_2_10_219.matches(version(2, 10, 219)));
+        assert!(PROFILE_2_10_219.matches(version(2, 10, 255)));
+        assert!(PROFILE_2_10_219.matches(version(2, 11, 0)));
+    }
+
+    #[test]
+    fn rejects_incompatible_versions() {
+        assert!(!PROFILE_2_10_219.matches(version(2, 10, 218)));
+        assert!(!PROFILE_2_10_219.matches(version(2, 9, 255)));
+        assert!(!PROFILE_2_10_219.matches(version(3, 0, 0)));
+    }
+
+    #[test]
+    fn detects_profile() {
+        assert_eq!(
+            Profile::detect(version(2, 11, 0), false).unwrap().name,
+            "2.10.219"
+        );
+        assert!(Profile::detect(version(3, 0, 0), false).is_err());
+        assert_eq!(
+            Profile::detect(version(3, 0, 0), true).unwrap().name,
+            Profile::latest().name
+        );
+    }
+}
@@ -0,0 +1,516 @@
+use std::fmt::Display;
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+
+use super::{command, hardware::HardwareSpeed, profile::Profile, request, topology::FanType};
+
+/// Status byte reported for a successful command
+pub const STATUS_OK: u8 = 0x00;
+
+/// Controller firmware version, as reported by [`Request::GetFirmwareInfo`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct FirmwareVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+impl Display for FirmwareVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}.{}.{}", self.major, self.minor, self.patch))
+    }
+}
+
+/// Single temperature sensor reading
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Temperature {
+    /// Whether a sensor is present on this channel
+    pub connected: bool,
+    /// Temperature in tenths of a degree celsius
+    pub value: u16,
+}
+
+/// Typed controller request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    SetControllerState(u8),
+    GetFirmwareInfo,
+    EnableDirectLighting,
+    GetConnectedFans,
+    GetLedCounts,
+    SetFanTypes(Vec<Option<FanType>>),
+    GetSpeeds,
+    GetTemp,
+    SetSpeeds(Vec<u16>),
+    SetColors(Vec<[u8; 3]>),
+    SetHardwareSpeeds(Vec<HardwareSpeed>),
+    SetHardwareLighting {
+        color: [u8; 3],
+        channel_count: usize,
+    },
+}
+
+impl Display for Request {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Request::SetControllerState(state) => {
+                f.write_fmt(format_args!("SetControllerState({state:02x})"))
+            }
+            Request::GetFirmwareInfo => f.write_fmt(format_args!("GetFirmwareInfo")),
+            Request::EnableDirectLighting => f.write_fmt(format_args!("EnableDirectLighting")),
+            Request::GetConnectedFans => f.write_fmt(format_args!("GetConnectedFans")),
+            Request::GetLedCounts => f.write_fmt(format_args!("GetLedCounts")),
+            Request::SetFanTypes(fan_types) => {
+                f.write_fmt(format_args!("SetFanTypes({fan_types:?})"))
+            }
+            Request::GetSpeeds => f.write_fmt(format_args!("GetSpeeds")),
+            Request::GetTemp => f.write_fmt(format_args!("GetTemp")),
+            Request::SetSpeeds(speeds) => f.write_fmt(format_args!("SetSpeeds({speeds:?})")),
+            Request::SetColors(_) => f.write_fmt(format_args!("SetColors(...)")),
+            Request::SetHardwareSpeeds(speeds) => {
+                f.write_fmt(format_args!("SetHardwareSpeeds({speeds:?})"))
+            }
+            Request::SetHardwareLighting { color, .. } => {
+                f.write_fmt(format_args!("SetHardwareLighting({color:?})"))
+            }
+        }
+    }
+}
+
+impl Request {
+    /// Encode into the sequence of commands sent to a device speaking the given profile
+    pub fn encode(&self, profile: &Profile) -> Vec<Vec<u8>> {
+        match self {
+            Request::SetControllerState(state) => {
+                vec![command::set_controller_state(*state).to_vec()]
+            }
+            Request::GetFirmwareInfo => vec![command::GET_FIRMWARE_INFO.to_vec()],
+            Request::EnableDirectLighting => request::enable_direct_lighting(profile),
+            Request::GetConnectedFans => request::get_connected_fans(profile),
+            Request::GetLedCounts => request::get_led_counts(profile),
+            Request::SetFanTypes(fan_types) => request::set_fan_types(profile, fan_types),
+            Request::GetSpeeds => request::get_speeds(profile),
+            Request::GetTemp => request::get_temp(profile),
+            Request::SetSpeeds(speeds) => request::set_speeds(profile, speeds),
+            Request::SetColors(colors) => request::set_colors(profile, colors),
+            Request::SetHardwareSpeeds(speeds) => [
+                request::set_hardware_speed_modes(
+                    profile,
+                    &speeds.iter().map(HardwareSpeed::mode).collect::<Vec<_>>(),
+                ),
+                request::set_hardware_fixed_percent(
+                    profile,
+                    &speeds
+                        .iter()
+                        .map(HardwareSpeed::fixed_percent)
+                        .collect::<Vec<_>>(),
+                ),
+                request::set_hardware_curves(
+                    profile,
+                    &speeds.iter().map(HardwareSpeed::curve).collect::<Vec<_>>(),
+                ),
+            ]
+            .concat(),
+            Request::SetHardwareLighting {
+                color,
+                channel_count,
+            } => request::set_hardware_lighting(profile, *color, *channel_count),
+        }
+    }
+
+    /// Decode the report received in response to this request's final command
+    pub fn decode_response(&self, profile: &Profile, report: &[u8]) -> Result<Response> {
+        let (command, status, data) = decode_header(report)?;
+        if status != STATUS_OK {
+            return Ok(Response::Error { command, status });
+        }
+
+        match self {
+            Request::GetFirmwareInfo => decode_firmware_info(data),
+            Request::GetConnectedFans => decode_connected_fans(profile, data),
+            Request::GetLedCounts => decode_led_counts(profile, data),
+            Request::GetSpeeds => decode_speeds(profile, data),
+            Request::GetTemp => decode_temperatures(profile, data),
+            _ => Ok(Response::Ack),
+        }
+    }
+}
+
+/// Typed controller response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Ack,
+    Error {
+        command: u8,
+        status: u8,
+    },
+    FirmwareInfo(FirmwareVersion),
+    ConnectedFans(Vec<bool>),
+    /// LED count per channel, or `None` if the channel is disconnected
+    LedCounts(Vec<Option<u16>>),
+    Speeds(Vec<u16>),
+    Temperatures(Vec<Temperature>),
+    /// Endpoint read without a typed layout
+    Data {
+        data_type: [u8; 2],
+        data: Vec<u8>,
+    },
+}
+
+impl Response {
+    /// Encode into a report as sent by a device speaking the given profile in response to `command`
+    pub fn encode(&self, profile: &Profile, command: u8) -> Vec<u8> {
+        let endpoints = &profile.endpoints;
+
+        let (status, body) = match self {
+            Response::Ack => (STATUS_OK, vec![]),
+            Response::Error { status, .. } => (*status, vec![]),
+            Response::FirmwareInfo(version) => {
+                (STATUS_OK, vec![version.major, version.minor, version.patch])
+            }
+            Response::ConnectedFans(connected) => (
+                STATUS_OK,
+                [
+                    endpoints.connected_fans.data_type,
+                    &[connected.len() as u8],
+                    &connected
+                        .iter()
+                        .map(|connected| if *connected { 0x07 } else { 0x01 })
+                        .collect::<Vec<_>>(),
+                ]
+                .concat(),
+            ),
+            Response::LedCounts(counts) => (
+                STATUS_OK,
+                [
+                    endpoints.led_count.data_type,
+                    &[counts.len() as u8],
+                    &counts
+                        .iter()
+                        .flat_map(|count| match count {
+                            Some(count) => {
+                                let count = count.to_le_bytes();
+                                [0x02, 0x00, count[0], count[1]]
+                            }
+                            None => [0x03, 0x00, 0x00, 0x00],
+                        })
+                        .collect::<Vec<_>>(),
+                ]
+                .concat(),
+            ),
+            Response::Speeds(speeds) => (
+                STATUS_OK,
+                [
+                    endpoints.speeds.data_type,
+                    &[speeds.len() as u8],
+                    &speeds
+                        .iter()
+                        .flat_map(|s| s.to_le_bytes())
+                        .collect::<Vec<_>>(),
+                ]
+                .concat(),
+            ),
+            Response::Temperatures(temps) => (
+                STATUS_OK,
+                [
+                    endpoints.temps.data_type,
+                    &[temps.len() as u8],
+                    &temps
+                        .iter()
+                        .flat_map(|temp| {
+                            let value = temp.value.to_le_bytes();
+                            [if temp.connected { 0x00 } else { 0x01 }, value[0], value[1]]
+                        })
+                        .collect::<Vec<_>>(),
+                ]
+                .concat(),
+            ),
+            Response::Data { data_type, data } => (STATUS_OK, [&data_type[..], data].concat()),
+        };
+
+        let length = profile.report.length;
+        let mut report = vec![0x00; length];
+        report[1] = command;
+        report[2] = status;
+        let len = body.len().min(length - 3);
+        report[3..3 + len].copy_from_slice(&body[..len]);
+        report
+    }
+
+    pub fn into_firmware_info(self) -> Result<FirmwareVersion> {
+        match self {
+            Response::FirmwareInfo(version) => Ok(version),
+            other => Err(anyhow!("Expected firmware info, got {other:?}")),
+        }
+    }
+
+    pub fn into_connected_fans(self) -> Result<Vec<bool>> {
+        match self {
+            Response::ConnectedFans(connected) => Ok(connected),
+            other => Err(anyhow!("Expected connected fans, got {other:?}")),
+        }
+    }
+
+    pub fn into_led_counts(self) -> Result<Vec<Option<u16>>> {
+        match self {
+            Response::LedCounts(counts) => Ok(counts),
+            other => Err(anyhow!("Expected LED counts, got {other:?}")),
+        }
+    }
+
+    pub fn into_speeds(self) -> Result<Vec<u16>> {
+        match self {
+            Response::Speeds(speeds) => Ok(speeds),
+            other => Err(anyhow!("Expected speeds, got {other:?}")),
+        }
+    }
+
+    pub fn into_temperatures(self) -> Result<Vec<Temperature>> {
+        match self {
+            Response::Temperatures(temps) => Ok(temps),
+            other => Err(anyhow!("Expected temperatures, got {other:?}")),
+        }
+    }
+}
+
+/// Run a nom parser over a single named field, naming it in any resulting error
+fn field<'a, O>(
+    name: &str,
+    input: &'a [u8],
+    mut parser: impl FnMut(&'a [u8]) -> nom::IResult<&'a [u8], O>,
+) -> Result<(&'a [u8], O)> {
+    parser(input).map_err(|_| {
+        anyhow!(
+            "Invalid {name:} field: ran out of input at {:02x?}",
+            &input[..input.len().min(8)]
+        )
+    })
+}
+
+/// Consume a named field that must match the expected bytes
+fn expect_field<'a>(name: &str, input: &'a [u8], expected: &[u8]) -> Result<&'a [u8]> {
+    let (input, found) = field(name, input, nom::bytes::complete::take(expected.len()))?;
+    if found != expected {
+        return Err(anyhow!(
+            "Invalid {name:} field: expected {expected:02x?}, found {found:02x?}"
+        ));
+    }
+    Ok(input)
+}
+
+/// Split a response report into its command echo, status byte and data
+fn decode_header(report: &[u8]) -> Result<(u8, u8, &[u8])> {
+    let (input, _) = field("report ID", report, nom::number::complete::u8)?;
+    let (input, command) = field("command", input, nom::number::complete::u8)?;
+    let (input, status) = field("status", input, nom::number::complete::u8)?;
+    Ok((command, status, input))
+}
+
+fn decode_firmware_info(data: &[u8]) -> Result<Response> {
+    let (data, major) = field("firmware major", data, nom::number::complete::u8)?;
+    let (data, minor) = field("firmware minor", data, nom::number::complete::u8)?;
+    let (_, patch) = field("firmware patch", data, nom::number::complete::u8)?;
+    Ok(Response::FirmwareInfo(FirmwareVersion {
+        major,
+        minor,
+        patch,
+    }))
+}
+
+fn decode_connected_fans(profile: &Profile, data: &[u8]) -> Result<Response> {
+    let data = expect_field(
+        "connected fans data type",
+        data,
+        profile.endpoints.connected_fans.data_type,
+    )?;
+    let (data, count) = field("connected fan count", data, nom::number::complete::u8)?;
+    let (_, connected) = field(
+        "connected fans",
+        data,
+        nom::multi::count(nom::number::complete::u8, count as usize),
+    )?;
+    Ok(Response::ConnectedFans(
+        connected.into_iter().map(|status| status == 0x07).collect(),
+    ))
+}
+
+fn decode_led_counts(profile: &Profile, data: &[u8]) -> Result<Response> {
+    let data = expect_field(
+        "LED count data type",
+        data,
+        profile.endpoints.led_count.data_type,
+    )?;
+    let (data, count) = field("LED channel count", data, nom::number::complete::u8)?;
+    let (_, counts) = field(
+        "LED counts",
+        data,
+        nom::multi::count(
+            nom::sequence::pair(nom::number::complete::le_u16, nom::number::complete::le_u16),
+            count as usize,
+        ),
+    )?;
+    Ok(Response::LedCounts(
+        counts
+            .into_iter()
+            .map(|(status, count)| if status == 0x0002 { Some(count) } else { None })
+            .collect(),
+    ))
+}
+
+fn decode_speeds(profile: &Profile, data: &[u8]) -> Result<Response> {
+    let data = expect_field("speeds data type", data, profile.endpoints.speeds.data_type)?;
+    let (data, count) = field("speed count", data, nom::number::complete::u8)?;
+    let (_, speeds) = field(
+        "speeds",
+        data,
+        nom::multi::count(nom::number::complete::le_u16, count as usize),
+    )?;
+    Ok(Response::Speeds(speeds))
+}
+
+fn decode_temperatures(profile: &Profile, data: &[u8]) -> Result<Response> {
+    let data = expect_field(
+        "temperature data type",
+        data,
+        profile.endpoints.temps.data_type,
+    )?;
+    let (data, count) = field("temperature count", data, nom::number::complete::u8)?;
+    let (_, temps) = field(
+        "temperatures",
+        data,
+        nom::multi::count(
+            nom::sequence::pair(nom::number::complete::u8, nom::number::complete::le_u16),
+            count as usize,
+        ),
+    )?;
+
+    let temps = temps
+        .into_iter()
+        .map(|(status, value)| match status {
+            0x00 => Ok(Temperature {
+                connected: true,
+                value,
+            }),
+            0x01 => Ok(Temperature {
+                connected: false,
+                value,
+            }),
+            status => Err(anyhow!(
+                "Invalid temperature sensor status field: expected 00 or 01, found {status:02x}"
+            )),
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Response::Temperatures(temps))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hid::profile::PROFILE_2_10_219;
+
+    const PROFILE: &Profile = &PROFILE_2_10_219;
+
+    #[test]
+    fn decodes_encoded_responses() {
+        let speeds = Response::Speeds(vec![2700, 0, 1200]);
+        let report = speeds.encode(PROFILE, 0x08);
+        assert_eq!(
+            Request::GetSpeeds
+                .decode_response(PROFILE, &report)
+                .unwrap(),
+            speeds
+        );
+
+        let temps = Response::Temperatures(vec![
+            Temperature {
+                connected: true,
+                value: 312,
+            },
+            Temperature {
+                connected: false,
+                value: 0,
+            },
+        ]);
+        let report = temps.encode(PROFILE, 0x08);
+        assert_eq!(
+            Request::GetTemp.decode_response(PROFILE, &report).unwrap(),
+            temps
+        );
+    }
+
+    #[test]
+    fn decodes_error_status() {
+        let report = Response::Error {
+            command: 0x08,
+            status: 0x03,
+        }
+        .encode(PROFILE, 0x08);
+        assert_eq!(
+            Request::GetSpeeds
+                .decode_response(PROFILE, &report)
+                .unwrap(),
+            Response::Error {
+                command: 0x08,
+                status: 0x03
+            }
+        );
+    }
+
+    #[test]
+    fn names_wrong_data_type() {
+        // A temperature report answering a speed request
+        let report = Response::Temperatures(vec![]).encode(PROFILE, 0x08);
+        let error = Request::GetSpeeds
+            .decode_response(PROFILE, &report)
+            .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("Invalid speeds data type field"),
+            "{error:}"
+        );
+    }
+
+    #[test]
+    fn names_truncated_field() {
+        let report = Response::Speeds(vec![1000, 1000]).encode(PROFILE, 0x08);
+        // Cut the report short within the second speed
+        let error = Request::GetSpeeds
+            .decode_response(PROFILE, &report[..9])
+            .unwrap_err();
+        assert!(
+            error.to_string().starts_with("Invalid speeds field"),
+            "{error:}"
+        );
+
+        let error = Request::GetFirmwareInfo
+            .decode_response(PROFILE, &[0x00, 0x02])
+            .unwrap_err();
+        assert!(
+            error.to_string().starts_with("Invalid status field"),
+            "{error:}"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_sensor_status() {
+        let mut report = Response::Temperatures(vec![Temperature {
+            connected: true,
+            value: 312,
+        }])
+        .encode(PROFILE, 0x08);
+        // Status of the first sensor, after the header, data type and count
+        report[6] = 0x05;
+        let error = Request::GetTemp
+            .decode_response(PROFILE, &report)
+            .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("Invalid temperature sensor status field"),
+            "{error:}"
+        );
+    }
+}
@@ -1,7 +1,6 @@
-use super::LED_COUNT_TOTAL;
-
 use super::command::*;
-use super::mode::*;
+use super::profile::{Endpoint, Profile};
+use super::topology::FanType;
 
 /// Firmware 2.10.219 initialization sequence captured from iCue
 pub fn init() -> Vec<Vec<u8>> {
@@ -379,84 +378,174 @@ pub fn init() -> Vec<Vec<u8>> {
         .collect()
 }
 
-pub fn enable_direct_lighting() -> Vec<Vec<u8>> {
+pub fn enable_direct_lighting(profile: &Profile) -> Vec<Vec<u8>> {
     vec![
         RESET.to_vec(),
-        set_mode(DIRECT),
+        set_mode(profile.endpoints.direct.id),
         write(&[0x02, 0x00, 0x00, 0x00, 0x12]),
     ]
 }
 
-pub fn set_fan_types_6x_ql() -> Vec<Vec<u8>> {
-    vec![
-        RESET_DIRECT.to_vec(),
-        set_mode_direct(FAN_TYPES),
-        ACK_DIRECT.to_vec(),
-        write_direct(&[
-            0x11, 0x00, 0x00, 0x00, 0x0d, 0x00, 0x07, 0x01, 0x08, 0x01, 0x06, 0x01, 0x06, 0x01,
-            0x06, 0x01, 0x06, 0x01, 0x06, 0x01, 0x06,
-        ]),
-    ]
+/// Write a payload of the given data type to the endpoint open on `handle`,
+/// split across as many reports as needed
+fn write_chunked(profile: &Profile, handle: u8, data_type: &[u8], data: &[u8]) -> Vec<Vec<u8>> {
+    let len = ((data_type.len() + data.len()) as u16).to_le_bytes();
+
+    let payload = [data_type, data].concat();
+    let (first, rest) = payload.split_at(
+        payload
+            .len()
+            .min(profile.report.write_chunk_first() + data_type.len()),
+    );
+
+    let first = [&[0x06, handle, len[0], len[1], 0x00, 0x00], first].concat();
+
+    std::iter::once(first)
+        .chain(
+            rest.chunks(profile.report.write_chunk_continue())
+                .map(|chunk| [&[0x07, handle], chunk].concat()),
+        )
+        .collect()
 }
 
-pub fn get_speeds() -> Vec<Vec<u8>> {
-    vec![
-        RESET_DIRECT.to_vec(),
-        set_mode_direct(super::mode::GET_SPEEDS),
-        ACK_DIRECT.to_vec(),
-        READ_DIRECT.to_vec(),
+/// Open the given endpoint on the direct handle and write a payload to it
+fn write_endpoint_direct(profile: &Profile, endpoint: Endpoint, data: &[u8]) -> Vec<Vec<u8>> {
+    [
+        vec![
+            RESET_DIRECT.to_vec(),
+            set_mode_direct(endpoint.id),
+            ACK_DIRECT.to_vec(),
+        ],
+        write_chunked(profile, 0x01, endpoint.data_type, data),
     ]
+    .concat()
+}
+
+/// Write the given fan types, leaving channels without a known type unconfigured
+pub fn set_fan_types(profile: &Profile, fan_types: &[Option<FanType>]) -> Vec<Vec<u8>> {
+    let types = fan_types.iter().flat_map(|fan_type| match fan_type {
+        Some(fan_type) => [0x01, u8::from(*fan_type)],
+        None => [0x00, 0x00],
+    });
+
+    write_endpoint_direct(
+        profile,
+        profile.endpoints.fan_types,
+        &[&[fan_types.len() as u8][..], &types.collect::<Vec<_>>()].concat(),
+    )
 }
 
-pub fn get_temp() -> Vec<Vec<u8>> {
+/// Open the given endpoint on the direct handle and read it back
+fn read_direct(endpoint: Endpoint) -> Vec<Vec<u8>> {
     vec![
         RESET_DIRECT.to_vec(),
-        set_mode_direct(super::mode::GET_TEMP),
+        set_mode_direct(endpoint.id),
         ACK_DIRECT.to_vec(),
         READ_DIRECT.to_vec(),
     ]
 }
 
-pub fn set_speeds(speeds: [u16; 7]) -> Vec<Vec<u8>> {
-    let speeds = speeds.into_iter().map(u16::to_be_bytes);
+pub fn get_connected_fans(profile: &Profile) -> Vec<Vec<u8>> {
+    read_direct(profile.endpoints.connected_fans)
+}
 
-    vec![
-        RESET_DIRECT.to_vec(),
-        set_mode_direct(SET_SPEEDS).to_vec(),
-        ACK_DIRECT.to_vec(),
-        [
-            &[0x06, 0x01, 0x1f, 0x00, 0x00, 0x00, 0x07, 0x00, 0x07],
-            &speeds
-                .enumerate()
-                .flat_map(|(i, speed)| [i as u8, speed[0], speed[1], 0x00])
-                .collect::<Vec<_>>()[..],
-        ]
-        .concat(),
-    ]
+pub fn get_led_counts(profile: &Profile) -> Vec<Vec<u8>> {
+    read_direct(profile.endpoints.led_count)
 }
 
-pub fn set_colors(colors: [[u8; 3]; LED_COUNT_TOTAL]) -> Vec<Vec<u8>> {
-    let colors = colors.into_iter().flatten().collect::<Vec<_>>();
-    let (pump_colors, colors) = colors.split_at(87);
-    let (colors_1, colors) = colors.split_at(93);
-    let (colors_2, colors) = colors.split_at(93);
-    let (colors_3, colors) = colors.split_at(93);
-    let (colors_4, colors) = colors.split_at(93);
-    let (colors_5, colors) = colors.split_at(93);
-    let (colors_6, colors_7) = colors.split_at(93);
+pub fn get_speeds(profile: &Profile) -> Vec<Vec<u8>> {
+    read_direct(profile.endpoints.speeds)
+}
 
-    vec![
-        [
-            &[0x06, 0x00, 0xbd, 0x02, 0x00, 0x00, 0x12, 0x00],
-            pump_colors,
+pub fn get_temp(profile: &Profile) -> Vec<Vec<u8>> {
+    read_direct(profile.endpoints.temps)
+}
+
+pub fn set_speeds(profile: &Profile, speeds: &[u16]) -> Vec<Vec<u8>> {
+    let data = speeds
+        .iter()
+        .map(|speed| speed.to_be_bytes())
+        .enumerate()
+        .flat_map(|(i, speed)| [i as u8, speed[0], speed[1], 0x00]);
+
+    write_endpoint_direct(
+        profile,
+        profile.endpoints.set_speeds,
+        &[&[speeds.len() as u8][..], &data.collect::<Vec<_>>()].concat(),
+    )
+}
+
+/// Write the given colors to the direct lighting endpoint
+pub fn set_colors(profile: &Profile, colors: &[[u8; 3]]) -> Vec<Vec<u8>> {
+    write_chunked(
+        profile,
+        0x00,
+        profile.endpoints.direct.data_type,
+        &colors.iter().flatten().copied().collect::<Vec<_>>(),
+    )
+}
+
+/// Select fixed-percent or curve control for each channel while in hardware mode
+pub fn set_hardware_speed_modes(profile: &Profile, modes: &[u8]) -> Vec<Vec<u8>> {
+    write_endpoint_direct(
+        profile,
+        profile.endpoints.hw_speed_mode,
+        &[&[modes.len() as u8][..], modes].concat(),
+    )
+}
+
+/// Set the duty used by fixed-percent channels while in hardware mode
+pub fn set_hardware_fixed_percent(profile: &Profile, duties: &[u16]) -> Vec<Vec<u8>> {
+    let data = duties.iter().flat_map(|duty| duty.to_le_bytes());
+
+    write_endpoint_direct(
+        profile,
+        profile.endpoints.hw_speed_fixed_percent,
+        &[&[duties.len() as u8][..], &data.collect::<Vec<_>>()].concat(),
+    )
+}
+
+/// Set the temperature curves used by curve channels while in hardware mode
+///
+/// Each point is a (temperature in tenths of a degree, duty percentage) pair,
+/// evaluated against the coolant temperature sensor.
+pub fn set_hardware_curves(profile: &Profile, curves: &[Vec<(u16, u16)>]) -> Vec<Vec<u8>> {
+    let data = curves.iter().flat_map(|points| {
+        [0x00, 0x00, points.len() as u8].into_iter().chain(
+            points
+                .iter()
+                .flat_map(|(temp, duty)| [temp.to_le_bytes(), duty.to_le_bytes()])
+                .flatten(),
+        )
+    });
+
+    write_endpoint_direct(
+        profile,
+        profile.endpoints.hw_speed_curve_percent,
+        &[&[curves.len() as u8][..], &data.collect::<Vec<_>>()].concat(),
+    )
+}
+
+/// Set a static color on every channel while in hardware mode
+///
+/// Layout follows the static lighting packet captured from iCue,
+/// with the color and channel list substituted.
+pub fn set_hardware_lighting(
+    profile: &Profile,
+    color: [u8; 3],
+    channel_count: usize,
+) -> Vec<Vec<u8>> {
+    let channels = (0..channel_count as u8).collect::<Vec<_>>();
+
+    write_endpoint_direct(
+        profile,
+        profile.endpoints.hw_lighting_mode,
+        &[
+            &[0x09, 0x00, 0x00, 0x00, 0x01][..],
+            &color,
+            &[0x00, channel_count as u8],
+            &channels,
         ]
         .concat(),
-        [&[0x07, 0x00], colors_1].concat(),
-        [&[0x07, 0x00], colors_2].concat(),
-        [&[0x07, 0x00], colors_3].concat(),
-        [&[0x07, 0x00], colors_4].concat(),
-        [&[0x07, 0x00], colors_5].concat(),
-        [&[0x07, 0x00], colors_6].concat(),
-        [&[0x07, 0x00], colors_7].concat(),
-    ]
+    )
 }
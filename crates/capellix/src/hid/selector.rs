@@ -0,0 +1,49 @@
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::{Error, Result};
+use hidapi::DeviceInfo;
+
+/// Criteria used to pick a Commander Core out of the attached devices
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum DeviceSelector {
+    /// First matching device found
+    #[default]
+    First,
+    /// Device with the given USB serial number
+    Serial(String),
+    /// Device at the given hidraw path, ex. /dev/hidraw3
+    Path(String),
+}
+
+impl DeviceSelector {
+    pub fn matches(&self, device_info: &DeviceInfo) -> bool {
+        match self {
+            DeviceSelector::First => true,
+            DeviceSelector::Serial(serial) => device_info.serial_number() == Some(serial.as_str()),
+            DeviceSelector::Path(path) => device_info.path().to_string_lossy() == *path,
+        }
+    }
+}
+
+impl Display for DeviceSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceSelector::First => f.write_str("first device"),
+            DeviceSelector::Serial(serial) => f.write_fmt(format_args!("serial {serial:}")),
+            DeviceSelector::Path(path) => f.write_fmt(format_args!("path {path:}")),
+        }
+    }
+}
+
+/// Parses absolute paths as hidraw paths, and anything else as a serial number
+impl FromStr for DeviceSelector {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('/') {
+            Ok(DeviceSelector::Path(s.to_string()))
+        } else {
+            Ok(DeviceSelector::Serial(s.to_string()))
+        }
+    }
+}
@@ -0,0 +1,463 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use log::debug;
+use parking_lot::Mutex;
+
+use super::{
+    profile::{Endpoint, Profile},
+    protocol::{FirmwareVersion, Response, Temperature},
+    state,
+    topology::FanType,
+    transport::Transport,
+    LED_COUNT_FAN_QL, LED_COUNT_PUMP,
+};
+
+const CHANNEL_COUNT: usize = 7;
+
+const PUMP_MAX_RPM: f32 = 2700.0;
+const FAN_MAX_RPM: f32 = 1500.0;
+
+const STATUS_ERROR: u8 = 0x03;
+
+/// Observable state of a simulated Commander Core
+#[derive(Debug, Clone)]
+pub struct SimulatorState {
+    /// Whether the device is attached, with all I/O failing while unset
+    pub attached: bool,
+    /// Whether the device has stopped answering, leaving every read to time out
+    pub unresponsive: bool,
+    /// Firmware version reported by GET_FIRMWARE_INFO
+    pub firmware: FirmwareVersion,
+    /// Current controller state, either [`state::HARDWARE`] or [`state::SOFTWARE`]
+    pub controller_state: u8,
+    /// Coolant temperature in tenths of a degree celsius
+    pub coolant_temp: u16,
+    /// Whether the coolant temperature sensor reports as connected
+    pub temp_sensor_connected: bool,
+    /// Last duty percentage written to each channel
+    pub fan_targets: [u16; CHANNEL_COUNT],
+    /// Current speed of each channel in RPM
+    pub speeds: [u16; CHANNEL_COUNT],
+    /// Fan type byte for each channel, as written by the fan types endpoint
+    pub fan_types: [u8; CHANNEL_COUNT],
+    /// LED count for each channel, with zero marking a disconnected channel
+    pub led_counts: [u16; CHANNEL_COUNT],
+    /// Last RGB color buffer written to the direct lighting endpoint
+    pub colors: Vec<u8>,
+    /// Raw payloads written to hardware-mode endpoints, keyed by endpoint ID
+    pub hardware: Vec<([u8; 2], Vec<u8>)>,
+}
+
+impl Default for SimulatorState {
+    fn default() -> Self {
+        SimulatorState {
+            attached: true,
+            unresponsive: false,
+            firmware: FirmwareVersion {
+                major: 2,
+                minor: 10,
+                patch: 219,
+            },
+            controller_state: state::HARDWARE,
+            coolant_temp: 312,
+            temp_sensor_connected: true,
+            fan_targets: [100; CHANNEL_COUNT],
+            speeds: [0; CHANNEL_COUNT],
+            fan_types: [
+                FanType::Pump.into(),
+                FanType::Ql.into(),
+                FanType::Ql.into(),
+                FanType::Ql.into(),
+                FanType::Ql.into(),
+                FanType::Ql.into(),
+                FanType::Ql.into(),
+            ],
+            led_counts: [
+                LED_COUNT_PUMP as u16,
+                LED_COUNT_FAN_QL as u16,
+                LED_COUNT_FAN_QL as u16,
+                LED_COUNT_FAN_QL as u16,
+                LED_COUNT_FAN_QL as u16,
+                LED_COUNT_FAN_QL as u16,
+                LED_COUNT_FAN_QL as u16,
+            ],
+            colors: vec![],
+            hardware: vec![],
+        }
+    }
+}
+
+impl SimulatorState {
+    /// Move channel speeds a step toward their duty targets
+    fn step_speeds(&mut self) {
+        for (i, (speed, target)) in self.speeds.iter_mut().zip(self.fan_targets).enumerate() {
+            let max = if i == 0 { PUMP_MAX_RPM } else { FAN_MAX_RPM };
+            let target = max * target.min(100) as f32 / 100.0;
+            let current = *speed as f32;
+            *speed = (current + (target - current) * 0.25) as u16;
+        }
+    }
+
+    /// Move coolant temperature a step toward the equilibrium for the current fan duty
+    fn step_temp(&mut self) {
+        let duty = self.fan_targets[1..]
+            .iter()
+            .map(|t| t.min(&100))
+            .sum::<u16>() as f32
+            / (CHANNEL_COUNT - 1) as f32;
+        let target = 280.0 + (100.0 - duty) * 1.5;
+        let current = self.coolant_temp as f32;
+        self.coolant_temp = (current + (target - current) * 0.05) as u16;
+    }
+
+    /// Store a payload written to a hardware-mode endpoint
+    fn set_hardware(&mut self, endpoint: [u8; 2], data: Vec<u8>) {
+        match self.hardware.iter_mut().find(|(e, _)| *e == endpoint) {
+            Some((_, existing)) => *existing = data,
+            None => self.hardware.push((endpoint, data)),
+        }
+    }
+}
+
+/// In-flight multi-report write
+#[derive(Debug)]
+struct PendingWrite {
+    endpoint: [u8; 2],
+    remaining: usize,
+    data: Vec<u8>,
+}
+
+/// In-process Commander Core speaking the protocol profile matching its firmware version
+///
+/// Each written report queues a single response report, which is returned by the next read.
+#[derive(Debug)]
+pub struct Simulator {
+    state: Arc<Mutex<SimulatorState>>,
+    profile: &'static Profile,
+    endpoints: [Option<[u8; 2]>; 2],
+    pending_write: Option<PendingWrite>,
+    responses: VecDeque<Vec<u8>>,
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Simulator::new(SimulatorState::default())
+    }
+}
+
+impl Simulator {
+    pub fn new(state: SimulatorState) -> Self {
+        let profile = Profile::detect(state.firmware, true).unwrap_or_else(|_| Profile::latest());
+        Simulator {
+            state: Arc::new(Mutex::new(state)),
+            profile,
+            endpoints: Default::default(),
+            pending_write: None,
+            responses: VecDeque::new(),
+        }
+    }
+
+    /// Shared handle to the simulated device state, for inspection or fault injection
+    pub fn state(&self) -> Arc<Mutex<SimulatorState>> {
+        self.state.clone()
+    }
+
+    fn reply(&mut self, command: u8, response: Response) {
+        self.responses
+            .push_back(response.encode(self.profile, command));
+    }
+
+    fn handle(&mut self, command: &[u8]) {
+        let error = Response::Error {
+            command: command[0],
+            status: STATUS_ERROR,
+        };
+
+        match command[0] {
+            // Set controller state
+            0x01 => {
+                self.state.lock().controller_state = command[3];
+                self.reply(command[0], Response::Ack);
+            }
+            // Get info
+            0x02 => {
+                let response = if command[1] == 0x13 {
+                    Response::FirmwareInfo(self.state.lock().firmware)
+                } else {
+                    Response::Ack
+                };
+                self.reply(command[0], response);
+            }
+            // Close endpoint
+            0x05 => {
+                self.endpoints[command[2] as usize & 1] = None;
+                self.reply(command[0], Response::Ack);
+            }
+            // Open endpoint
+            0x0d => {
+                self.endpoints[command[1] as usize & 1] = Some([command[2], command[3]]);
+                self.reply(command[0], Response::Ack);
+            }
+            // Endpoint status
+            0x09 => self.reply(command[0], Response::Ack),
+            // Read endpoint
+            0x08 => match self.endpoints[command[1] as usize & 1] {
+                Some(endpoint) => {
+                    let response = self.read_endpoint(endpoint);
+                    self.reply(command[0], response);
+                }
+                None => self.reply(command[0], error),
+            },
+            // Begin write
+            0x06 => match self.endpoints[command[1] as usize & 1] {
+                Some(endpoint) => {
+                    let len = u16::from_le_bytes([command[2], command[3]]) as usize;
+                    self.pending_write = Some(PendingWrite {
+                        endpoint,
+                        remaining: len,
+                        data: vec![],
+                    });
+                    self.continue_write(&command[6..]);
+                    self.reply(command[0], Response::Ack);
+                }
+                None => self.reply(command[0], error),
+            },
+            // Continue write
+            0x07 => {
+                if self.pending_write.is_some() {
+                    self.continue_write(&command[2..]);
+                    self.reply(command[0], Response::Ack);
+                } else {
+                    self.reply(command[0], error);
+                }
+            }
+            _ => self.reply(command[0], error),
+        }
+    }
+
+    fn continue_write(&mut self, bytes: &[u8]) {
+        let pending = self.pending_write.as_mut().unwrap();
+        let len = pending.remaining.min(bytes.len());
+        pending.data.extend(&bytes[..len]);
+        pending.remaining -= len;
+
+        if pending.remaining == 0 {
+            let pending = self.pending_write.take().unwrap();
+            self.write_endpoint(pending.endpoint, pending.data);
+        }
+    }
+
+    /// Build the response to a read of the given endpoint
+    fn read_endpoint(&mut self, endpoint: [u8; 2]) -> Response {
+        let endpoints = &self.profile.endpoints;
+        let mut state = self.state.lock();
+
+        match endpoint {
+            endpoint if is_endpoint(endpoint, endpoints.speeds) => {
+                state.step_speeds();
+                Response::Speeds(state.speeds.to_vec())
+            }
+            endpoint if is_endpoint(endpoint, endpoints.temps) => {
+                state.step_temp();
+                Response::Temperatures(vec![Temperature {
+                    connected: state.temp_sensor_connected,
+                    value: state.coolant_temp,
+                }])
+            }
+            endpoint if is_endpoint(endpoint, endpoints.connected_fans) => {
+                Response::ConnectedFans(state.led_counts.iter().map(|count| *count > 0).collect())
+            }
+            endpoint if is_endpoint(endpoint, endpoints.led_count) => Response::LedCounts(
+                state
+                    .led_counts
+                    .iter()
+                    .map(|count| if *count > 0 { Some(*count) } else { None })
+                    .collect(),
+            ),
+            endpoint if is_endpoint(endpoint, endpoints.fan_types) => {
+                let types = state.fan_types.iter().flat_map(|ty| [0x01, *ty]);
+                let data_type = endpoints.fan_types.data_type;
+                Response::Data {
+                    data_type: [data_type[0], data_type[1]],
+                    data: [&[CHANNEL_COUNT as u8][..], &types.collect::<Vec<_>>()].concat(),
+                }
+            }
+            endpoint => match state.hardware.iter().find(|(e, _)| *e == endpoint) {
+                Some((_, data)) if data.len() >= 2 => Response::Data {
+                    data_type: [data[0], data[1]],
+                    data: data[2..].to_vec(),
+                },
+                _ => Response::Data {
+                    data_type: [0x00, 0x00],
+                    data: vec![],
+                },
+            },
+        }
+    }
+
+    /// Apply a completed write to the given endpoint
+    fn write_endpoint(&mut self, endpoint: [u8; 2], data: Vec<u8>) {
+        debug!("Simulator write to endpoint {endpoint:02x?}: {data:02x?}");
+
+        let endpoints = &self.profile.endpoints;
+        let mut state = self.state.lock();
+        let body = data.get(2..).unwrap_or_default();
+
+        match endpoint {
+            endpoint if is_endpoint(endpoint, endpoints.direct) => {
+                if !body.is_empty() {
+                    state.colors = body.to_vec();
+                }
+            }
+            endpoint if is_endpoint(endpoint, endpoints.fan_types) => {
+                for (i, ty) in body.iter().skip(2).step_by(2).enumerate() {
+                    if let Some(fan_type) = state.fan_types.get_mut(i) {
+                        *fan_type = *ty;
+                    }
+                }
+            }
+            endpoint if is_endpoint(endpoint, endpoints.set_speeds) => {
+                for entry in body.iter().skip(1).copied().collect::<Vec<_>>().chunks(4) {
+                    if let [channel, hi, lo, _] = *entry {
+                        if let Some(target) = state.fan_targets.get_mut(channel as usize) {
+                            *target = u16::from_be_bytes([hi, lo]);
+                        }
+                    }
+                }
+            }
+            endpoint => state.set_hardware(endpoint, data),
+        }
+    }
+}
+
+/// Whether an opened endpoint ID refers to the given profile endpoint
+fn is_endpoint(opened: [u8; 2], endpoint: Endpoint) -> bool {
+    let (id, rest) = opened.split_at(endpoint.id.len().min(2));
+    id == endpoint.id && rest.iter().all(|byte| *byte == 0x00)
+}
+
+impl Transport for Simulator {
+    fn write(&mut self, data: &[u8]) -> Result<usize> {
+        if !self.state.lock().attached {
+            return Err(anyhow!("Simulated device is detached"));
+        }
+
+        // Skip report ID and write header
+        let command = data
+            .get(2..)
+            .filter(|command| !command.is_empty())
+            .ok_or_else(|| anyhow!("Simulator received empty report"))?;
+        self.handle(command);
+        Ok(data.len())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if !self.state.lock().attached {
+            return Err(anyhow!("Simulated device is detached"));
+        }
+
+        let report = self
+            .responses
+            .pop_front()
+            .ok_or_else(|| anyhow!("Simulator read with no pending response"))?;
+        let len = report.len().min(buf.len());
+        buf[..len].copy_from_slice(&report[..len]);
+        Ok(len)
+    }
+
+    fn read_timeout(&mut self, buf: &mut [u8], timeout: i32) -> Result<usize> {
+        if self.state.lock().unresponsive {
+            self.responses.clear();
+            std::thread::sleep(std::time::Duration::from_millis(timeout.max(0) as u64));
+            return Ok(0);
+        }
+
+        if self.responses.is_empty() {
+            Ok(0)
+        } else {
+            self.read(buf)
+        }
+    }
+
+    fn reopen(&mut self) -> Result<()> {
+        let mut state = self.state.lock();
+        if !state.attached {
+            return Err(anyhow!("Simulated device is detached"));
+        }
+
+        // Re-enumeration resets the controller to its power-on state
+        state.controller_state = state::HARDWARE;
+        drop(state);
+
+        self.endpoints = Default::default();
+        self.pending_write = None;
+        self.responses.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hid::{protocol::Request, Hid};
+
+    #[test]
+    fn answers_requests_through_transport() {
+        let simulator = Simulator::default();
+        let device = simulator.state();
+        let mut hid = Hid::simulated(simulator);
+
+        assert!(matches!(
+            hid.send(&Request::GetFirmwareInfo).unwrap(),
+            Response::FirmwareInfo(FirmwareVersion {
+                major: 2,
+                minor: 10,
+                patch: 219
+            })
+        ));
+
+        hid.send(&Request::SetControllerState(state::SOFTWARE))
+            .unwrap();
+        assert_eq!(device.lock().controller_state, state::SOFTWARE);
+
+        hid.send(&Request::SetSpeeds(vec![80, 40, 40, 40, 40, 40, 40]))
+            .unwrap();
+        assert_eq!(device.lock().fan_targets, [80, 40, 40, 40, 40, 40, 40]);
+        match hid.send(&Request::GetSpeeds).unwrap() {
+            Response::Speeds(speeds) => {
+                assert_eq!(speeds.len(), CHANNEL_COUNT);
+                assert!(speeds[0] > speeds[1]);
+            }
+            response => panic!("Unexpected response {response:?}"),
+        }
+
+        match hid.send(&Request::GetTemp).unwrap() {
+            Response::Temperatures(temps) => assert!(temps[0].connected),
+            response => panic!("Unexpected response {response:?}"),
+        }
+
+        hid.send(&Request::EnableDirectLighting).unwrap();
+        hid.send(&Request::SetColors(vec![[255, 0, 0], [0, 0, 255]]))
+            .unwrap();
+        assert_eq!(device.lock().colors[..6], [255, 0, 0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn fails_while_detached() {
+        let simulator = Simulator::default();
+        let device = simulator.state();
+        let mut hid = Hid::simulated(simulator);
+
+        device.lock().attached = false;
+        assert!(hid.send(&Request::GetFirmwareInfo).is_err());
+        assert!(hid.reopen().is_err());
+
+        // Reattaching resets the device to hardware mode
+        device.lock().attached = true;
+        device.lock().controller_state = state::SOFTWARE;
+        hid.reopen().unwrap();
+        assert_eq!(device.lock().controller_state, state::HARDWARE);
+        assert!(hid.send(&Request::GetFirmwareInfo).is_ok());
+    }
+}
@@ -0,0 +1,211 @@
+use std::{fmt::Display, ops::Range};
+
+use anyhow::{anyhow, Result};
+use log::info;
+use serde::Serialize;
+
+use super::{
+    protocol::Request, Hid, LED_COUNT_FAN_LL, LED_COUNT_FAN_ML, LED_COUNT_FAN_QL, LED_COUNT_FAN_SP,
+    LED_COUNT_PUMP,
+};
+
+/// Device type attached to a channel, as written to the fan types endpoint
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FanType {
+    Pump,
+    Ql,
+    Ll,
+    Ml,
+    Sp,
+}
+
+impl FanType {
+    /// Infer the device type from a channel index and its reported LED count
+    pub fn from_led_count(channel: usize, led_count: u16) -> Option<Self> {
+        match (channel, led_count as usize) {
+            (0, LED_COUNT_PUMP) => Some(FanType::Pump),
+            (_, LED_COUNT_FAN_QL) => Some(FanType::Ql),
+            (_, LED_COUNT_FAN_LL) => Some(FanType::Ll),
+            (_, LED_COUNT_FAN_SP) => Some(FanType::Sp),
+            (_, LED_COUNT_FAN_ML) => Some(FanType::Ml),
+            _ => None,
+        }
+    }
+
+    pub fn led_count(&self) -> usize {
+        match self {
+            FanType::Pump => LED_COUNT_PUMP,
+            FanType::Ql => LED_COUNT_FAN_QL,
+            FanType::Ll => LED_COUNT_FAN_LL,
+            FanType::Ml => LED_COUNT_FAN_ML,
+            FanType::Sp => LED_COUNT_FAN_SP,
+        }
+    }
+
+    /// Approximate speed at 100% duty
+    pub fn max_rpm(&self) -> u16 {
+        match self {
+            FanType::Pump => 2700,
+            FanType::Ql => 1500,
+            FanType::Ll => 1500,
+            FanType::Ml => 2400,
+            FanType::Sp => 1400,
+        }
+    }
+}
+
+impl Display for FanType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FanType::Pump => f.write_str("pump"),
+            FanType::Ql => f.write_str("ql"),
+            FanType::Ll => f.write_str("ll"),
+            FanType::Ml => f.write_str("ml"),
+            FanType::Sp => f.write_str("sp"),
+        }
+    }
+}
+
+impl From<FanType> for u8 {
+    fn from(fan_type: FanType) -> Self {
+        match fan_type {
+            FanType::Ml => 0x02,
+            FanType::Ll => 0x04,
+            FanType::Sp => 0x05,
+            FanType::Ql => 0x06,
+            FanType::Pump => 0x08,
+        }
+    }
+}
+
+impl TryFrom<u8> for FanType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
+        match value {
+            0x02 => Ok(FanType::Ml),
+            0x04 => Ok(FanType::Ll),
+            0x05 => Ok(FanType::Sp),
+            0x06 => Ok(FanType::Ql),
+            0x08 => Ok(FanType::Pump),
+            _ => Err(anyhow!("Invalid fan type {value:02x}")),
+        }
+    }
+}
+
+/// Probed state of a single controller channel
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Channel {
+    pub connected: bool,
+    pub led_count: u16,
+    pub fan_type: Option<FanType>,
+}
+
+/// Channels and LEDs attached to a controller
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Topology {
+    pub channels: Vec<Channel>,
+}
+
+impl Topology {
+    /// Query the connected fans and LED counts from the device
+    pub fn probe(hid: &mut Hid) -> Result<Self> {
+        let connected = hid
+            .send(&Request::GetConnectedFans)?
+            .into_connected_fans()?;
+        let led_counts = hid.send(&Request::GetLedCounts)?.into_led_counts()?;
+
+        if connected.len() != led_counts.len() {
+            return Err(anyhow!(
+                "Connected fan count {} does not match LED channel count {}",
+                connected.len(),
+                led_counts.len()
+            ));
+        }
+
+        let channels = connected
+            .into_iter()
+            .zip(led_counts)
+            .enumerate()
+            .map(|(i, (connected, led_count))| {
+                let led_count = led_count.unwrap_or_default();
+                Channel {
+                    connected,
+                    led_count,
+                    fan_type: FanType::from_led_count(i, led_count),
+                }
+            })
+            .collect();
+
+        let topology = Topology { channels };
+        info!("Topology: {topology:}");
+        Ok(topology)
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.channels.len()
+    }
+
+    /// Total number of LEDs across all channels
+    pub fn led_count(&self) -> usize {
+        self.channels.iter().map(|c| c.led_count as usize).sum()
+    }
+
+    /// Range of the given channel's LEDs within the color buffer
+    pub fn led_range(&self, channel: usize) -> Range<usize> {
+        let start = self.channels[..channel.min(self.channels.len())]
+            .iter()
+            .map(|c| c.led_count as usize)
+            .sum::<usize>();
+        let len = self
+            .channels
+            .get(channel)
+            .map(|c| c.led_count as usize)
+            .unwrap_or_default();
+        start..start + len
+    }
+
+    pub fn fan_types(&self) -> Vec<Option<FanType>> {
+        self.channels.iter().map(|c| c.fan_type).collect()
+    }
+}
+
+#[cfg(test)]
+impl Topology {
+    /// Channels of unknown type with the given LED counts, with zero marking a disconnected channel
+    pub fn with_led_counts(led_counts: &[u16]) -> Self {
+        Topology {
+            channels: led_counts
+                .iter()
+                .map(|&led_count| Channel {
+                    connected: led_count > 0,
+                    led_count,
+                    fan_type: None,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Display for Topology {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, channel) in self.channels.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+
+            match (channel.connected, channel.fan_type) {
+                (false, _) => f.write_fmt(format_args!("{i:}: disconnected"))?,
+                (true, Some(fan_type)) => f.write_fmt(format_args!(
+                    "{i:}: {fan_type:?} ({} LEDs)",
+                    channel.led_count
+                ))?,
+                (true, None) => {
+                    f.write_fmt(format_args!("{i:}: unknown ({} LEDs)", channel.led_count))?
+                }
+            }
+        }
+        Ok(())
+    }
+}
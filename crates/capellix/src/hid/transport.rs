@@ -0,0 +1,138 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use hidapi::{HidApi, HidDevice};
+use log::info;
+use parking_lot::Mutex;
+
+use super::{selector::DeviceSelector, INTERFACE_NUMBER, PID, VID};
+
+/// Report-level I/O backing a [`Hid`](super::Hid)
+pub trait Transport: Send {
+    /// Send a report to the device, returning the number of bytes written
+    fn write(&mut self, data: &[u8]) -> Result<usize>;
+
+    /// Block until a report is received, returning the number of bytes read
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
+
+    /// Wait up to `timeout` milliseconds for a report, returning the number of bytes read
+    fn read_timeout(&mut self, buf: &mut [u8], timeout: i32) -> Result<usize>;
+
+    /// Reopen the underlying device after it has been disconnected
+    fn reopen(&mut self) -> Result<()>;
+}
+
+/// Transport backed by a physical device opened through hidapi
+///
+/// Holds a reference to the shared hidapi context,
+/// which is released once every device opened through it has been dropped.
+pub struct HidapiTransport {
+    api: Arc<Mutex<HidApi>>,
+    selector: DeviceSelector,
+    path: String,
+    device: HidDevice,
+}
+
+impl HidapiTransport {
+    /// Open the first Commander Core matching `selector`, skipping any at the given paths
+    pub fn open(
+        api: Arc<Mutex<HidApi>>,
+        selector: &DeviceSelector,
+        exclude: &[String],
+    ) -> Result<Self> {
+        let (device, path, serial) = {
+            let api = api.lock();
+
+            let device_info = api
+                .device_list()
+                .filter(|device_info| {
+                    device_info.vendor_id() == VID
+                        && device_info.product_id() == PID
+                        && device_info.interface_number() == INTERFACE_NUMBER
+                })
+                .filter(|device_info| {
+                    !exclude.contains(&device_info.path().to_string_lossy().to_string())
+                })
+                .find(|device_info| selector.matches(device_info))
+                .ok_or_else(|| anyhow!("Failed to find device matching {selector:}"))?;
+
+            let path = device_info.path().to_string_lossy().to_string();
+            let serial = device_info.serial_number().map(ToString::to_string);
+
+            info!(
+                "Found {} at {path:} (serial {})",
+                device_info
+                    .product_string()
+                    .ok_or_else(|| anyhow!("Failed to fetch product string"))?,
+                serial.as_deref().unwrap_or("unknown"),
+            );
+
+            let device = device_info
+                .open_device(&api)
+                .map_err(|_| anyhow!("Failed to open device at {path:}"))?;
+            device.set_blocking_mode(true)?;
+
+            (device, path, serial)
+        };
+
+        // Prefer the serial number when reopening, since hidraw paths can change on re-enumeration
+        let selector = match serial {
+            Some(serial) if !serial.is_empty() => DeviceSelector::Serial(serial),
+            _ => DeviceSelector::Path(path.clone()),
+        };
+
+        Ok(HidapiTransport {
+            api,
+            selector,
+            path,
+            device,
+        })
+    }
+
+    /// Hidraw path of the opened device
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Transport for HidapiTransport {
+    fn write(&mut self, data: &[u8]) -> Result<usize> {
+        Ok(self.device.write(data)?)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        Ok(self.device.read(buf)?)
+    }
+
+    fn read_timeout(&mut self, buf: &mut [u8], timeout: i32) -> Result<usize> {
+        Ok(self.device.read_timeout(buf, timeout)?)
+    }
+
+    fn reopen(&mut self) -> Result<()> {
+        self.api.lock().refresh_devices()?;
+        *self = HidapiTransport::open(self.api.clone(), &self.selector, &[])?;
+        Ok(())
+    }
+}
+
+/// Placeholder transport for a [`Hid`](super::Hid) that has not been opened yet
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ClosedTransport;
+
+impl Transport for ClosedTransport {
+    fn write(&mut self, _: &[u8]) -> Result<usize> {
+        Err(anyhow!("HID device is not open"))
+    }
+
+    fn read(&mut self, _: &mut [u8]) -> Result<usize> {
+        Err(anyhow!("HID device is not open"))
+    }
+
+    fn read_timeout(&mut self, _: &mut [u8], _: i32) -> Result<usize> {
+        Err(anyhow!("HID device is not open"))
+    }
+
+    fn reopen(&mut self) -> Result<()> {
+        Err(anyhow!("HID device is not open"))
+    }
+}
@@ -1,5 +1,14 @@
+pub mod compositor;
+pub mod config;
+pub mod curve;
+pub mod effect;
 pub mod hid;
+pub mod output;
+pub mod rpm;
+pub mod stall;
+pub mod thermal;
 pub mod thread;
+pub mod zone;
 pub mod atomic_changed;
 pub mod then;
 
@@ -0,0 +1,384 @@
+use std::fmt::Display;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    effect::float_param_str,
+    hid::topology::Topology,
+    thread::capellix::Colors,
+    zone::{zone_bytes, Zone},
+};
+
+pub const OUTPUT_BRIGHTNESS: u8 = 0;
+pub const OUTPUT_GAMMA: u8 = 1;
+pub const OUTPUT_WHITE_BALANCE: u8 = 2;
+pub const OUTPUT_POWER_CAP: u8 = 3;
+pub const OUTPUT_RESET: u8 = 4;
+
+/// Smallest gamma accepted, to keep the exponent from collapsing every color to full brightness
+const GAMMA_MIN: f32 = 0.1;
+
+/// Color correction for a device or zone
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Correction {
+    /// Scale applied to every color, from 0 to 1
+    pub brightness: f32,
+    /// Exponent applied to each color component, where 1 leaves colors as they are
+    pub gamma: f32,
+    /// Scale applied to the red, green and blue components, from 0 to 1
+    pub white_balance: [f32; 3],
+    /// Limit on the average output of the covered LEDs as a fraction of full white,
+    /// with brighter frames scaled down uniformly to fit
+    pub power_cap: f32,
+}
+
+impl Default for Correction {
+    fn default() -> Self {
+        Correction {
+            brightness: 1.0,
+            gamma: 1.0,
+            white_balance: [1.0; 3],
+            power_cap: 1.0,
+        }
+    }
+}
+
+impl Correction {
+    /// Clamp each setting to its valid range
+    pub fn clamped(self) -> Self {
+        Correction {
+            brightness: self.brightness.clamp(0.0, 1.0),
+            gamma: self.gamma.max(GAMMA_MIN),
+            white_balance: self.white_balance.map(|scale| scale.clamp(0.0, 1.0)),
+            power_cap: self.power_cap.clamp(0.0, 1.0),
+        }
+    }
+
+    /// Stack another correction on top of this one, as applied to a zone within a device
+    ///
+    /// The power cap is left as it is, since caps apply over separate groups of LEDs.
+    fn then(self, rhs: Correction) -> Self {
+        Correction {
+            brightness: self.brightness * rhs.brightness,
+            gamma: self.gamma * rhs.gamma,
+            white_balance: [0, 1, 2].map(|i| self.white_balance[i] * rhs.white_balance[i]),
+            power_cap: self.power_cap,
+        }
+    }
+
+    /// Apply gamma, brightness and white balance to a color, returning components from 0 to 1
+    fn correct(&self, color: [u8; 3]) -> [f32; 3] {
+        [0, 1, 2].map(|i| {
+            (color[i] as f32 / 255.0).powf(self.gamma) * self.brightness * self.white_balance[i]
+        })
+    }
+
+    /// Encode as `[brightness, gamma, red, green, blue, power cap]`, each as a LE f32
+    pub fn encode(&self) -> Vec<u8> {
+        [
+            self.brightness,
+            self.gamma,
+            self.white_balance[0],
+            self.white_balance[1],
+            self.white_balance[2],
+            self.power_cap,
+        ]
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect()
+    }
+}
+
+impl Display for Correction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [red, green, blue] = self.white_balance;
+        f.write_fmt(format_args!(
+            "brightness {} gamma {} white-balance {red:} {green:} {blue:} power-cap {}",
+            self.brightness, self.gamma, self.power_cap
+        ))
+    }
+}
+
+/// Correction set for a single zone
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub struct ZoneCorrection {
+    pub zone: Zone,
+    #[serde(flatten)]
+    pub correction: Correction,
+}
+
+/// Change to one setting of a device or zone correction
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OutputSetting {
+    Brightness(f32),
+    Gamma(f32),
+    WhiteBalance([f32; 3]),
+    PowerCap(f32),
+    /// Return every setting to its default, removing a zone's correction entirely
+    Reset,
+}
+
+impl OutputSetting {
+    /// Encode as `[kind, parameters...]`, with parameters as LE f32
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, values) = match *self {
+            OutputSetting::Brightness(brightness) => (OUTPUT_BRIGHTNESS, vec![brightness]),
+            OutputSetting::Gamma(gamma) => (OUTPUT_GAMMA, vec![gamma]),
+            OutputSetting::WhiteBalance(scales) => (OUTPUT_WHITE_BALANCE, scales.to_vec()),
+            OutputSetting::PowerCap(cap) => (OUTPUT_POWER_CAP, vec![cap]),
+            OutputSetting::Reset => (OUTPUT_RESET, vec![]),
+        };
+
+        [
+            &[kind][..],
+            &values
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect::<Vec<_>>()[..],
+        ]
+        .concat()
+    }
+}
+
+impl Display for OutputSetting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            OutputSetting::Brightness(brightness) => {
+                f.write_fmt(format_args!("brightness {brightness:}"))
+            }
+            OutputSetting::Gamma(gamma) => f.write_fmt(format_args!("gamma {gamma:}")),
+            OutputSetting::WhiteBalance([red, green, blue]) => {
+                f.write_fmt(format_args!("white-balance {red:} {green:} {blue:}"))
+            }
+            OutputSetting::PowerCap(cap) => f.write_fmt(format_args!("power-cap {cap:}")),
+            OutputSetting::Reset => f.write_str("reset"),
+        }
+    }
+}
+
+/// Most zone corrections a controller can hold, as their count is encoded in a byte
+pub const MAX_ZONE_CORRECTIONS: usize = u8::MAX as usize;
+
+/// Correction applied to a controller's frames just before they're sent to the device
+///
+/// Zone corrections are stacked on the device correction for the LEDs they cover,
+/// and their power caps are applied before the device's.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct OutputStage {
+    pub device: Correction,
+    pub zones: Vec<ZoneCorrection>,
+}
+
+impl OutputStage {
+    /// Check that a setting can be changed, which fails if it would add a zone beyond the limit
+    pub fn check(&self, zone: Option<&Zone>, setting: &OutputSetting) -> Result<()> {
+        match zone {
+            Some(zone)
+                if *setting != OutputSetting::Reset
+                    && self.zones.len() >= MAX_ZONE_CORRECTIONS
+                    && !self.zones.iter().any(|entry| entry.zone == *zone) =>
+            {
+                Err(anyhow!(
+                    "At most {MAX_ZONE_CORRECTIONS:} zones can have their own correction"
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Change a setting of the device correction, or of a zone's if given
+    pub fn set(&mut self, zone: Option<Zone>, setting: OutputSetting) -> Result<()> {
+        self.check(zone.as_ref(), &setting)?;
+
+        let correction = match zone {
+            None => &mut self.device,
+            Some(zone) if setting == OutputSetting::Reset => {
+                self.zones.retain(|entry| entry.zone != zone);
+                return Ok(());
+            }
+            Some(zone) => match self.zones.iter().position(|entry| entry.zone == zone) {
+                Some(i) => &mut self.zones[i].correction,
+                None => {
+                    self.zones.push(ZoneCorrection {
+                        zone,
+                        correction: Correction::default(),
+                    });
+                    &mut self.zones.last_mut().unwrap().correction
+                }
+            },
+        };
+
+        match setting {
+            OutputSetting::Brightness(brightness) => correction.brightness = brightness,
+            OutputSetting::Gamma(gamma) => correction.gamma = gamma,
+            OutputSetting::WhiteBalance(scales) => correction.white_balance = scales,
+            OutputSetting::PowerCap(cap) => correction.power_cap = cap,
+            OutputSetting::Reset => *correction = Correction::default(),
+        }
+
+        *correction = correction.clamped();
+        Ok(())
+    }
+
+    /// Encode as `[device correction, zone count, (zone, correction)...]`
+    pub fn encode(&self) -> Vec<u8> {
+        [
+            self.device.encode(),
+            vec![self.zones.len() as u8],
+            self.zones
+                .iter()
+                .flat_map(|entry| [entry.zone.encode(), entry.correction.encode()].concat())
+                .collect(),
+        ]
+        .concat()
+    }
+
+    /// Correct a frame for output
+    pub fn apply(&self, topology: &Topology, colors: &[[u8; 3]]) -> Colors {
+        if *self == OutputStage::default() {
+            return colors.to_vec();
+        }
+
+        let mut corrections = vec![self.device; colors.len()];
+        for entry in &self.zones {
+            if let Some(range) = Self::range(topology, &entry.zone, colors.len()) {
+                for correction in &mut corrections[range] {
+                    *correction = correction.then(entry.correction);
+                }
+            }
+        }
+
+        let mut frame = colors
+            .iter()
+            .zip(&corrections)
+            .map(|(color, correction)| correction.correct(*color))
+            .collect::<Vec<_>>();
+
+        for entry in &self.zones {
+            if let Some(range) = Self::range(topology, &entry.zone, frame.len()) {
+                Self::cap(&mut frame[range], entry.correction.power_cap);
+            }
+        }
+        Self::cap(&mut frame, self.device.power_cap);
+
+        frame
+            .into_iter()
+            .map(|color| color.map(|value| (value.clamp(0.0, 1.0) * 255.0).round() as u8))
+            .collect()
+    }
+
+    /// LEDs covered by a zone, if it lies within the frame
+    fn range(topology: &Topology, zone: &Zone, len: usize) -> Option<std::ops::Range<usize>> {
+        zone.led_range(topology).filter(|range| range.end <= len)
+    }
+
+    /// Scale LEDs down uniformly so their average output doesn't exceed the cap
+    fn cap(frame: &mut [[f32; 3]], cap: f32) {
+        if frame.is_empty() {
+            return;
+        }
+
+        let average = frame.iter().flatten().sum::<f32>() / (frame.len() * 3) as f32;
+        if average > cap {
+            let scale = cap / average;
+            for value in frame.iter_mut().flatten() {
+                *value *= scale;
+            }
+        }
+    }
+}
+
+impl Display for OutputStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("device {}", self.device))?;
+
+        for entry in &self.zones {
+            f.write_fmt(format_args!("\n{} {}", entry.zone, entry.correction))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse a setting in the form `brightness <0-1>`, `gamma <exponent>`,
+/// `white-balance <red> <green> <blue>`, `power-cap <0-1>` or `reset`
+pub fn output_setting_str(input: &str) -> nom::IResult<&str, OutputSetting> {
+    nom::branch::alt((
+        nom::combinator::map(
+            nom::sequence::preceded(nom::bytes::complete::tag("brightness"), float_param_str),
+            OutputSetting::Brightness,
+        ),
+        nom::combinator::map(
+            nom::sequence::preceded(nom::bytes::complete::tag("gamma"), float_param_str),
+            OutputSetting::Gamma,
+        ),
+        nom::combinator::map(
+            nom::sequence::preceded(
+                nom::bytes::complete::tag("white-balance"),
+                nom::sequence::tuple((float_param_str, float_param_str, float_param_str)),
+            ),
+            |(red, green, blue)| OutputSetting::WhiteBalance([red, green, blue]),
+        ),
+        nom::combinator::map(
+            nom::sequence::preceded(nom::bytes::complete::tag("power-cap"), float_param_str),
+            OutputSetting::PowerCap,
+        ),
+        nom::combinator::value(OutputSetting::Reset, nom::bytes::complete::tag("reset")),
+    ))(input)
+}
+
+pub fn output_setting_bytes(input: &[u8]) -> nom::IResult<&[u8], OutputSetting> {
+    let (input, kind) = nom::number::complete::u8(input)?;
+    match kind {
+        OUTPUT_BRIGHTNESS => {
+            nom::combinator::map(nom::number::complete::le_f32, OutputSetting::Brightness)(input)
+        }
+        OUTPUT_GAMMA => {
+            nom::combinator::map(nom::number::complete::le_f32, OutputSetting::Gamma)(input)
+        }
+        OUTPUT_WHITE_BALANCE => nom::combinator::map(
+            nom::sequence::tuple((
+                nom::number::complete::le_f32,
+                nom::number::complete::le_f32,
+                nom::number::complete::le_f32,
+            )),
+            |(red, green, blue)| OutputSetting::WhiteBalance([red, green, blue]),
+        )(input),
+        OUTPUT_POWER_CAP => {
+            nom::combinator::map(nom::number::complete::le_f32, OutputSetting::PowerCap)(input)
+        }
+        OUTPUT_RESET => Ok((input, OutputSetting::Reset)),
+        _ => Err(nom::Err::Error(nom::error::Error {
+            input,
+            code: nom::error::ErrorKind::Tag,
+        })),
+    }
+}
+
+pub fn correction_bytes(input: &[u8]) -> nom::IResult<&[u8], Correction> {
+    let (input, values) = nom::multi::count(nom::number::complete::le_f32, 6)(input)?;
+    Ok((
+        input,
+        Correction {
+            brightness: values[0],
+            gamma: values[1],
+            white_balance: [values[2], values[3], values[4]],
+            power_cap: values[5],
+        },
+    ))
+}
+
+pub fn output_stage_bytes(input: &[u8]) -> nom::IResult<&[u8], OutputStage> {
+    let (input, device) = correction_bytes(input)?;
+    let (input, zones) = nom::multi::length_count(
+        nom::number::complete::u8,
+        nom::combinator::map(
+            nom::sequence::pair(zone_bytes, correction_bytes),
+            |(zone, correction)| ZoneCorrection { zone, correction },
+        ),
+    )(input)?;
+
+    Ok((input, OutputStage { device, zones }))
+}
@@ -0,0 +1,71 @@
+use std::time::Instant;
+
+use pid_controller::{PidController, PidParameters};
+
+/// Closed-loop controller driving a channel's duty towards an RPM setpoint
+///
+/// Error is measured in RPM and the output is a duty percentage,
+/// so gains are expressed in percent per RPM.
+#[derive(Debug, Clone)]
+pub struct RpmTarget {
+    pid: PidController<f32>,
+    /// Duty percentage output by the last update
+    duty: u16,
+    last_tick: Option<Instant>,
+}
+
+impl RpmTarget {
+    /// Create a controller starting from the channel's current duty,
+    /// so switching modes doesn't cause a jump in speed
+    pub fn new(params: PidParameters<f32>, rpm: u16, duty: u16) -> Self {
+        let mut pid = PidController::new(params);
+        pid.inputs.setpoint = rpm as f32;
+        if params.integral_factor > 0.0 {
+            pid.state.integral = duty as f32 / params.integral_factor;
+        }
+
+        RpmTarget {
+            pid,
+            duty,
+            last_tick: None,
+        }
+    }
+
+    pub fn rpm(&self) -> u16 {
+        self.pid.inputs.setpoint as u16
+    }
+
+    pub fn set_rpm(&mut self, rpm: u16) {
+        self.pid.inputs.setpoint = rpm as f32;
+    }
+
+    /// Feed a speed reading, returning the new duty percentage
+    pub fn update(&mut self, measured: u16) -> u16 {
+        let now = Instant::now();
+        self.pid.inputs.measured_value = measured as f32;
+
+        let delta = match self.last_tick.replace(now) {
+            Some(last_tick) => now.duration_since(last_tick).as_secs_f32(),
+            None => {
+                // Seed the error so the first derivative term doesn't spike
+                self.pid.state.error = self.pid.inputs.error();
+                return self.duty;
+            }
+        };
+
+        if delta <= 0.0 {
+            return self.duty;
+        }
+
+        self.pid.tick(delta);
+
+        // Keep the integral within the duty range to avoid windup while saturated
+        let integral_factor = self.pid.params.integral_factor;
+        if integral_factor > 0.0 {
+            self.pid.state.integral = self.pid.state.integral.clamp(0.0, 100.0 / integral_factor);
+        }
+
+        self.duty = self.pid.outputs.total().clamp(0.0, 100.0).round() as u16;
+        self.duty
+    }
+}
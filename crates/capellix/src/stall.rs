@@ -0,0 +1,94 @@
+use crate::hid::topology::Topology;
+
+/// Minimum duty percentage at which a channel is expected to spin
+const STALL_DUTY_MIN: u16 = 20;
+
+/// Fraction of the expected RPM below which a channel is considered stalled
+const STALL_RPM_FRACTION: f32 = 0.3;
+
+/// Change in a channel's stall state
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StallEvent {
+    Stalled(usize),
+    Recovered(usize),
+}
+
+/// Tracks consecutive abnormal speed readings for each channel
+#[derive(Debug, Default, Clone)]
+pub struct StallDetector {
+    /// Consecutive abnormal readings for healthy channels,
+    /// or consecutive normal readings for stalled channels
+    counts: Vec<usize>,
+    stalled: Vec<bool>,
+}
+
+impl StallDetector {
+    pub fn new(channel_count: usize) -> Self {
+        StallDetector {
+            counts: vec![0; channel_count],
+            stalled: vec![false; channel_count],
+        }
+    }
+
+    pub fn is_stalled(&self, channel: usize) -> bool {
+        self.stalled.get(channel).copied().unwrap_or_default()
+    }
+
+    /// Bitmask of stalled channels
+    pub fn mask(&self) -> u8 {
+        self.stalled
+            .iter()
+            .enumerate()
+            .filter(|(_, stalled)| **stalled)
+            .fold(0, |acc, (channel, _)| acc | 1 << channel)
+    }
+
+    /// Feed a speed reading alongside the duties that were sent,
+    /// returning channels whose state flipped after `ticks` consecutive readings
+    pub fn update(
+        &mut self,
+        topology: &Topology,
+        duties: &[u16],
+        speeds: &[u16],
+        ticks: usize,
+    ) -> Vec<StallEvent> {
+        let mut events = vec![];
+
+        for (channel, ((count, stalled), (duty, speed))) in self
+            .counts
+            .iter_mut()
+            .zip(self.stalled.iter_mut())
+            .zip(duties.iter().zip(speeds))
+            .enumerate()
+        {
+            let abnormal = match topology.channels.get(channel) {
+                Some(info) if info.connected && *duty >= STALL_DUTY_MIN => {
+                    let expected = info
+                        .fan_type
+                        .map(|fan_type| fan_type.max_rpm() as f32 * *duty as f32 / 100.0)
+                        .unwrap_or_default();
+                    *speed == 0 || (*speed as f32) < expected * STALL_RPM_FRACTION
+                }
+                _ => false,
+            };
+
+            if abnormal == *stalled {
+                *count = 0;
+                continue;
+            }
+
+            *count += 1;
+            if *count >= ticks {
+                *count = 0;
+                *stalled = abnormal;
+                events.push(if abnormal {
+                    StallEvent::Stalled(channel)
+                } else {
+                    StallEvent::Recovered(channel)
+                });
+            }
+        }
+
+        events
+    }
+}
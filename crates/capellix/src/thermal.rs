@@ -0,0 +1,182 @@
+use std::fmt::Display;
+
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+
+/// Coolant temperature condition, ordered by severity
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThermalState {
+    #[default]
+    Normal,
+    Warning,
+    Critical,
+}
+
+impl From<ThermalState> for u8 {
+    fn from(state: ThermalState) -> Self {
+        match state {
+            ThermalState::Normal => 0,
+            ThermalState::Warning => 1,
+            ThermalState::Critical => 2,
+        }
+    }
+}
+
+impl TryFrom<u8> for ThermalState {
+    type Error = Error;
+
+    fn try_from(state: u8) -> Result<Self, Self::Error> {
+        match state {
+            0 => Ok(ThermalState::Normal),
+            1 => Ok(ThermalState::Warning),
+            2 => Ok(ThermalState::Critical),
+            _ => Err(anyhow!("Invalid thermal state {state:}")),
+        }
+    }
+}
+
+impl Display for ThermalState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ThermalState::Normal => f.write_str("normal"),
+            ThermalState::Warning => f.write_str("warning"),
+            ThermalState::Critical => f.write_str("critical"),
+        }
+    }
+}
+
+/// Coolant temperature thresholds in degrees celsius
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct ThermalLimits {
+    pub warning: Option<f32>,
+    pub critical: Option<f32>,
+    /// Temperature drop below a threshold required before leaving its state
+    pub hysteresis: f32,
+}
+
+impl ThermalLimits {
+    /// State for the given temperature, given the current state
+    ///
+    /// Rising temperatures escalate immediately,
+    /// while falling temperatures must clear a threshold by the hysteresis to de-escalate.
+    pub fn evaluate(&self, current: ThermalState, temp: f32) -> ThermalState {
+        let above = |threshold: Option<f32>, offset: f32| matches!(threshold, Some(threshold) if temp >= threshold - offset);
+
+        let rising = if above(self.critical, 0.0) {
+            ThermalState::Critical
+        } else if above(self.warning, 0.0) {
+            ThermalState::Warning
+        } else {
+            ThermalState::Normal
+        };
+
+        if rising >= current {
+            return rising;
+        }
+
+        match current {
+            ThermalState::Critical if above(self.critical, self.hysteresis) => {
+                ThermalState::Critical
+            }
+            ThermalState::Critical | ThermalState::Warning
+                if above(self.warning, self.hysteresis) =>
+            {
+                ThermalState::Warning
+            }
+            _ => rising,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: ThermalLimits = ThermalLimits {
+        warning: Some(35.0),
+        critical: Some(40.0),
+        hysteresis: 2.0,
+    };
+
+    #[test]
+    fn escalates_immediately() {
+        assert_eq!(
+            LIMITS.evaluate(ThermalState::Normal, 34.9),
+            ThermalState::Normal
+        );
+        assert_eq!(
+            LIMITS.evaluate(ThermalState::Normal, 35.0),
+            ThermalState::Warning
+        );
+        assert_eq!(
+            LIMITS.evaluate(ThermalState::Warning, 40.0),
+            ThermalState::Critical
+        );
+        assert_eq!(
+            LIMITS.evaluate(ThermalState::Normal, 45.0),
+            ThermalState::Critical
+        );
+    }
+
+    #[test]
+    fn de_escalates_past_hysteresis() {
+        assert_eq!(
+            LIMITS.evaluate(ThermalState::Critical, 38.0),
+            ThermalState::Critical
+        );
+        assert_eq!(
+            LIMITS.evaluate(ThermalState::Critical, 37.9),
+            ThermalState::Warning
+        );
+        assert_eq!(
+            LIMITS.evaluate(ThermalState::Warning, 33.0),
+            ThermalState::Warning
+        );
+        assert_eq!(
+            LIMITS.evaluate(ThermalState::Warning, 32.9),
+            ThermalState::Normal
+        );
+        assert_eq!(
+            LIMITS.evaluate(ThermalState::Critical, 30.0),
+            ThermalState::Normal
+        );
+    }
+
+    #[test]
+    fn skips_unset_thresholds() {
+        let limits = ThermalLimits {
+            warning: None,
+            ..LIMITS
+        };
+        assert_eq!(
+            limits.evaluate(ThermalState::Normal, 39.0),
+            ThermalState::Normal
+        );
+        assert_eq!(
+            limits.evaluate(ThermalState::Critical, 38.0),
+            ThermalState::Critical
+        );
+        assert_eq!(
+            limits.evaluate(ThermalState::Critical, 37.0),
+            ThermalState::Normal
+        );
+
+        assert_eq!(
+            ThermalLimits::default().evaluate(ThermalState::Normal, 100.0),
+            ThermalState::Normal
+        );
+    }
+
+    #[test]
+    fn round_trips_through_u8() {
+        for state in [
+            ThermalState::Normal,
+            ThermalState::Warning,
+            ThermalState::Critical,
+        ] {
+            assert_eq!(ThermalState::try_from(u8::from(state)).unwrap(), state);
+        }
+        assert!(ThermalState::try_from(3).is_err());
+    }
+}
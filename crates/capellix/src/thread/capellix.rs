@@ -1,8 +1,9 @@
 use std::{
     net::SocketAddr,
+    panic::AssertUnwindSafe,
     path::PathBuf,
     sync::{
-        atomic::{AtomicU16, Ordering},
+        atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU8, Ordering},
         Arc,
     },
     time::Duration,
@@ -10,7 +11,10 @@ use std::{
 
 use anyhow::{anyhow, Result};
 use clap::Parser;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use nix::unistd::{Gid, Uid};
+use parking_lot::Mutex;
+use sd_notify::NotifyState;
 
 use futures::StreamExt;
 use tokio::{
@@ -21,48 +25,152 @@ use tokio::{
     task::{spawn, JoinHandle},
     time::interval,
 };
-use tokio_stream::wrappers::{IntervalStream, ReceiverStream, SignalStream, WatchStream};
+use tokio_stream::wrappers::{IntervalStream, ReceiverStream, SignalStream};
 
 use crate::{
+    compositor::{Layer, LayerUpdate, Lighting},
+    config::Config,
+    effect::Effect,
     hid::{
-        command::{set_controller_state, GET_FIRMWARE_INFO},
-        request,
-        state::{HARDWARE, SOFTWARE},
-        Hid, LED_COUNT_TOTAL,
+        protocol::{FirmwareVersion, Request},
+        selector::DeviceSelector,
+        simulator::Simulator,
+        state::HARDWARE,
+        topology::{FanType, Topology},
+        Hid,
     },
+    output::{OutputSetting, OutputStage},
     then::Then,
+    thermal::{ThermalLimits, ThermalState},
     thread::{
+        controller::{Controller, ControllerHandle},
+        openrgb_server_thread::OpenRgbServerThread,
         print_thread_result,
-        pump_target::{Fan, FanTargetThread},
+        pump_target::{Fan, FanTarget, FanTargetThread},
         server_thread::ServerThread,
+        socket::unix_socket_paths,
+        unix_server_thread::{
+            gid_from_str, mode_from_str, uid_from_str, UnixServerThread, UnixSocketOptions,
+        },
+        watchdog::{HardwareRestore, Heartbeat, WatchdogThread},
     },
+    zone::Zone,
 };
 
-pub type Colors = Box<[[u8; 3]; LED_COUNT_TOTAL]>;
+pub type Colors = Vec<[u8; 3]>;
 
 #[derive(Debug)]
 pub struct SharedState {
+    /// Whether the device is currently connected and initialized
+    pub available: AtomicBool,
     pub coolant_temp: AtomicU16,
     pub pump_speed: AtomicU16,
-    pub fan_targets: [AtomicU16; 7],
+    /// Last speed reading for each channel in RPM
+    pub speeds: Vec<AtomicU16>,
+    pub fan_targets: Vec<AtomicU16>,
+    /// Bitmask of channels detected as stalled, forcing all channels to full speed while nonzero
+    pub stalled: AtomicU8,
+    /// Coolant temperature condition, as a ThermalState
+    pub thermal: AtomicU8,
+    /// Device type on each channel as its fan types endpoint value, or 0 if none is connected
+    pub fan_types: Vec<AtomicU8>,
+    pub firmware: Mutex<Option<FirmwareVersion>>,
+    /// Channels and LEDs found when the device was last initialized
+    pub topology: Mutex<Topology>,
+    /// LED colors most recently sent to the device
+    pub colors: Mutex<Colors>,
+    /// Correction applied to LED colors before they're sent
+    pub output: Mutex<OutputStage>,
+    /// Frames of LED colors sent to the device
+    pub frames_sent: AtomicU32,
+    /// Frames skipped because they matched the last frame sent
+    pub frames_skipped: AtomicU32,
+    /// Lighting changes replaced by a later one before the next frame was rendered
+    pub frames_coalesced: AtomicU32,
+    /// Time spent sending the last frame, in microseconds
+    pub frame_time: AtomicU32,
+    /// Moving average of the time spent sending a frame, in microseconds
+    pub frame_time_average: AtomicU32,
+    /// Longest time spent sending a frame, in microseconds
+    pub frame_time_max: AtomicU32,
 }
 
 impl Default for SharedState {
     fn default() -> Self {
-        const DEFAULT_FAN: AtomicU16 = AtomicU16::new(50);
+        SharedState::new(0)
+    }
+}
+
+impl SharedState {
+    pub fn new(channel_count: usize) -> Self {
         SharedState {
+            available: AtomicBool::new(true),
             coolant_temp: AtomicU16::new(312),
             pump_speed: AtomicU16::new(2268),
-            fan_targets: [DEFAULT_FAN; 7],
+            speeds: (0..channel_count).map(|_| AtomicU16::new(0)).collect(),
+            fan_targets: (0..channel_count).map(|_| AtomicU16::new(50)).collect(),
+            stalled: AtomicU8::new(0),
+            thermal: AtomicU8::new(ThermalState::Normal.into()),
+            fan_types: (0..channel_count).map(|_| AtomicU8::new(0)).collect(),
+            firmware: Mutex::new(None),
+            topology: Mutex::new(Topology::default()),
+            colors: Mutex::new(vec![]),
+            output: Mutex::new(OutputStage::default()),
+            frames_sent: AtomicU32::new(0),
+            frames_skipped: AtomicU32::new(0),
+            frames_coalesced: AtomicU32::new(0),
+            frame_time: AtomicU32::new(0),
+            frame_time_average: AtomicU32::new(0),
+            frame_time_max: AtomicU32::new(0),
+        }
+    }
+
+    /// Store the firmware version, topology and fan types found when initializing the device
+    pub fn store_device(&self, firmware: FirmwareVersion, topology: &Topology) {
+        *self.firmware.lock() = Some(firmware);
+        *self.topology.lock() = topology.clone();
+
+        for (stored, channel) in self.fan_types.iter().zip(&topology.channels) {
+            let fan_type = match (channel.connected, channel.fan_type) {
+                (true, Some(fan_type)) => fan_type.into(),
+                _ => 0,
+            };
+            stored.store(fan_type, Ordering::Relaxed);
         }
     }
+
+    /// Record the time spent sending a frame of LED colors
+    pub fn record_frame(&self, elapsed: Duration) {
+        let micros = elapsed.as_micros().min(u32::MAX as u128) as u32;
+
+        // Only the main loop sends frames, so the average needs no compare-exchange
+        let average = match self.frames_sent.fetch_add(1, Ordering::Relaxed) {
+            0 => micros,
+            _ => {
+                let average = self.frame_time_average.load(Ordering::Relaxed);
+                average - average / 8 + micros / 8
+            }
+        };
+
+        self.frame_time.store(micros, Ordering::Relaxed);
+        self.frame_time_average.store(average, Ordering::Relaxed);
+        self.frame_time_max.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    /// Device type connected to the given channel
+    pub fn fan_type(&self, channel: usize) -> Option<FanType> {
+        FanType::try_from(self.fan_types.get(channel)?.load(Ordering::Relaxed)).ok()
+    }
 }
 
 enum CapellixEvent {
     TempTick,
     SpeedTick,
-    SetFanSpeed(Fan, u16),
-    SetColors(Colors),
+    ReconnectTick,
+    ColorTick,
+    SetFanSpeed(usize, Fan, FanTarget),
+    SetLighting(usize, LayerUpdate),
+    SetOutput(usize, Option<Zone>, OutputSetting),
     Exit,
 }
 
@@ -77,19 +185,39 @@ pub struct Capellix {
     #[clap(long, parse(try_from_str = Self::tick_from_str), default_value = "0.25")]
     speed_tick_duration: Duration,
 
-    /// Duration in seconds to wait between color updates
+    /// Duration in seconds to wait between attempts to reopen a disconnected controller
+    #[clap(long, parse(try_from_str = Self::tick_from_str), default_value = "2")]
+    reconnect_tick_duration: Duration,
+
+    /// Number of consecutive abnormal speed readings before a channel is considered stalled
+    ///
+    /// A channel is abnormal if it reads 0 RPM, or far below the RPM expected for its duty.
+    /// While any channel is stalled, all channels are forced to 100%.
+    #[clap(long, default_value = "12")]
+    stall_ticks: usize,
+
+    /// Duration in seconds to wait between frames of LED colors
+    ///
+    /// Lighting changes received between frames are merged, so that only the latest is sent,
+    /// and frames identical to the last one sent are skipped.
     #[clap(long, parse(try_from_str = Self::tick_from_str), default_value = "0.03333333333")]
     color_tick_duration: Duration,
 
     /// If set, the provided files will be watched for changes and used to update the fans' speed targets
+    ///
+    /// Applies to the first controller.
     #[clap(long)]
     fan_target_files: Vec<PathBuf>,
 
     /// If set, fan speed will be written to the provided files each tick
+    ///
+    /// Applies to the first controller.
     #[clap(long)]
     fan_speed_files: Vec<PathBuf>,
 
     /// If set, coolant temperature will be written to the provided file each tick
+    ///
+    /// Applies to the first controller.
     #[clap(long)]
     coolant_temp_file: Option<PathBuf>,
 
@@ -101,6 +229,71 @@ pub struct Capellix {
     #[clap(long, default_value = "127.0.0.1:27359")]
     listen_address: SocketAddr,
 
+    /// If set, start an OpenRGB SDK server, so OpenRGB clients can set the LED colors
+    ///
+    /// Each controller is listed as a cooler with a zone for each channel and a single Direct mode.
+    /// Colors are drawn on the default lighting layer.
+    #[clap(long)]
+    openrgb: bool,
+
+    /// Socket address to listen on when starting an OpenRGB SDK server
+    #[clap(long, default_value = "127.0.0.1:6742")]
+    openrgb_address: SocketAddr,
+
+    /// If set, start a Unix socket server to listen for commands
+    ///
+    /// capellixctl connects to this socket by default.
+    #[clap(long)]
+    listen_unix: bool,
+
+    /// Path of the Unix socket
+    ///
+    /// If unset, $XDG_RUNTIME_DIR/capellix.sock is used, or /run/capellix.sock if that's unset.
+    #[clap(long)]
+    unix_socket_path: Option<PathBuf>,
+
+    /// User name or ID to own the Unix socket
+    #[clap(long, parse(try_from_str = uid_from_str))]
+    unix_socket_owner: Option<Uid>,
+
+    /// Group name or ID to own the Unix socket
+    #[clap(long, parse(try_from_str = gid_from_str))]
+    unix_socket_group: Option<Gid>,
+
+    /// Octal permissions of the Unix socket
+    #[clap(long, parse(try_from_str = mode_from_str), default_value = "0660")]
+    unix_socket_mode: u32,
+
+    /// User name or ID permitted to connect to the Unix socket
+    ///
+    /// May be passed multiple times.
+    /// If neither this nor --allow-group is set, any user with access to the socket file may connect.
+    /// Root and the daemon's own user are always permitted.
+    #[clap(long = "allow-user", parse(try_from_str = uid_from_str))]
+    allowed_users: Vec<Uid>,
+
+    /// Group name or ID whose members are permitted to connect to the Unix socket
+    ///
+    /// May be passed multiple times.
+    #[clap(long = "allow-group", parse(try_from_str = gid_from_str))]
+    allowed_groups: Vec<Gid>,
+
+    /// If set, show the provided lighting effect on every controller at startup, ex. "chase 00ffff 1.5"
+    ///
+    /// One of:
+    ///   static <color>,
+    ///   breathing <color> [period],
+    ///   rainbow [period],
+    ///   chase <color> [period [length]],
+    ///   coolant [<cold> <hot> [<min> <max>]]
+    ///
+    /// Periods are in seconds and temperatures in degrees celsius.
+    /// Overrides any [[effects]] in the config file.
+    /// The effect is drawn on the default layer,
+    /// and runs until a client sets colors or another effect there.
+    #[clap(long)]
+    effect: Option<Effect>,
+
     /// Subtracts a factor of the provided offset from temperature readings relative to LED brightness
     #[clap(long)]
     led_temp_offset: Option<f32>,
@@ -109,7 +302,29 @@ pub struct Capellix {
     #[clap(long)]
     pump_speed_temp_offset: Option<f32>,
 
-    /// If set, don't exit when encountering a newer than expected device firmware
+    /// Coolant temperature in degrees celsius at which to log a warning
+    #[clap(long)]
+    coolant_warning_temp: Option<f32>,
+
+    /// Coolant temperature in degrees celsius at which to force all channels to 100%
+    ///
+    /// Lower targets from files and socket clients are stored but not applied until the temperature recovers.
+    #[clap(long)]
+    coolant_critical_temp: Option<f32>,
+
+    /// Degrees celsius the coolant must drop below a threshold before its condition clears
+    #[clap(long, default_value = "2")]
+    coolant_temp_hysteresis: f32,
+
+    /// If set, run the provided shell command when coolant temperature becomes critical
+    ///
+    /// The controller index and temperature are passed in the
+    /// CAPELLIX_CONTROLLER and CAPELLIX_COOLANT_TEMP environment variables.
+    #[clap(long)]
+    coolant_critical_command: Option<String>,
+
+    /// If set, fall back to the newest protocol profile when the device firmware
+    /// doesn't match any supported version range, instead of exiting
     ///
     /// Warning: In the event of a protocol change,
     /// sending unexpected commands to the controller may result in a soft brick.
@@ -118,75 +333,212 @@ pub struct Capellix {
     #[clap(long)]
     unrecognized_firmware: bool,
 
-    #[clap(skip = Hid::new().expect("Failed to initialize HID"))]
-    hid: Hid,
+    /// Serial number or hidraw path of a controller to drive, ex. /dev/hidraw3
+    ///
+    /// May be passed multiple times to drive several controllers from one daemon,
+    /// which are then addressed by index in the order given.
+    /// If unset, the first controller found will be used.
+    #[clap(long = "device")]
+    devices: Vec<DeviceSelector>,
+
+    /// If set, drive in-process simulated Commander Cores instead of physical devices
+    #[clap(long)]
+    simulate: bool,
+
+    /// If set, load configuration from the provided TOML file
+    ///
+    /// A [hardware] table is programmed into the controller at startup,
+    /// and used as a fallback whenever the daemon isn't running,
+    /// such as during boot or after a crash.
+    ///
+    /// [[curves]] entries drive fan targets from coolant temperature
+    /// until overridden by a target file or socket command,
+    /// an [rpm] table sets the gains used for RPM targets,
+    /// [[effects]] entries set the lighting effect shown at startup,
+    /// and [[outputs]] entries set the color correction applied to LED output.
+    #[clap(long)]
+    config_file: Option<PathBuf>,
+
+    /// Duration in seconds the main loop may go without handling an event
+    /// before the watchdog restores hardware mode and exits
+    #[clap(long, parse(try_from_str = Self::tick_from_str), default_value = "10")]
+    watchdog_timeout: Duration,
+
+    /// If set, notify systemd on startup and shutdown,
+    /// and ping its watchdog while the main loop is responsive
+    ///
+    /// Intended for use with Type=notify and WatchdogSec= units.
+    #[clap(long)]
+    systemd_notify: bool,
+
+    /// If set, put the controllers back into hardware mode and exit
+    ///
+    /// Intended for ExecStopPost=, to recover after the daemon is killed
+    /// without a chance to clean up, such as by SIGKILL.
+    #[clap(long)]
+    restore_hardware_mode: bool,
+
+    #[clap(skip)]
+    config: Config,
+
+    #[clap(skip)]
+    restore: HardwareRestore,
 
     #[clap(skip)]
-    state: Arc<SharedState>,
+    heartbeat: Arc<Heartbeat>,
 
-    #[clap(skip = [[0;3]; LED_COUNT_TOTAL])]
-    colors: Colors,
+    #[clap(skip)]
+    controllers: Vec<Controller>,
 }
 
 impl Capellix {
     pub fn run(self) -> Result<()> {
         let runtime = Runtime::new()?;
         let _guard = runtime.enter();
-        runtime.block_on(self.run_async())
+
+        // Unwinding releases any handle the main loop held when it panicked
+        let restore = self.restore.clone();
+        match std::panic::catch_unwind(AssertUnwindSafe(|| runtime.block_on(self.run_async()))) {
+            Ok(result) => result,
+            Err(_) => {
+                restore.restore();
+                std::process::abort();
+            }
+        }
     }
 
     pub async fn run_async(mut self) -> Result<()> {
-        // Flush any pending reads to make sure the device is in sync
-        self.hid.flush_read(50)?;
-
-        // Run HID initialization
-        info!("Setting controller to software mode");
-        self.hid.command(&set_controller_state(SOFTWARE))?;
-
-        info!("Fetching firmware version");
-        self.hid.command(GET_FIRMWARE_INFO)?;
-        let (major, minor, patch) = (self.hid.buffer[3], self.hid.buffer[4], self.hid.buffer[5]);
-        info!("Firmware version {major:}.{minor:}.{patch:}");
-
-        if major < 2 || minor < 10 || patch < 219 {
-            return Err(anyhow!(
-                "Firmware versions prior to 2.10.219 are not supported."
-            ));
-        } else if !self.unrecognized_firmware && (major > 2 || minor > 10 || patch > 219) {
-            return Err(anyhow!("Expected firmware version 2.10.219, stopping.\nTo skip this check, pass the --unrecognized-firmware flag."));
+        if let Some(path) = &self.config_file {
+            info!("Loading config from {path:?}");
+            self.config = Config::load(path)?;
         }
 
-        info!("Enabling direct lighting");
-        self.hid.request(request::enable_direct_lighting())?;
+        let selectors = if self.devices.is_empty() {
+            vec![DeviceSelector::First]
+        } else {
+            self.devices.clone()
+        };
+
+        let hids = if self.simulate {
+            selectors
+                .iter()
+                .map(|_| Hid::simulated(Simulator::default()))
+                .collect()
+        } else {
+            Hid::open_all(&selectors)?
+        };
+
+        if self.restore_hardware_mode {
+            for mut hid in hids {
+                hid.flush_read(50)?;
+                hid.send(&Request::SetControllerState(HARDWARE))?;
+            }
+            info!("Restored hardware mode");
+            return Ok(());
+        }
 
-        info!("Setting fan types to 6x QL");
-        self.hid.request(request::set_fan_types_6x_ql())?;
+        self.controllers = hids
+            .into_iter()
+            .enumerate()
+            .map(|(i, hid)| Controller::init(i, hid, self.unrecognized_firmware, &self.config))
+            .collect::<Result<Vec<_>>>()?;
 
-        // Setup threads
-        let (set_fan_speed_tx, set_fan_speed_rx) = sync::mpsc::channel::<(Fan, u16)>(14);
+        for controller in &self.controllers {
+            self.restore.set(controller.index, controller.hid.clone());
+        }
+        self.restore.install_panic_hook();
 
-        let (set_colors_tx, set_colors_rx) =
-            sync::watch::channel::<Colors>(Box::new([[0; 3]; LED_COUNT_TOTAL]));
-        let set_colors_tx = Arc::new(set_colors_tx);
+        // Setup threads
+        let mut handles = vec![];
+        let mut set_fan_speed_rxs = vec![];
+        let mut set_lighting_rxs = vec![];
+        let mut set_output_rxs = vec![];
+        for controller in &self.controllers {
+            let (set_fan_speed_tx, set_fan_speed_rx) = sync::mpsc::channel::<(Fan, FanTarget)>(14);
+            let (set_lighting_tx, set_lighting_rx) = sync::mpsc::channel::<LayerUpdate>(14);
+            let (set_output_tx, set_output_rx) =
+                sync::mpsc::channel::<(Option<Zone>, OutputSetting)>(14);
+
+            handles.push(ControllerHandle {
+                state: controller.state.clone(),
+                set_fan_speed_tx,
+                set_lighting_tx,
+                set_output_tx,
+            });
+            set_fan_speed_rxs.push(set_fan_speed_rx);
+            set_lighting_rxs.push(set_lighting_rx);
+            set_output_rxs.push(set_output_rx);
+        }
 
         let (exit_tx, exit_rx) = sync::watch::channel(true);
 
+        let watchdog_join_handle = {
+            let watchdog = WatchdogThread::new(
+                self.heartbeat.clone(),
+                self.restore.clone(),
+                exit_rx.clone(),
+                self.watchdog_timeout,
+                self.systemd_notify,
+            );
+            std::thread::spawn(move || {
+                watchdog
+                    .run()
+                    .then(print_thread_result("WatchdogThread"))
+                    .ok();
+            })
+        };
+
+        let set_fan_speed_tx = handles[0].set_fan_speed_tx.clone();
+
         let server_join_handle = if self.listen {
-            let state = self.state.clone();
-            let set_pump_speed_tx = set_fan_speed_tx.clone();
+            let handles = handles.clone();
             let exit_rx = exit_rx.clone();
             Some(spawn(async move {
-                ServerThread::new(
-                    state,
-                    set_pump_speed_tx,
-                    set_colors_tx,
-                    exit_rx,
-                    self.listen_address,
-                )
-                .run()
-                .await
-                .then(print_thread_result("ServerThread"))
-                .ok();
+                ServerThread::new(handles, exit_rx, self.listen_address)
+                    .run()
+                    .await
+                    .then(print_thread_result("ServerThread"))
+                    .ok();
+            }))
+        } else {
+            None
+        };
+
+        let openrgb_server_join_handle = if self.openrgb {
+            let handles = handles.clone();
+            let exit_rx = exit_rx.clone();
+            Some(spawn(async move {
+                OpenRgbServerThread::new(handles, exit_rx, self.openrgb_address)
+                    .run()
+                    .await
+                    .then(print_thread_result("OpenRgbServerThread"))
+                    .ok();
+            }))
+        } else {
+            None
+        };
+
+        let unix_server_join_handle = if self.listen_unix {
+            let options = UnixSocketOptions {
+                path: self
+                    .unix_socket_path
+                    .clone()
+                    .unwrap_or_else(|| unix_socket_paths().remove(0)),
+                owner: self.unix_socket_owner,
+                group: self.unix_socket_group,
+                mode: self.unix_socket_mode,
+                allowed_users: self.allowed_users.clone(),
+                allowed_groups: self.allowed_groups.clone(),
+            };
+
+            let handles = handles.clone();
+            let exit_rx = exit_rx.clone();
+            Some(spawn(async move {
+                UnixServerThread::new(handles, exit_rx, options)
+                    .run()
+                    .await
+                    .then(print_thread_result("UnixServerThread"))
+                    .ok();
             }))
         } else {
             None
@@ -219,9 +571,25 @@ impl Capellix {
             IntervalStream::new(interval(self.temp_tick_duration)).map(|_| CapellixEvent::TempTick);
         let speed_tick = IntervalStream::new(interval(self.speed_tick_duration))
             .map(|_| CapellixEvent::SpeedTick);
-        let set_pump_speed_rx = ReceiverStream::new(set_fan_speed_rx)
-            .map(|(fan, speed)| CapellixEvent::SetFanSpeed(fan, speed));
-        let set_colors_rx = WatchStream::new(set_colors_rx).map(CapellixEvent::SetColors);
+        let reconnect_tick = IntervalStream::new(interval(self.reconnect_tick_duration))
+            .map(|_| CapellixEvent::ReconnectTick);
+        let color_tick = IntervalStream::new(interval(self.color_tick_duration))
+            .map(|_| CapellixEvent::ColorTick);
+        let set_pump_speed_rx = futures::stream::select_all(
+            set_fan_speed_rxs.into_iter().enumerate().map(|(i, rx)| {
+                ReceiverStream::new(rx)
+                    .map(move |(fan, speed)| CapellixEvent::SetFanSpeed(i, fan, speed))
+            }),
+        );
+        let set_lighting_rx =
+            futures::stream::select_all(set_lighting_rxs.into_iter().enumerate().map(|(i, rx)| {
+                ReceiverStream::new(rx).map(move |update| CapellixEvent::SetLighting(i, update))
+            }));
+        let set_output_rx =
+            futures::stream::select_all(set_output_rxs.into_iter().enumerate().map(|(i, rx)| {
+                ReceiverStream::new(rx)
+                    .map(move |(zone, setting)| CapellixEvent::SetOutput(i, zone, setting))
+            }));
 
         let exit = futures::stream_select!(
             SignalStream::new(unix::signal(SignalKind::interrupt())?),
@@ -235,29 +603,87 @@ impl Capellix {
         let mut events = futures::stream_select!(
             temp_tick,
             speed_tick,
+            reconnect_tick,
+            color_tick,
             set_pump_speed_rx,
-            set_colors_rx,
+            set_lighting_rx,
+            set_output_rx,
             exit,
         );
 
+        for i in 0..self.controllers.len() {
+            let update = LayerUpdate::Draw {
+                layer: Layer::default(),
+                owner: None,
+                lighting: self.initial_lighting(&self.controllers[i]),
+            };
+            let result = self.set_lighting(i, update);
+            self.check_result(i, result);
+        }
+
+        if self.systemd_notify {
+            sd_notify::notify(false, &[NotifyState::Ready])?;
+        }
+
         while let Some(event) = events.next().await {
+            self.heartbeat.beat();
+
             match event {
                 CapellixEvent::TempTick => {
-                    self.temp_tick().await?;
+                    for i in 0..self.controllers.len() {
+                        if self.controllers[i].is_connected() {
+                            let result = self.temp_tick(i).await;
+                            self.check_result(i, result);
+                        }
+                    }
                 }
                 CapellixEvent::SpeedTick => {
-                    self.speed_tick().await?;
+                    for i in 0..self.controllers.len() {
+                        if self.controllers[i].is_connected() {
+                            let result = self.speed_tick(i).await;
+                            self.check_result(i, result);
+                        }
+                    }
+                }
+                CapellixEvent::ReconnectTick => {
+                    for i in 0..self.controllers.len() {
+                        if self.controllers[i].is_connected() || self.controllers[i].is_failed() {
+                            continue;
+                        }
+
+                        if let Err(e) = self.controllers[i].reconnect() {
+                            debug!("Controller {i:} reconnect failed: {e:}");
+                        }
+                    }
+                }
+                CapellixEvent::SetFanSpeed(i, fan, speed) => {
+                    let result = self.write_fan_target(i, fan, speed);
+                    self.check_result(i, result);
+                }
+                CapellixEvent::ColorTick => {
+                    for i in 0..self.controllers.len() {
+                        if self.controllers[i].is_connected() {
+                            let result = self.color_tick(i);
+                            self.check_result(i, result);
+                        }
+                    }
                 }
-                CapellixEvent::SetFanSpeed(fan, speed) => {
-                    self.write_fan_target(fan, speed)?;
+                CapellixEvent::SetLighting(i, update) => {
+                    let result = self.set_lighting(i, update);
+                    self.check_result(i, result);
                 }
-                CapellixEvent::SetColors(colors) => {
-                    self.write_colors(colors)?;
+                CapellixEvent::SetOutput(i, zone, setting) => {
+                    let result = self.set_output(i, zone, setting);
+                    self.check_result(i, result);
                 }
                 CapellixEvent::Exit => break,
             }
         }
 
+        if self.systemd_notify {
+            sd_notify::notify(false, &[NotifyState::Stopping])?;
+        }
+
         exit_tx.send(false)?;
 
         info!("Joining threads");
@@ -269,36 +695,120 @@ impl Capellix {
             handle.await?;
         }
 
-        info!("Setting controller to hardware mode");
-        self.hid.request([set_controller_state(HARDWARE)])?;
+        if let Some(handle) = openrgb_server_join_handle {
+            handle.await?;
+        }
+
+        if let Some(handle) = unix_server_join_handle {
+            handle.await?;
+        }
+
+        watchdog_join_handle
+            .join()
+            .map_err(|_| anyhow!("WatchdogThread panicked"))?;
+
+        for controller in &mut self.controllers {
+            if controller.is_connected() {
+                controller.release()?;
+            } else {
+                warn!(
+                    "Controller {} unavailable, leaving it in its current mode",
+                    controller.index
+                );
+            }
+        }
 
         Ok(())
     }
 
+    /// Lighting to show on a controller at startup,
+    /// which is the --effect flag, its configured effect, or its current colors
+    fn initial_lighting(&self, controller: &Controller) -> Lighting {
+        self.effect
+            .clone()
+            .or_else(|| {
+                self.config
+                    .effects
+                    .iter()
+                    .find(|effect| effect.controller == controller.index)
+                    .map(|effect| effect.effect.clone())
+            })
+            .map(Lighting::Effect)
+            .unwrap_or_else(|| Lighting::Colors(controller.colors.clone()))
+    }
+
     fn tick_from_str(s: &str) -> Result<Duration> {
         Ok(Duration::from_secs_f32(s.parse::<f32>()?))
     }
 
-    async fn temp_tick(&mut self) -> Result<()> {
+    /// Spawn the user's critical temperature command without waiting on it
+    fn run_critical_command(command: &str, index: usize, temp: u16) {
+        info!("Running coolant critical command: {command:}");
+
+        let child = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("CAPELLIX_CONTROLLER", index.to_string())
+            .env(
+                "CAPELLIX_COOLANT_TEMP",
+                format!("{:.1}", temp as f32 / 10.0),
+            )
+            .spawn();
+
+        match child {
+            // Reap the child in the background so it doesn't linger as a zombie
+            Ok(mut child) => {
+                std::thread::spawn(move || child.wait());
+            }
+            Err(e) => error!("Failed to run coolant critical command: {e:}"),
+        }
+    }
+
+    /// Mark a controller as unavailable if a request to it failed,
+    /// leaving it to be reopened by the reconnect tick
+    fn check_result(&mut self, index: usize, result: Result<()>) {
+        if let Err(e) = result {
+            self.controllers[index].disconnect(e);
+        }
+    }
+
+    async fn temp_tick(&mut self, index: usize) -> Result<()> {
         debug!("Temp tick");
 
-        self.hid.request(request::get_temp())?;
+        let controller = &mut self.controllers[index];
+        let temps = controller
+            .hid
+            .lock()
+            .send(&Request::GetTemp)?
+            .into_temperatures()?;
+
+        let temp = match temps.first() {
+            Some(temp) if temp.connected => {
+                controller.temp_sensor_connected = true;
+                temp.value
+            }
+            _ => {
+                if controller.temp_sensor_connected {
+                    warn!("Controller {index:} coolant temperature sensor not connected");
+                    controller.temp_sensor_connected = false;
+                }
+                return Ok(());
+            }
+        };
 
-        let temp = u16::from_le_bytes([self.hid.buffer[7], self.hid.buffer[8]]);
+        let pump_leds = controller.topology.led_range(0);
 
-        let temp = if let Some(offset) = self.led_temp_offset {
+        let temp = if let (Some(offset), false) = (self.led_temp_offset, pump_leds.is_empty()) {
             let offset = offset * 10.0;
 
-            let total = self
-                .colors
+            let total = controller.colors[pump_leds.clone()]
                 .iter()
-                .take(29)
                 .flatten()
                 .copied()
                 .map(|v| v as f32 / 255.0)
                 .sum::<f32>();
 
-            let total = total / (29.0 * 3.0);
+            let total = total / (pump_leds.len() as f32 * 3.0);
             let offset = (offset * total) as u16;
 
             temp - offset
@@ -309,7 +819,8 @@ impl Capellix {
         let temp = if let Some(offset) = self.pump_speed_temp_offset {
             let offset = offset * 10.0;
 
-            let total = (((self.state.pump_speed.load(Ordering::Relaxed) as f32 / 2700.0) - 0.75)
+            let total = (((controller.state.pump_speed.load(Ordering::Relaxed) as f32 / 2700.0)
+                - 0.75)
                 / 0.25)
                 .clamp(0.0, 1.0);
 
@@ -322,9 +833,29 @@ impl Capellix {
 
         debug!("Temp: {}", temp);
 
-        self.state.coolant_temp.store(temp, Ordering::Relaxed);
+        controller.state.coolant_temp.store(temp, Ordering::Relaxed);
 
-        if let Some(path) = &self.coolant_temp_file {
+        let failsafe = controller.failsafe();
+        let targets_changed = controller.apply_curves(temp as f32 / 10.0);
+
+        let limits = ThermalLimits {
+            warning: self.coolant_warning_temp,
+            critical: self.coolant_critical_temp,
+            hysteresis: self.coolant_temp_hysteresis,
+        };
+
+        if let (Some(ThermalState::Critical), Some(command)) = (
+            controller.check_thermal(temp as f32 / 10.0, &limits),
+            &self.coolant_critical_command,
+        ) {
+            Self::run_critical_command(command, index, temp);
+        }
+
+        if controller.failsafe() != failsafe || (targets_changed && !controller.failsafe()) {
+            controller.send_fan_targets()?;
+        }
+
+        if let (0, Some(path)) = (index, &self.coolant_temp_file) {
             if let Err(e) = write(path, format!("{}\n", temp * 100)).await {
                 error!("{e:}");
             }
@@ -333,61 +864,201 @@ impl Capellix {
         Ok(())
     }
 
-    async fn speed_tick(&mut self) -> Result<()> {
+    async fn speed_tick(&mut self, index: usize) -> Result<()> {
         debug!("Speed tick");
 
-        self.hid.request(request::get_speeds())?;
+        let controller = &mut self.controllers[index];
+        let speeds = controller
+            .hid
+            .lock()
+            .send(&Request::GetSpeeds)?
+            .into_speeds()?;
 
-        let pump_speed = u16::from_le_bytes([self.hid.buffer[6], self.hid.buffer[7]]);
-        let fan1_speed = u16::from_le_bytes([self.hid.buffer[8], self.hid.buffer[9]]);
-        let fan2_speed = u16::from_le_bytes([self.hid.buffer[10], self.hid.buffer[11]]);
-        let fan3_speed = u16::from_le_bytes([self.hid.buffer[12], self.hid.buffer[13]]);
-        let fan4_speed = u16::from_le_bytes([self.hid.buffer[14], self.hid.buffer[15]]);
-        let fan5_speed = u16::from_le_bytes([self.hid.buffer[16], self.hid.buffer[17]]);
-        let fan6_speed = u16::from_le_bytes([self.hid.buffer[18], self.hid.buffer[19]]);
+        debug!("Speeds: {:?}", speeds);
 
-        let speeds = [
-            pump_speed, fan1_speed, fan2_speed, fan3_speed, fan4_speed, fan5_speed, fan6_speed,
-        ];
+        let pump_speed = *speeds
+            .first()
+            .ok_or_else(|| anyhow!("Speed report contains no channels"))?;
+        controller
+            .state
+            .pump_speed
+            .store(pump_speed, Ordering::Relaxed);
 
-        debug!("Speeds: {:?}", speeds);
+        for (stored, speed) in controller.state.speeds.iter().zip(&speeds) {
+            stored.store(*speed, Ordering::Relaxed);
+        }
 
-        self.state.pump_speed.store(pump_speed, Ordering::Relaxed);
+        let targets_changed = controller.apply_rpm_targets(&speeds);
+        let failsafe_changed = controller.check_stalls(&speeds, self.stall_ticks);
+        if failsafe_changed || (targets_changed && !controller.failsafe()) {
+            controller.send_fan_targets()?;
+        }
 
-        for (path, speed) in self.fan_speed_files.iter().zip(speeds) {
-            if let Err(e) = write(path, format!("{}\n", speed)).await {
-                error!("Speed tick error: {e:}");
+        if index == 0 {
+            for (path, speed) in self.fan_speed_files.iter().zip(speeds) {
+                if let Err(e) = write(path, format!("{}\n", speed)).await {
+                    error!("Speed tick error: {e:}");
+                }
             }
         }
 
         Ok(())
     }
 
-    fn write_fan_target(&mut self, in_fan: Fan, in_speed: u16) -> Result<()> {
-        if in_speed
-            != self.state.fan_targets[u8::try_from(in_fan)? as usize].load(Ordering::Relaxed)
-        {
-            info!("Set fan {in_fan:?} target to {in_speed:}");
+    /// Override a fan's target, or hand it back to its built-in curve
+    fn write_fan_target(&mut self, index: usize, in_fan: Fan, in_target: FanTarget) -> Result<()> {
+        let controller = &mut self.controllers[index];
+        let channel = u8::from(in_fan) as usize;
+        let target = match controller.state.fan_targets.get(channel) {
+            Some(target) => target,
+            None => {
+                warn!("{in_fan:?} is not present on controller {index:}");
+                return Ok(());
+            }
+        };
+
+        let in_speed = match in_target {
+            FanTarget::Duty(speed) => {
+                controller.overridden[channel] = true;
+                controller.rpm_targets[channel] = None;
+                speed
+            }
+            FanTarget::Rpm(rpm) => {
+                info!("Set controller {index:} fan {in_fan:?} RPM target to {rpm:}");
+                controller.overridden[channel] = true;
+                controller.set_rpm_target(channel, rpm);
+                return Ok(());
+            }
+            FanTarget::Curve => {
+                if controller.curves[channel].is_some() {
+                    info!("Controller {index:} fan {in_fan:?} following built-in curve");
+                } else {
+                    warn!(
+                        "Controller {index:} fan {in_fan:?} has no curve, keeping current target"
+                    );
+                }
+                controller.overridden[channel] = false;
+                controller.rpm_targets[channel] = None;
+                return Ok(());
+            }
+        };
 
-            let idx = u8::try_from(in_fan)?;
-            self.state.fan_targets[idx as usize].store(in_speed, Ordering::Relaxed);
+        if in_speed != target.load(Ordering::Relaxed) {
+            info!("Set controller {index:} fan {in_fan:?} target to {in_speed:}");
 
-            let mut speeds = [0; 7];
-            for (i, target) in self.state.fan_targets.iter().enumerate() {
-                speeds[i] = target.load(Ordering::Relaxed);
+            target.store(in_speed, Ordering::Relaxed);
+
+            // Unavailable controllers have their targets replayed on reconnect
+            if !controller.is_connected() {
+                return Ok(());
             }
 
-            self.hid.request(request::set_speeds(speeds))?;
+            controller.send_fan_targets()?;
         }
         Ok(())
     }
 
-    fn write_colors(&mut self, in_colors: Colors) -> Result<()> {
-        debug!("Set colors");
-        let mut colors = [[0; 3]; LED_COUNT_TOTAL];
-        colors.copy_from_slice(&*in_colors);
-        self.hid.request(request::set_colors(colors))?;
-        self.colors.copy_from_slice(&colors);
+    /// Apply a change to one of a controller's lighting layers,
+    /// sending the composited frame if it may have changed
+    fn set_lighting(&mut self, index: usize, update: LayerUpdate) -> Result<()> {
+        let controller = &mut self.controllers[index];
+
+        if let LayerUpdate::Draw {
+            layer, lighting, ..
+        } = &update
+        {
+            match lighting {
+                Lighting::Colors(colors) if colors.len() != controller.topology.led_count() => {
+                    warn!(
+                        "Received {} colors for {} LEDs on controller {index:}, resizing",
+                        colors.len(),
+                        controller.topology.led_count()
+                    );
+                }
+                Lighting::Effect(effect) => {
+                    info!(
+                        "Controller {index:} layer {} showing effect {effect:}",
+                        layer.name
+                    );
+                }
+                _ => (),
+            }
+        }
+
+        let coolant_temp = controller.state.coolant_temp.load(Ordering::Relaxed) as f32 / 10.0;
+        match controller
+            .compositor
+            .apply(update, &controller.topology, coolant_temp)
+        {
+            Ok(true) => {
+                Self::queue_frame(controller);
+                Ok(())
+            }
+            Ok(false) => Ok(()),
+            Err(e) => {
+                warn!("Controller {index:} {e:}");
+                Ok(())
+            }
+        }
+    }
+
+    /// Change a controller's output correction, and resend the current frame through it on the next tick
+    fn set_output(
+        &mut self,
+        index: usize,
+        zone: Option<Zone>,
+        setting: OutputSetting,
+    ) -> Result<()> {
+        let controller = &mut self.controllers[index];
+
+        match zone {
+            Some(zone) => info!("Set controller {index:} zone {zone:} output {setting:}"),
+            None => info!("Set controller {index:} output {setting:}"),
+        }
+        // Checked by the socket thread already, but another client may have added a zone since
+        if let Err(e) = controller.state.output.lock().set(zone, setting) {
+            warn!("Controller {index:} {e:}");
+            return Ok(());
+        }
+
+        Self::queue_frame(controller);
         Ok(())
     }
+
+    /// Mark a controller's lighting as changed, to be rendered on the next color tick
+    fn queue_frame(controller: &mut Controller) {
+        if controller.frame_pending {
+            controller
+                .state
+                .frames_coalesced
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        controller.frame_pending = true;
+    }
+
+    /// Expire timed out layers, and render the next frame if lighting changed
+    /// since the last tick or any layer is animated
+    fn color_tick(&mut self, index: usize) -> Result<()> {
+        let controller = &mut self.controllers[index];
+        let expired = controller.compositor.expire();
+
+        if expired || controller.frame_pending || controller.compositor.animated() {
+            controller.frame_pending = false;
+            Self::write_frame(controller)?;
+        }
+
+        Ok(())
+    }
+
+    /// Flatten a controller's lighting layers and send the result
+    fn write_frame(controller: &mut Controller) -> Result<()> {
+        debug!("Set colors");
+
+        let coolant_temp = controller.state.coolant_temp.load(Ordering::Relaxed) as f32 / 10.0;
+        let frame = controller
+            .compositor
+            .render(&controller.topology, coolant_temp);
+
+        controller.send_colors(frame)
+    }
 }
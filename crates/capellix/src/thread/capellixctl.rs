@@ -1,22 +1,280 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
 
 use anyhow::{anyhow, Result};
-use clap::Parser;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use clap::{Parser, Subcommand};
+use serde_json::json;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
 use crate::{
-    thread::socket::socket_command::SocketCommand, thread::socket::socket_response::SocketResponse,
+    compositor::{layer_name_str, Layer},
+    effect::{color_from_str, Effect},
+    hid::topology::FanType,
+    output::OutputSetting,
+    thread::{
+        capellix::Colors,
+        monitor::Monitor,
+        pump_target::Fan,
+        socket::{
+            frame::{encode_request, read_response},
+            socket_command::{socket_command_str, SocketCommand},
+            socket_response::SocketResponse,
+            unix_socket_paths,
+        },
+    },
+    zone::Zone,
 };
 
 /// Control program for the capellix daemon
 #[derive(Parser)]
 pub struct CapellixCtl {
-    /// Socket address
-    #[clap(short, long, default_value = "127.0.0.1:27359")]
-    address: SocketAddr,
+    /// TCP socket address, ex. 127.0.0.1:27359
+    ///
+    /// If unset, the daemon's Unix socket is used instead.
+    #[clap(short, long, global = true)]
+    address: Option<SocketAddr>,
 
-    /// Command to execute
-    command: Vec<String>,
+    /// Path of the daemon's Unix socket
+    ///
+    /// If unset, $XDG_RUNTIME_DIR/capellix.sock is used if it exists, or /run/capellix.sock otherwise.
+    #[clap(short, long, global = true)]
+    socket: Option<PathBuf>,
+
+    /// Index of the controller to address, in the order passed to the daemon's --device flags
+    ///
+    /// If unset, the first controller will be used.
+    #[clap(short, long, global = true)]
+    controller: Option<u8>,
+
+    /// Print responses as JSON lines, in the same form as the socket's `format json` text mode
+    #[clap(long, global = true)]
+    json: bool,
+
+    /// Name of the lighting layer to draw colors, zones and effects on
+    ///
+    /// If unset, the default layer shared with other clients is used.
+    /// Named layers without a --timeout are removed as soon as capellixctl exits.
+    #[clap(short, long, global = true, parse(try_from_str = layer_name_from_str))]
+    layer: Option<String>,
+
+    /// Priority of the layer, where higher layers are drawn over lower ones
+    #[clap(long, global = true, default_value = "0", allow_hyphen_values = true)]
+    priority: i16,
+
+    /// Zone the layer is restricted to, ex. pump
+    #[clap(long, global = true)]
+    mask: Option<Zone>,
+
+    /// Duration in seconds after the last change before the layer is removed
+    #[clap(short, long, global = true, parse(try_from_str = seconds_from_str))]
+    timeout: Option<Duration>,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show whether the controller is connected, along with its firmware, coolant temperature and alarms
+    Status,
+    /// Read a value from the controller
+    #[clap(subcommand)]
+    Get(Get),
+    /// Change a fan target, the LED colors, the lighting effect or the output correction
+    #[clap(subcommand)]
+    Set(Set),
+    /// Drop a fan's target, handing it back to its built-in curve
+    Clear { fan: Fan },
+    /// Remove the lighting layer selected by --layer, revealing the layers below
+    ClearLayer,
+    /// Return the output correction of the device or a zone to its defaults
+    ResetOutput {
+        /// Zone to reset, or unset to reset the device correction
+        #[clap(short, long)]
+        zone: Option<Zone>,
+    },
+    /// Read a value repeatedly until interrupted
+    Watch {
+        /// Duration in seconds between reads
+        #[clap(short, long, parse(try_from_str = seconds_from_str), default_value = "1")]
+        interval: Duration,
+
+        #[clap(subcommand)]
+        value: Get,
+    },
+    /// Show a live dashboard of temperatures, speeds, alarms and LED colors
+    ///
+    /// Fan targets can be adjusted with the keyboard while it's running.
+    Monitor {
+        /// Duration in seconds between reads
+        #[clap(short, long, parse(try_from_str = seconds_from_str), default_value = "1")]
+        interval: Duration,
+    },
+    /// Print telemetry pushed by the daemon until interrupted
+    Subscribe {
+        /// Milliseconds between updates, or 0 to send an update whenever a value changes
+        #[clap(short, long, default_value = "0")]
+        interval: u16,
+    },
+    /// Send a command in the socket's text grammar, ex. `raw set-fan-target fan1 40`
+    Raw {
+        #[clap(required = true)]
+        command: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Copy, Clone)]
+enum Get {
+    /// Coolant temperature in degrees celsius
+    Temp,
+    /// Device type, speed and target of every channel
+    Speeds,
+    /// Device type, speed and target of a single channel
+    Speed { fan: Fan },
+    /// Controller firmware version
+    Firmware,
+    /// Stalled channels and the coolant temperature condition
+    Alarm,
+    /// LED colors most recently sent to the device
+    Colors,
+    /// Correction applied to LED colors before they're sent
+    Output,
+    /// Counters and HID timings for the frames of LED colors sent to the device
+    Frames,
+}
+
+impl From<Get> for SocketCommand {
+    fn from(get: Get) -> Self {
+        match get {
+            Get::Temp => SocketCommand::GetCoolantTemp,
+            Get::Speeds => SocketCommand::GetChannels,
+            Get::Speed { fan } => SocketCommand::GetChannel(fan),
+            Get::Firmware => SocketCommand::GetFirmware,
+            Get::Alarm => SocketCommand::GetAlarm,
+            Get::Colors => SocketCommand::GetColors,
+            Get::Output => SocketCommand::GetOutput,
+            Get::Frames => SocketCommand::GetFrames,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Set {
+    /// Set a fan's duty in percent, overriding its built-in curve
+    Target { fan: Fan, percent: u16 },
+    /// Drive a fan's duty towards the given RPM, overriding its built-in curve
+    Rpm { fan: Fan, rpm: u16 },
+    /// Set the LED colors, repeating the given colors across every LED
+    ///
+    /// Colors are given as hex, ex. ff8000 or #ff8000, or as decimal components, ex. 255,128,0.
+    Color {
+        #[clap(required = true, parse(try_from_str = color_from_str))]
+        colors: Vec<[u8; 3]>,
+    },
+    /// Set the colors of part of the frame, leaving the rest as it is, ex. `set zone fan2 ff0000`
+    ///
+    /// The zone is one of pump, fan<n>, an inclusive LED index range such as 29-62,
+    /// or a single LED index, counted across every channel in order.
+    /// The given colors are repeated across the zone.
+    Zone {
+        zone: Zone,
+
+        #[clap(required = true, parse(try_from_str = color_from_str))]
+        colors: Vec<[u8; 3]>,
+    },
+    /// Replace the LED colors with an effect rendered by the daemon, ex. `set effect rainbow 10`
+    ///
+    /// One of:
+    ///   static <color>,
+    ///   breathing <color> [period],
+    ///   rainbow [period],
+    ///   chase <color> [period [length]],
+    ///   coolant [<cold> <hot> [<min> <max>]]
+    ///
+    /// Periods are in seconds and temperatures in degrees celsius.
+    /// The effect runs until colors or another effect are set.
+    Effect {
+        #[clap(required = true)]
+        effect: Vec<String>,
+    },
+    /// Scale the brightness of LED output, from 0 to 1
+    Brightness {
+        brightness: f32,
+
+        /// Zone to correct, stacked on the device correction, or unset to correct the device
+        #[clap(short, long)]
+        zone: Option<Zone>,
+    },
+    /// Apply a gamma exponent to LED output, ex. 2.2
+    Gamma {
+        gamma: f32,
+
+        /// Zone to correct, stacked on the device correction, or unset to correct the device
+        #[clap(short, long)]
+        zone: Option<Zone>,
+    },
+    /// Scale the red, green and blue components of LED output, each from 0 to 1
+    WhiteBalance {
+        red: f32,
+        green: f32,
+        blue: f32,
+
+        /// Zone to correct, stacked on the device correction, or unset to correct the device
+        #[clap(short, long)]
+        zone: Option<Zone>,
+    },
+    /// Limit the average LED output to a fraction of full white, from 0 to 1
+    PowerCap {
+        cap: f32,
+
+        /// Zone to limit, or unset to limit the whole device
+        #[clap(short, long)]
+        zone: Option<Zone>,
+    },
+}
+
+/// Parse a duration in seconds
+fn seconds_from_str(s: &str) -> Result<Duration> {
+    Ok(Duration::from_secs_f32(s.parse::<f32>()?))
+}
+
+fn layer_name_from_str(s: &str) -> Result<String> {
+    match layer_name_str(s) {
+        Ok(("", name)) => Ok(name.to_string()),
+        _ => Err(anyhow!("Invalid layer name {s:?}")),
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin {}
+
+impl<T> Stream for T where T: AsyncRead + AsyncWrite + Unpin {}
+
+/// Versioned connection to the daemon, addressing a single controller
+pub struct Connection {
+    socket: Box<dyn Stream>,
+    controller: Option<u8>,
+    next_id: u16,
+}
+
+impl Connection {
+    /// Send a command and wait for its response
+    pub async fn request(&mut self, command: SocketCommand) -> Result<SocketResponse> {
+        let command = match self.controller {
+            Some(index) => SocketCommand::Controller(index, Box::new(command)),
+            None => command,
+        };
+
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        self.socket.write_all(&encode_request(id, command)).await?;
+
+        let (header, response) = read_response(&mut self.socket).await?;
+        if header.id != id {
+            return Err(anyhow!("Response ID {} does not match request", header.id));
+        }
+
+        Ok(response)
+    }
 }
 
 impl CapellixCtl {
@@ -27,36 +285,293 @@ impl CapellixCtl {
     }
 
     pub async fn run_async(self) -> Result<()> {
-        let command: SocketCommand = self
-            .command
-            .join(" ")
-            .parse()
-            .map_err(|_| anyhow!("Failed to parse command"))?;
-
-        let mut socket = tokio::net::TcpStream::connect(&self.address).await?;
-
-        socket
-            .write(&[vec![b'C', b'P', b'L', b'X'], Vec::from(command)].concat())
-            .await?;
-
-        let mut buf = [0; 16];
-        socket.read(&mut buf).await?;
-
-        match SocketResponse::try_from(&buf[..])? {
-            SocketResponse::GetCoolantTemp(temp) => println!("{temp:}"),
-            SocketResponse::GetPumpSpeed(speed) => println!("{speed:}"),
-            SocketResponse::SetPumpSpeed(success) => {
-                if !success {
-                    std::process::exit(1)
+        let mut connection = Connection {
+            socket: self.connect().await?,
+            controller: self.controller,
+            next_id: 1,
+        };
+
+        if let Some(name) = &self.layer {
+            let layer = Layer {
+                name: name.clone(),
+                priority: self.priority,
+                mask: self.mask,
+                timeout: self.timeout,
+            };
+            let response = connection.request(SocketCommand::SetLayer(layer)).await?;
+            if !matches!(response, SocketResponse::SetLayer(true)) {
+                return Err(Self::failure(response));
+            }
+        }
+
+        match self.command {
+            Command::Status => self.status(&mut connection).await,
+            Command::Get(get) => {
+                let response = connection.request(get.into()).await?;
+                self.print(response)
+            }
+            Command::Set(Set::Target { fan, percent }) => {
+                let response = connection
+                    .request(SocketCommand::SetFanTarget(fan, percent))
+                    .await?;
+                self.print(response)
+            }
+            Command::Set(Set::Rpm { fan, rpm }) => {
+                let response = connection
+                    .request(SocketCommand::SetRpmTarget(fan, rpm))
+                    .await?;
+                self.print(response)
+            }
+            Command::Set(Set::Color { ref colors }) => {
+                let colors = Self::fill_colors(&mut connection, colors).await?;
+                let response = connection.request(SocketCommand::SetColors(colors)).await?;
+                self.print(response)
+            }
+            Command::Set(Set::Zone { zone, ref colors }) => {
+                let response = connection
+                    .request(SocketCommand::SetZone(zone, colors.clone()))
+                    .await?;
+                self.print(response)
+            }
+            Command::Set(Set::Effect { ref effect }) => {
+                let effect = effect.join(" ").parse::<Effect>()?;
+                let response = connection.request(SocketCommand::SetEffect(effect)).await?;
+                self.print(response)
+            }
+            Command::Set(Set::Brightness { brightness, zone }) => {
+                let response = connection
+                    .request(SocketCommand::SetOutput(
+                        zone,
+                        OutputSetting::Brightness(brightness),
+                    ))
+                    .await?;
+                self.print(response)
+            }
+            Command::Set(Set::Gamma { gamma, zone }) => {
+                let response = connection
+                    .request(SocketCommand::SetOutput(zone, OutputSetting::Gamma(gamma)))
+                    .await?;
+                self.print(response)
+            }
+            Command::Set(Set::WhiteBalance {
+                red,
+                green,
+                blue,
+                zone,
+            }) => {
+                let response = connection
+                    .request(SocketCommand::SetOutput(
+                        zone,
+                        OutputSetting::WhiteBalance([red, green, blue]),
+                    ))
+                    .await?;
+                self.print(response)
+            }
+            Command::Set(Set::PowerCap { cap, zone }) => {
+                let response = connection
+                    .request(SocketCommand::SetOutput(zone, OutputSetting::PowerCap(cap)))
+                    .await?;
+                self.print(response)
+            }
+            Command::Clear { fan } => {
+                let response = connection
+                    .request(SocketCommand::ClearFanTarget(fan))
+                    .await?;
+                self.print(response)
+            }
+            Command::ClearLayer => {
+                let response = connection.request(SocketCommand::ClearLayer).await?;
+                self.print(response)
+            }
+            Command::ResetOutput { zone } => {
+                let response = connection
+                    .request(SocketCommand::SetOutput(zone, OutputSetting::Reset))
+                    .await?;
+                self.print(response)
+            }
+            Command::Watch { interval, value } => {
+                let mut interval = tokio::time::interval(interval);
+                loop {
+                    interval.tick().await;
+                    let response = connection.request(value.into()).await?;
+                    // Keep watching through disconnects, since the daemon replays state on reconnect
+                    if let Err(e) = self.print(response) {
+                        eprintln!("{e:}");
+                    }
                 }
             }
-            SocketResponse::SetColors(success) => {
-                if !success {
-                    std::process::exit(1)
+            Command::Monitor { interval } => {
+                Monitor::new(connection, self.controller.unwrap_or_default(), interval)
+                    .run()
+                    .await
+            }
+            Command::Subscribe { interval } => {
+                let response = connection
+                    .request(SocketCommand::Subscribe(interval))
+                    .await?;
+                self.print(response)?;
+
+                // Print pushed updates until the daemon closes the connection
+                while let Ok((_, response)) = read_response(&mut connection.socket).await {
+                    self.print(response)?;
                 }
+
+                Ok(())
+            }
+            Command::Raw { ref command } => {
+                let command = command.join(" ");
+                let command = match socket_command_str(&command) {
+                    Ok((rest, command)) if rest.trim().is_empty() => command,
+                    _ => return Err(anyhow!("Invalid command {command:?}")),
+                };
+                let response = connection.request(command).await?;
+                self.print(response)
             }
         }
+    }
+
+    async fn connect(&self) -> Result<Box<dyn Stream>> {
+        if let Some(address) = self.address {
+            let socket = tokio::net::TcpStream::connect(&address)
+                .await
+                .map_err(|e| anyhow!("Failed to connect to {address:}: {e:}"))?;
+            return Ok(Box::new(socket));
+        }
+
+        let path = self.socket.clone().unwrap_or_else(|| {
+            let mut paths = unix_socket_paths();
+            let fallback = paths.pop().unwrap();
+            paths
+                .into_iter()
+                .find(|path| path.exists())
+                .unwrap_or(fallback)
+        });
+
+        let socket = tokio::net::UnixStream::connect(&path)
+            .await
+            .map_err(|e| anyhow!("Failed to connect to {path:?}: {e:}"))?;
+        Ok(Box::new(socket))
+    }
+
+    /// Repeat the given colors across every LED on the controller
+    async fn fill_colors(connection: &mut Connection, colors: &[[u8; 3]]) -> Result<Colors> {
+        let led_count: usize = match connection.request(SocketCommand::GetChannels).await? {
+            SocketResponse::GetChannels(statuses) => statuses
+                .iter()
+                .filter_map(|status| status.fan_type.as_ref().map(FanType::led_count))
+                .sum(),
+            response => return Err(Self::failure(response)),
+        };
+
+        Ok(colors.iter().copied().cycle().take(led_count).collect())
+    }
+
+    /// Print a summary of the controller's state
+    async fn status(&self, connection: &mut Connection) -> Result<()> {
+        let connected = match connection.request(SocketCommand::GetStatus).await? {
+            SocketResponse::GetStatus(connected) => connected,
+            response => return Err(Self::failure(response)),
+        };
+
+        if !connected {
+            if !self.json {
+                return Err(anyhow!("{}", SocketResponse::GetStatus(false)));
+            }
+            println!("{}", json!({ "connected": false }));
+            return Ok(());
+        }
+
+        let firmware = match connection.request(SocketCommand::GetFirmware).await? {
+            SocketResponse::GetFirmware(firmware) => firmware,
+            response => return Err(Self::failure(response)),
+        };
+
+        let temp = match connection.request(SocketCommand::GetCoolantTemp).await? {
+            SocketResponse::GetCoolantTemp(temp) => temp,
+            response => return Err(Self::failure(response)),
+        };
+
+        let (stalled, thermal) = match connection.request(SocketCommand::GetAlarm).await? {
+            SocketResponse::GetAlarm { stalled, thermal } => (stalled, thermal),
+            response => return Err(Self::failure(response)),
+        };
+
+        if self.json {
+            println!(
+                "{}",
+                json!({
+                    "connected": true,
+                    "firmware": firmware,
+                    "coolant_temp": temp,
+                    "stalled": stalled,
+                    "thermal": thermal,
+                })
+            );
+        } else {
+            println!("connected");
+            println!("firmware {firmware:}");
+            println!("coolant {:.1}°C", temp as f32 / 10.0);
+            println!("alarm {}", SocketResponse::GetAlarm { stalled, thermal });
+        }
 
         Ok(())
     }
+
+    /// Print a response, returning an error if it reports a failure
+    fn print(&self, response: SocketResponse) -> Result<()> {
+        if self.json {
+            println!("{}", serde_json::to_string(&response)?);
+        }
+
+        match response {
+            SocketResponse::SetPumpSpeed(true)
+            | SocketResponse::ClearFanTarget(true)
+            | SocketResponse::SetRpmTarget(true)
+            | SocketResponse::SetColors(true)
+            | SocketResponse::SetZone(true)
+            | SocketResponse::SetEffect(true)
+            | SocketResponse::SetLayer(true)
+            | SocketResponse::ClearLayer(true)
+            | SocketResponse::SetOutput(true)
+            | SocketResponse::Subscribe(true)
+            | SocketResponse::Unsubscribe(true)
+            | SocketResponse::SetFormat(true) => Ok(()),
+            response @ (SocketResponse::SetPumpSpeed(false)
+            | SocketResponse::ClearFanTarget(false)
+            | SocketResponse::SetRpmTarget(false)
+            | SocketResponse::SetColors(false)
+            | SocketResponse::SetZone(false)
+            | SocketResponse::SetEffect(false)
+            | SocketResponse::SetLayer(false)
+            | SocketResponse::ClearLayer(false)
+            | SocketResponse::SetOutput(false)
+            | SocketResponse::Subscribe(false)
+            | SocketResponse::Unsubscribe(false)
+            | SocketResponse::SetFormat(false)
+            | SocketResponse::Unavailable
+            | SocketResponse::Error(_)) => Err(Self::failure(response)),
+            _ if self.json => Ok(()),
+            SocketResponse::GetCoolantTemp(temp) => {
+                println!("{:.1}°C", temp as f32 / 10.0);
+                Ok(())
+            }
+            SocketResponse::GetPumpSpeed(speed) => {
+                println!("{speed:} rpm");
+                Ok(())
+            }
+            response => {
+                println!("{response:}");
+                Ok(())
+            }
+        }
+    }
+
+    /// Describe a response that doesn't carry the expected result
+    fn failure(response: SocketResponse) -> anyhow::Error {
+        match response {
+            SocketResponse::Unavailable => anyhow!("{}", SocketResponse::Unavailable),
+            SocketResponse::Error(message) => anyhow!(message),
+            response => anyhow!("Command failed: {response:?}"),
+        }
+    }
 }
@@ -0,0 +1,472 @@
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Instant,
+};
+
+use anyhow::{anyhow, Error, Result};
+use log::{debug, error, info, warn};
+use parking_lot::Mutex;
+use tokio::sync::mpsc;
+
+use crate::{
+    compositor::{Compositor, LayerUpdate},
+    config::Config,
+    curve::FanCurve,
+    hid::{
+        profile::Profile,
+        protocol::{FirmwareVersion, Request},
+        state::{HARDWARE, SOFTWARE},
+        topology::Topology,
+        Hid, SharedHid,
+    },
+    output::{OutputSetting, OutputStage, ZoneCorrection, MAX_ZONE_CORRECTIONS},
+    rpm::RpmTarget,
+    stall::{StallDetector, StallEvent},
+    thermal::{ThermalLimits, ThermalState},
+    thread::{
+        capellix::{Colors, SharedState},
+        pump_target::{Fan, FanTarget},
+    },
+    zone::Zone,
+};
+
+/// Connection state of a controller
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ControllerStatus {
+    /// Initialized and accepting requests
+    Connected,
+    /// Disconnected, waiting for the device to return
+    Unavailable,
+    /// Returned as a different device, left in hardware mode until restarted
+    Failed,
+}
+
+/// Device state owned by the main loop for a single Commander Core
+pub struct Controller {
+    pub index: usize,
+    /// Primary handle to the device, shared with the watchdog and panic hook
+    /// so they can restore hardware mode without interleaving with a request in progress
+    pub hid: SharedHid,
+    pub topology: Topology,
+    pub state: Arc<SharedState>,
+    pub colors: Colors,
+    /// Whether `colors` has reached the device, so an identical frame can be skipped
+    pub colors_sent: bool,
+    /// Lighting layers drawn by clients and effects, flattened into `colors`
+    pub compositor: Compositor,
+    /// Whether the layers changed since the last frame was rendered
+    pub frame_pending: bool,
+    pub temp_sensor_connected: bool,
+    pub status: ControllerStatus,
+    /// Built-in temperature curve for each channel, if configured
+    pub curves: Vec<Option<FanCurve>>,
+    /// Channels whose curve is suspended by an externally set target
+    pub overridden: Vec<bool>,
+    /// Closed-loop RPM setpoint for each channel, if set
+    pub rpm_targets: Vec<Option<RpmTarget>>,
+    pub stall: StallDetector,
+    unrecognized_firmware: bool,
+    config: Config,
+}
+
+/// Channels used by other threads to address a single controller
+#[derive(Debug, Clone)]
+pub struct ControllerHandle {
+    pub state: Arc<SharedState>,
+    pub set_fan_speed_tx: mpsc::Sender<(Fan, FanTarget)>,
+    pub set_lighting_tx: mpsc::Sender<LayerUpdate>,
+    pub set_output_tx: mpsc::Sender<(Option<Zone>, OutputSetting)>,
+}
+
+impl Controller {
+    /// Put the device into software mode and probe its firmware and topology
+    pub fn init(
+        index: usize,
+        mut hid: Hid,
+        unrecognized_firmware: bool,
+        config: &Config,
+    ) -> Result<Self> {
+        let (firmware, topology) =
+            Self::initialize(index, &mut hid, unrecognized_firmware, config)?;
+        let curves = Self::curves(index, topology.channel_count(), config)?;
+
+        let state = SharedState::new(topology.channel_count());
+        state.store_device(firmware, &topology);
+        *state.output.lock() = Self::output(index, config)?;
+
+        Ok(Controller {
+            index,
+            hid: Arc::new(Mutex::new(hid)),
+            state: Arc::new(state),
+            colors: vec![[0; 3]; topology.led_count()],
+            colors_sent: false,
+            compositor: Compositor::default(),
+            frame_pending: false,
+            overridden: vec![false; topology.channel_count()],
+            rpm_targets: vec![None; topology.channel_count()],
+            stall: StallDetector::new(topology.channel_count()),
+            curves,
+            topology,
+            temp_sensor_connected: true,
+            status: ControllerStatus::Connected,
+            unrecognized_firmware,
+            config: config.clone(),
+        })
+    }
+
+    fn initialize(
+        index: usize,
+        hid: &mut Hid,
+        unrecognized_firmware: bool,
+        config: &Config,
+    ) -> Result<(FirmwareVersion, Topology)> {
+        // Flush any pending reads to make sure the device is in sync
+        hid.flush_read(50)?;
+
+        // Run HID initialization
+        info!("Setting controller {index:} to software mode");
+        hid.send(&Request::SetControllerState(SOFTWARE))?;
+
+        info!("Fetching firmware version");
+        let firmware = hid.send(&Request::GetFirmwareInfo)?.into_firmware_info()?;
+        info!("Firmware version {firmware:}");
+
+        let profile = Profile::detect(firmware, unrecognized_firmware)?;
+        info!("Using protocol profile {}", profile.name);
+        hid.set_profile(profile);
+
+        for request in profile.init {
+            info!("Sending init request {request:}");
+            hid.send(request)?;
+        }
+
+        info!("Probing connected fans");
+        let topology = Topology::probe(hid)?;
+
+        info!("Setting fan types");
+        hid.send(&Request::SetFanTypes(topology.fan_types()))?;
+
+        if let Some(hardware) = &config.hardware {
+            info!("Programming hardware mode fallback");
+            let profile = hardware.profile(topology.channel_count())?;
+            for request in profile.requests() {
+                hid.send(&request)?;
+            }
+        }
+
+        Ok((firmware, topology))
+    }
+
+    /// Build the configured curves for this controller's channels
+    fn curves(
+        index: usize,
+        channel_count: usize,
+        config: &Config,
+    ) -> Result<Vec<Option<FanCurve>>> {
+        let mut curves = vec![None; channel_count];
+
+        for curve in config
+            .curves
+            .iter()
+            .filter(|curve| curve.controller == index)
+        {
+            let fan = curve.fan()?;
+            match curves.get_mut(u8::from(fan) as usize) {
+                Some(slot) => {
+                    info!("Controller {index:} {fan:?} following built-in curve");
+                    *slot = Some(curve.curve()?);
+                }
+                None => warn!("Curve for {fan:?} targets a missing channel on controller {index:}"),
+            }
+        }
+
+        Ok(curves)
+    }
+
+    /// Build the configured color correction for this controller
+    fn output(index: usize, config: &Config) -> Result<OutputStage> {
+        let mut output = OutputStage::default();
+
+        for entry in config
+            .outputs
+            .iter()
+            .filter(|entry| entry.controller == index)
+        {
+            let correction = entry.correction.clamped();
+            match entry.zone()? {
+                Some(_) if output.zones.len() >= MAX_ZONE_CORRECTIONS => {
+                    return Err(anyhow!(
+                        "Controller {index:} has more than {MAX_ZONE_CORRECTIONS:} zone outputs configured"
+                    ));
+                }
+                Some(zone) => output.zones.push(ZoneCorrection { zone, correction }),
+                None => output.device = correction,
+            }
+        }
+
+        Ok(output)
+    }
+
+    /// Evaluate each non-overridden curve against the given coolant temperature,
+    /// storing the resulting targets
+    ///
+    /// Returns true if any target changed.
+    pub fn apply_curves(&mut self, temp: f32) -> bool {
+        let mut changed = false;
+
+        for (channel, curve) in self.curves.iter_mut().enumerate() {
+            let curve = match curve {
+                Some(curve) if !self.overridden[channel] => curve,
+                _ => continue,
+            };
+
+            let duty = curve.evaluate(temp);
+            let target = &self.state.fan_targets[channel];
+            if target.swap(duty, Ordering::Relaxed) != duty {
+                debug!(
+                    "Controller {} channel {channel:} curve target {duty:}",
+                    self.index
+                );
+                changed = true;
+            }
+        }
+
+        changed
+    }
+
+    /// Switch a channel to closed-loop control towards the given RPM,
+    /// starting from its current duty
+    pub fn set_rpm_target(&mut self, channel: usize, rpm: u16) {
+        match &mut self.rpm_targets[channel] {
+            Some(target) => target.set_rpm(rpm),
+            slot => {
+                let duty = self.state.fan_targets[channel].load(Ordering::Relaxed);
+                *slot = Some(RpmTarget::new(self.config.rpm.into(), rpm, duty));
+            }
+        }
+    }
+
+    /// Feed speed readings to each channel's RPM target, storing the resulting duties
+    ///
+    /// Returns true if any target changed.
+    pub fn apply_rpm_targets(&mut self, speeds: &[u16]) -> bool {
+        let mut changed = false;
+
+        for ((channel, target), speed) in self.rpm_targets.iter_mut().enumerate().zip(speeds) {
+            let target = match target {
+                Some(target) => target,
+                None => continue,
+            };
+
+            let duty = target.update(*speed);
+            if self.state.fan_targets[channel].swap(duty, Ordering::Relaxed) != duty {
+                debug!(
+                    "Controller {} channel {channel:} at {speed:} RPM, duty {duty:} for target {} RPM",
+                    self.index,
+                    target.rpm()
+                );
+                changed = true;
+            }
+        }
+
+        changed
+    }
+
+    /// Whether all channels are being forced to full speed
+    pub fn failsafe(&self) -> bool {
+        self.state.stalled.load(Ordering::Relaxed) != 0
+            || self.thermal_state() == ThermalState::Critical
+    }
+
+    pub fn thermal_state(&self) -> ThermalState {
+        ThermalState::try_from(self.state.thermal.load(Ordering::Relaxed)).unwrap_or_default()
+    }
+
+    /// Update the coolant condition from a temperature reading
+    ///
+    /// Returns the new state if it changed.
+    pub fn check_thermal(&mut self, temp: f32, limits: &ThermalLimits) -> Option<ThermalState> {
+        let current = self.thermal_state();
+        let next = limits.evaluate(current, temp);
+        if next == current {
+            return None;
+        }
+
+        match next {
+            ThermalState::Critical => error!(
+                "Controller {} coolant temperature critical at {temp:}°C, forcing all channels to 100%",
+                self.index
+            ),
+            ThermalState::Warning => warn!(
+                "Controller {} coolant temperature {} at {temp:}°C",
+                self.index,
+                if current > next { "recovering" } else { "high" }
+            ),
+            ThermalState::Normal => info!(
+                "Controller {} coolant temperature back to normal at {temp:}°C",
+                self.index
+            ),
+        }
+
+        self.state.thermal.store(next.into(), Ordering::Relaxed);
+        Some(next)
+    }
+
+    /// Duties currently applied to the device,
+    /// which are the stored targets unless the failsafe is active
+    pub fn effective_targets(&self) -> Vec<u16> {
+        self.state
+            .fan_targets
+            .iter()
+            .map(|target| {
+                if self.failsafe() {
+                    100
+                } else {
+                    target.load(Ordering::Relaxed)
+                }
+            })
+            .collect()
+    }
+
+    /// Apply the output correction to a frame of LED colors, then store it and send it to the device,
+    /// unless it matches the frame last sent
+    pub fn send_colors(&mut self, colors: Colors) -> Result<()> {
+        let colors = self.state.output.lock().apply(&self.topology, &colors);
+        if self.colors_sent && colors == self.colors {
+            self.state.frames_skipped.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+
+        *self.state.colors.lock() = colors.clone();
+        self.colors = colors;
+
+        // Unavailable controllers have their colors replayed on reconnect
+        if self.is_connected() {
+            let start = Instant::now();
+            self.hid
+                .lock()
+                .send(&Request::SetColors(self.colors.clone()))?;
+            self.state.record_frame(start.elapsed());
+            self.colors_sent = true;
+        }
+
+        Ok(())
+    }
+
+    /// Send the effective fan targets to the device
+    pub fn send_fan_targets(&mut self) -> Result<()> {
+        let targets = self.effective_targets();
+        self.hid.lock().send(&Request::SetSpeeds(targets))?;
+        Ok(())
+    }
+
+    /// Check speed readings for stalled channels, updating the alarm flags
+    ///
+    /// Returns true if the failsafe was engaged or released.
+    pub fn check_stalls(&mut self, speeds: &[u16], ticks: usize) -> bool {
+        let failsafe = self.failsafe();
+        let duties = self.effective_targets();
+
+        for event in self.stall.update(&self.topology, &duties, speeds, ticks) {
+            match event {
+                StallEvent::Stalled(0) => error!(
+                    "Controller {} pump failure detected at {} RPM, forcing all channels to 100%",
+                    self.index, speeds[0]
+                ),
+                StallEvent::Stalled(channel) => error!(
+                    "Controller {} channel {channel:} stall detected at {} RPM, forcing all channels to 100%",
+                    self.index, speeds[channel]
+                ),
+                StallEvent::Recovered(channel) => warn!(
+                    "Controller {} channel {channel:} recovered at {} RPM",
+                    self.index, speeds[channel]
+                ),
+            }
+        }
+
+        self.state
+            .stalled
+            .store(self.stall.mask(), Ordering::Relaxed);
+
+        if failsafe && !self.failsafe() {
+            info!("Controller {} failsafe released", self.index);
+        }
+
+        failsafe != self.failsafe()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.status == ControllerStatus::Connected
+    }
+
+    /// Mark the device as unavailable after a failed request
+    pub fn disconnect(&mut self, e: Error) {
+        error!("Controller {} unavailable: {e:}", self.index);
+        self.status = ControllerStatus::Unavailable;
+        self.state.available.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.status == ControllerStatus::Failed
+    }
+
+    /// Attempt to reopen an unavailable device,
+    /// replaying its initialization and the last fan targets and colors
+    pub fn reconnect(&mut self) -> Result<()> {
+        let (firmware, topology) = {
+            let mut hid = self.hid.lock();
+            hid.reopen()?;
+            Self::initialize(
+                self.index,
+                &mut hid,
+                self.unrecognized_firmware,
+                &self.config,
+            )?
+        };
+
+        // Per-channel state shared with other threads is sized once at startup,
+        // so a device with a different channel count is handed back to hardware mode for good
+        if topology.channel_count() != self.topology.channel_count() {
+            error!(
+                "Controller {} reconnected with {} channels instead of {}, restart capellix to use it",
+                self.index,
+                topology.channel_count(),
+                self.topology.channel_count()
+            );
+            self.status = ControllerStatus::Failed;
+            return self.release();
+        }
+
+        self.state.store_device(firmware, &topology);
+
+        if topology != self.topology {
+            warn!(
+                "Controller {} topology changed while disconnected",
+                self.index
+            );
+            self.colors.resize(topology.led_count(), [0; 3]);
+            self.topology = topology;
+        }
+
+        info!("Replaying fan targets and colors");
+        self.send_fan_targets()?;
+        self.hid
+            .lock()
+            .send(&Request::SetColors(self.colors.clone()))?;
+        self.colors_sent = true;
+
+        info!("Controller {} reconnected", self.index);
+        self.status = ControllerStatus::Connected;
+        self.state.available.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Hand the device back to its hardware-mode behavior
+    pub fn release(&mut self) -> Result<()> {
+        info!("Setting controller {} to hardware mode", self.index);
+        self.hid
+            .lock()
+            .send(&Request::SetControllerState(HARDWARE))?;
+        Ok(())
+    }
+}
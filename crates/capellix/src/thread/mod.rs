@@ -1,8 +1,14 @@
 pub mod capellix;
 pub mod capellixctl;
+pub mod controller;
+pub mod monitor;
+pub mod openrgb;
+pub mod openrgb_server_thread;
 pub mod pump_target;
 pub mod server_thread;
 pub mod socket;
+pub mod unix_server_thread;
+pub mod watchdog;
 
 use anyhow::Result;
 use log::{error, info};
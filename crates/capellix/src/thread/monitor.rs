@@ -0,0 +1,471 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use crossterm::{
+    event::{Event, EventStream, KeyCode, KeyEvent, KeyModifiers},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use futures::StreamExt;
+use tokio::time::interval;
+use tokio_stream::wrappers::IntervalStream;
+use tui::{
+    backend::{Backend, CrosstermBackend},
+    layout::{Constraint, Direction, Layout},
+    style::{Color, Modifier, Style},
+    symbols,
+    text::{Span, Spans},
+    widgets::{
+        Axis, Block, Borders, Cell, Chart, Dataset, GraphType, Paragraph, Row, Table, TableState,
+    },
+    Frame, Terminal,
+};
+
+use crate::{
+    hid::{protocol::FirmwareVersion, topology::FanType},
+    thermal::ThermalState,
+    thread::{
+        capellix::Colors,
+        capellixctl::Connection,
+        pump_target::Fan,
+        socket::{
+            socket_command::SocketCommand,
+            socket_response::{ChannelStatus, SocketResponse},
+        },
+    },
+};
+
+/// Number of coolant temperature samples kept for the history chart
+const HISTORY_LEN: usize = 300;
+
+/// Duty change applied by a single keypress, in percent
+const TARGET_STEP: i32 = 5;
+
+const HELP: &str = "↑/↓ select  ←/→ adjust target  c clear target  q quit";
+
+enum MonitorEvent {
+    Input(std::io::Result<Event>),
+    Tick,
+}
+
+/// Most recent readings from the daemon
+#[derive(Debug, Default)]
+struct Readings {
+    connected: bool,
+    firmware: Option<FirmwareVersion>,
+    /// Coolant temperature in tenths of a degree celsius
+    coolant_temp: Option<u16>,
+    channels: Vec<ChannelStatus>,
+    stalled: u8,
+    thermal: ThermalState,
+    colors: Colors,
+}
+
+/// Live terminal dashboard for a single controller
+pub struct Monitor {
+    connection: Connection,
+    controller: u8,
+    interval: Duration,
+    readings: Readings,
+    /// Coolant temperature in degrees celsius against seconds since the monitor started
+    history: VecDeque<(f64, f64)>,
+    started: Instant,
+    table: TableState,
+    /// Outcome of the last keyboard command, shown in place of the key help
+    message: Option<String>,
+}
+
+impl Monitor {
+    pub fn new(connection: Connection, controller: u8, interval: Duration) -> Self {
+        let mut table = TableState::default();
+        table.select(Some(0));
+
+        Monitor {
+            connection,
+            controller,
+            interval,
+            readings: Default::default(),
+            history: Default::default(),
+            started: Instant::now(),
+            table,
+            message: None,
+        }
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        enable_raw_mode()?;
+        let mut stdout = std::io::stdout();
+        execute!(stdout, EnterAlternateScreen)?;
+        let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+
+        let result = self.event_loop(&mut terminal).await;
+
+        // Restore the terminal before reporting a lost connection
+        disable_raw_mode()?;
+        execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+        terminal.show_cursor()?;
+
+        result
+    }
+
+    async fn event_loop<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<()> {
+        let input = EventStream::new().map(MonitorEvent::Input);
+        let tick = IntervalStream::new(interval(self.interval)).map(|_| MonitorEvent::Tick);
+
+        let mut events = futures::stream_select!(input, tick);
+
+        while let Some(event) = events.next().await {
+            match event {
+                MonitorEvent::Tick => self.refresh().await?,
+                MonitorEvent::Input(event) => {
+                    if let Event::Key(key) = event? {
+                        if !self.key(key).await? {
+                            break;
+                        }
+                    }
+                }
+            }
+
+            terminal.draw(|f| self.draw(f))?;
+        }
+
+        Ok(())
+    }
+
+    /// Send a query, returning None if the controller has become unavailable
+    async fn query(&mut self, command: SocketCommand) -> Result<Option<SocketResponse>> {
+        match self.connection.request(command).await? {
+            SocketResponse::Unavailable => {
+                self.readings.connected = false;
+                Ok(None)
+            }
+            SocketResponse::Error(message) => Err(anyhow!(message)),
+            response => Ok(Some(response)),
+        }
+    }
+
+    async fn refresh(&mut self) -> Result<()> {
+        if let Some(SocketResponse::GetStatus(connected)) =
+            self.query(SocketCommand::GetStatus).await?
+        {
+            self.readings.connected = connected;
+        }
+
+        if let Some(SocketResponse::GetColors(colors)) =
+            self.query(SocketCommand::GetColors).await?
+        {
+            self.readings.colors = colors;
+        }
+
+        if !self.readings.connected {
+            return Ok(());
+        }
+
+        if let Some(SocketResponse::GetFirmware(firmware)) =
+            self.query(SocketCommand::GetFirmware).await?
+        {
+            self.readings.firmware = Some(firmware);
+        }
+
+        if let Some(SocketResponse::GetChannels(channels)) =
+            self.query(SocketCommand::GetChannels).await?
+        {
+            self.readings.channels = channels;
+        }
+
+        if let Some(SocketResponse::GetAlarm { stalled, thermal }) =
+            self.query(SocketCommand::GetAlarm).await?
+        {
+            self.readings.stalled = stalled;
+            self.readings.thermal = thermal;
+        }
+
+        if let Some(SocketResponse::GetCoolantTemp(temp)) =
+            self.query(SocketCommand::GetCoolantTemp).await?
+        {
+            self.readings.coolant_temp = Some(temp);
+
+            self.history
+                .push_back((self.started.elapsed().as_secs_f64(), temp as f64 / 10.0));
+            while self.history.len() > HISTORY_LEN {
+                self.history.pop_front();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handle a keypress, returning false if the monitor should exit
+    async fn key(&mut self, key: KeyEvent) -> Result<bool> {
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(false),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                return Ok(false)
+            }
+            KeyCode::Up | KeyCode::Char('k') => self.select(-1),
+            KeyCode::Down | KeyCode::Char('j') => self.select(1),
+            KeyCode::Right | KeyCode::Char('+') | KeyCode::Char('=') => {
+                self.adjust(TARGET_STEP).await?
+            }
+            KeyCode::Left | KeyCode::Char('-') => self.adjust(-TARGET_STEP).await?,
+            KeyCode::Char('c') => self.clear().await?,
+            _ => (),
+        }
+
+        Ok(true)
+    }
+
+    fn select(&mut self, offset: isize) {
+        let count = self.readings.channels.len() as isize;
+        if count == 0 {
+            return;
+        }
+
+        let selected = self.table.selected().unwrap_or_default() as isize;
+        self.table
+            .select(Some((selected + offset).rem_euclid(count) as usize));
+    }
+
+    fn selected_channel(&mut self) -> Option<&mut ChannelStatus> {
+        self.readings
+            .channels
+            .get_mut(self.table.selected().unwrap_or_default())
+    }
+
+    async fn adjust(&mut self, delta: i32) -> Result<()> {
+        let status = match self.selected_channel() {
+            Some(status) => *status,
+            None => return Ok(()),
+        };
+
+        let fan = Fan::try_from(status.channel)?;
+        let target = (status.target as i32 + delta).clamp(0, 100) as u16;
+
+        self.message = Some(
+            match self
+                .connection
+                .request(SocketCommand::SetFanTarget(fan, target))
+                .await?
+            {
+                SocketResponse::SetPumpSpeed(true) => {
+                    // Show the new target straight away, so repeated presses accumulate
+                    if let Some(status) = self.selected_channel() {
+                        status.target = target;
+                    }
+                    format!("{} target set to {target:}%", channel_name(status.channel))
+                }
+                response => format!("Failed to set target: {response:}"),
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn clear(&mut self) -> Result<()> {
+        let status = match self.selected_channel() {
+            Some(status) => *status,
+            None => return Ok(()),
+        };
+
+        let fan = Fan::try_from(status.channel)?;
+
+        self.message = Some(
+            match self
+                .connection
+                .request(SocketCommand::ClearFanTarget(fan))
+                .await?
+            {
+                SocketResponse::ClearFanTarget(true) => {
+                    format!("{} handed back to its curve", channel_name(status.channel))
+                }
+                response => format!("Failed to clear target: {response:}"),
+            },
+        );
+
+        Ok(())
+    }
+
+    fn draw<B: Backend>(&mut self, f: &mut Frame<B>) {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(3),
+                Constraint::Min(8),
+                Constraint::Length(self.readings.channels.len() as u16 + 3),
+                Constraint::Length(1),
+            ])
+            .split(f.size());
+
+        f.render_widget(self.header(), chunks[0]);
+        f.render_widget(self.chart(), chunks[1]);
+
+        let table = self.table();
+        f.render_stateful_widget(table, chunks[2], &mut self.table);
+
+        let footer = Paragraph::new(self.message.as_deref().unwrap_or(HELP))
+            .style(Style::default().fg(Color::DarkGray));
+        f.render_widget(footer, chunks[3]);
+    }
+
+    fn header(&self) -> Paragraph<'_> {
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .title(format!("capellix controller {}", self.controller));
+
+        if !self.readings.connected {
+            return Paragraph::new(Span::styled(
+                "device unavailable",
+                Style::default().fg(Color::Red),
+            ))
+            .block(block);
+        }
+
+        let alarm = SocketResponse::GetAlarm {
+            stalled: self.readings.stalled,
+            thermal: self.readings.thermal,
+        };
+        let alarm_color = match (self.readings.stalled, self.readings.thermal) {
+            (0, ThermalState::Normal) => Color::Green,
+            (0, ThermalState::Warning) => Color::Yellow,
+            _ => Color::Red,
+        };
+
+        let mut spans = vec![Span::styled("connected", Style::default().fg(Color::Green))];
+
+        if let Some(firmware) = self.readings.firmware {
+            spans.push(Span::raw(format!("  firmware {firmware:}")));
+        }
+
+        if let Some(temp) = self.readings.coolant_temp {
+            spans.push(Span::raw(format!("  coolant {:.1}°C", temp as f32 / 10.0)));
+        }
+
+        spans.push(Span::raw("  alarm "));
+        spans.push(Span::styled(
+            alarm.to_string(),
+            Style::default().fg(alarm_color),
+        ));
+
+        Paragraph::new(Spans::from(spans)).block(block)
+    }
+
+    fn chart(&mut self) -> Chart<'_> {
+        let history = self.history.make_contiguous();
+
+        let (start, end) = match (history.first(), history.last()) {
+            (Some((start, _)), Some((end, _))) => (*start, end.max(start + 1.0)),
+            _ => (0.0, 1.0),
+        };
+
+        let (min, max) = history
+            .iter()
+            .fold((f64::MAX, f64::MIN), |(min, max), (_, temp)| {
+                (min.min(*temp), max.max(*temp))
+            });
+        let (min, max) = if min > max {
+            (0.0, 1.0)
+        } else {
+            ((min - 1.0).floor(), (max + 1.0).ceil())
+        };
+
+        let dataset = Dataset::default()
+            .name("coolant")
+            .marker(symbols::Marker::Braille)
+            .graph_type(GraphType::Line)
+            .style(Style::default().fg(Color::Cyan))
+            .data(history);
+
+        let labels = |values: [f64; 2], unit: &str| {
+            values
+                .iter()
+                .map(|value| Span::from(format!("{value:.0}{unit:}")))
+                .collect()
+        };
+
+        Chart::new(vec![dataset])
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title("Coolant temperature"),
+            )
+            .x_axis(
+                Axis::default()
+                    .style(Style::default().fg(Color::Gray))
+                    .bounds([start, end])
+                    .labels(labels([start, end], "s")),
+            )
+            .y_axis(
+                Axis::default()
+                    .style(Style::default().fg(Color::Gray))
+                    .bounds([min, max])
+                    .labels(labels([min, max], "°C")),
+            )
+    }
+
+    fn table(&self) -> Table<'static> {
+        // Colors are laid out channel by channel, in the order of the topology
+        let mut offset = 0;
+
+        let rows = self.readings.channels.iter().map(|status| {
+            let led_count = status
+                .fan_type
+                .as_ref()
+                .map(FanType::led_count)
+                .unwrap_or_default();
+            let leds = self
+                .readings
+                .colors
+                .iter()
+                .skip(offset)
+                .take(led_count)
+                .map(|[r, g, b]| Span::styled("█", Style::default().fg(Color::Rgb(*r, *g, *b))))
+                .collect::<Vec<_>>();
+            offset += led_count;
+
+            let style = if self.readings.stalled & 1 << status.channel != 0 {
+                Style::default().fg(Color::Red)
+            } else {
+                Style::default()
+            };
+
+            Row::new(vec![
+                Cell::from(channel_name(status.channel)),
+                Cell::from(
+                    status
+                        .fan_type
+                        .map(|fan_type| fan_type.to_string())
+                        .unwrap_or_else(|| "-".to_string()),
+                ),
+                Cell::from(status.speed.to_string()),
+                Cell::from(format!("{}%", status.target)),
+                Cell::from(Spans::from(leds)),
+            ])
+            .style(style)
+        });
+
+        Table::new(rows.collect::<Vec<_>>())
+            .header(
+                Row::new(vec!["Channel", "Device", "RPM", "Target", "LEDs"])
+                    .style(Style::default().add_modifier(Modifier::BOLD)),
+            )
+            .block(Block::default().borders(Borders::ALL).title("Channels"))
+            .widths(&[
+                Constraint::Length(8),
+                Constraint::Length(6),
+                Constraint::Length(6),
+                Constraint::Length(6),
+                Constraint::Min(10),
+            ])
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+    }
+}
+
+fn channel_name(channel: u8) -> String {
+    match channel {
+        0 => "pump".to_string(),
+        channel => format!("fan{channel:}"),
+    }
+}
@@ -0,0 +1,307 @@
+pub mod packet;
+
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use log::{debug, info, warn};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::watch;
+use tokio_util::codec::FramedRead;
+
+use crate::{
+    compositor::{Layer, LayerUpdate, Lighting},
+    thread::{
+        controller::ControllerHandle,
+        openrgb::packet::{
+            controller_data, max_payload_length, openrgb_request_bytes, reply, zone_channels,
+            Header, OpenRgbPacket, OpenRgbRequest, OPENRGB_HEADER_LENGTH, OPENRGB_PROTOCOL_VERSION,
+            OPENRGB_REQUEST_CONTROLLER_COUNT, OPENRGB_REQUEST_CONTROLLER_DATA,
+            OPENRGB_REQUEST_PROTOCOL_VERSION,
+        },
+        pump_target::Fan,
+    },
+    zone::Zone,
+};
+
+/// Splits a stream into OpenRGB SDK packets
+#[derive(Debug)]
+pub struct OpenRgbCodec {
+    buf: Vec<u8>,
+    /// Longest payload accepted, so a client can't make the buffer grow without bound
+    max_length: usize,
+}
+
+impl OpenRgbCodec {
+    pub fn new(max_length: usize) -> Self {
+        OpenRgbCodec {
+            buf: vec![],
+            max_length,
+        }
+    }
+}
+
+impl tokio_util::codec::Decoder for OpenRgbCodec {
+    type Item = OpenRgbPacket;
+
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.buf.extend(src.split_to(src.len()));
+
+        let header = match Header::decode(&self.buf)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+
+        if header.length as usize > self.max_length {
+            return Err(anyhow!(
+                "OpenRGB packet {} is {} bytes long, more than the largest valid packet",
+                header.id,
+                header.length
+            ));
+        }
+
+        let end = OPENRGB_HEADER_LENGTH + header.length as usize;
+        if self.buf.len() < end {
+            return Ok(None);
+        }
+
+        let rest = self.buf.split_off(end);
+        let payload = std::mem::replace(&mut self.buf, rest);
+
+        // Trailing bytes are tolerated, as newer clients may append fields
+        let request = openrgb_request_bytes(header.id, &payload[OPENRGB_HEADER_LENGTH..])
+            .map(|(_, request)| request)
+            .map_err(|_| anyhow!("Invalid OpenRGB packet {}", header.id));
+
+        Ok(Some(OpenRgbPacket {
+            device: header.device,
+            request,
+        }))
+    }
+}
+
+/// Serves a single OpenRGB client, drawing its colors on the default layer
+pub struct OpenRgbThread<S> {
+    controllers: Vec<ControllerHandle>,
+    exit_rx: watch::Receiver<bool>,
+    stream: S,
+}
+
+enum OpenRgbEvent {
+    Read(Result<OpenRgbPacket>),
+    /// The client closed its end of the connection
+    Closed,
+    RunningChanged(bool),
+}
+
+impl<S> OpenRgbThread<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    pub fn new(
+        controllers: Vec<ControllerHandle>,
+        exit_rx: watch::Receiver<bool>,
+        stream: S,
+    ) -> Self {
+        OpenRgbThread {
+            controllers,
+            exit_rx,
+            stream,
+        }
+    }
+
+    pub async fn run(self) -> Result<()> {
+        let (stream, mut sink) = tokio::io::split(self.stream);
+
+        // Clients that never announce a version speak version 0
+        let mut version = 0;
+
+        // Sized for the controller with the most LEDs, as any of them may be addressed
+        let led_count = self
+            .controllers
+            .iter()
+            .map(|controller| controller.state.topology.lock().led_count())
+            .max()
+            .unwrap_or_default();
+        let codec = OpenRgbCodec::new(max_payload_length(led_count));
+
+        let stream = FramedRead::new(stream, codec);
+        let exit = tokio_stream::wrappers::WatchStream::new(self.exit_rx.clone());
+
+        let mut events = futures::stream_select!(
+            stream
+                .map(OpenRgbEvent::Read)
+                .chain(futures::stream::iter([OpenRgbEvent::Closed])),
+            exit.map(OpenRgbEvent::RunningChanged)
+        );
+
+        while let Some(event) = events.next().await {
+            match event {
+                OpenRgbEvent::Read(packet) => {
+                    let OpenRgbPacket { device, request } = packet?;
+
+                    let request = match request {
+                        Ok(request) => request,
+                        Err(e) => {
+                            warn!("OpenRgbThread {e:}");
+                            continue;
+                        }
+                    };
+
+                    if let OpenRgbRequest::ProtocolVersion(client_version) = request {
+                        version = client_version.min(OPENRGB_PROTOCOL_VERSION);
+                        debug!("OpenRgbThread negotiated protocol version {version:}");
+                    }
+
+                    // OpenRGB has no error reply, so failed requests are only logged
+                    match Self::handle(&self.controllers, device, version, request).await {
+                        Ok(Some(response)) => sink.write_all(&response).await?,
+                        Ok(None) => (),
+                        Err(e) => warn!("OpenRgbThread {e:}"),
+                    }
+                }
+                OpenRgbEvent::Closed => {
+                    debug!("OpenRgbThread client closed connection");
+                    break;
+                }
+                OpenRgbEvent::RunningChanged(running) => {
+                    if !running {
+                        info!("OpenRgbThread got exit event");
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Act on a request, returning the reply to send if it expects one
+    async fn handle(
+        controllers: &[ControllerHandle],
+        device: u32,
+        version: u32,
+        request: OpenRgbRequest,
+    ) -> Result<Option<Vec<u8>>> {
+        let lighting = match request {
+            OpenRgbRequest::ControllerCount => {
+                return Ok(Some(reply(
+                    0,
+                    OPENRGB_REQUEST_CONTROLLER_COUNT,
+                    &(controllers.len() as u32).to_le_bytes(),
+                )));
+            }
+            OpenRgbRequest::ProtocolVersion(_) => {
+                return Ok(Some(reply(
+                    0,
+                    OPENRGB_REQUEST_PROTOCOL_VERSION,
+                    &OPENRGB_PROTOCOL_VERSION.to_le_bytes(),
+                )));
+            }
+            OpenRgbRequest::ControllerData(requested) => {
+                let state = &controller(controllers, device)?.state;
+                let data = controller_data(
+                    device as usize,
+                    &state.topology.lock(),
+                    *state.firmware.lock(),
+                    &state.colors.lock(),
+                    requested.min(version),
+                );
+                return Ok(Some(reply(device, OPENRGB_REQUEST_CONTROLLER_DATA, &data)));
+            }
+            OpenRgbRequest::ClientName(name) => {
+                info!("OpenRGB client {name:?} connected");
+                return Ok(None);
+            }
+            OpenRgbRequest::ResizeZone { zone, size } => {
+                return Err(anyhow!("Zone {zone:} can't be resized to {size:} LEDs"));
+            }
+            // Direct is the only mode, so there's nothing to switch to
+            OpenRgbRequest::SetCustomMode | OpenRgbRequest::UpdateMode(_) => return Ok(None),
+            OpenRgbRequest::Unsupported(id) => {
+                debug!("OpenRgbThread ignoring packet {id:}");
+                return Ok(None);
+            }
+            OpenRgbRequest::UpdateLeds(colors) => Lighting::Colors(colors),
+            OpenRgbRequest::UpdateZoneLeds(zone, colors) => {
+                let topology = controller(controllers, device)?
+                    .state
+                    .topology
+                    .lock()
+                    .clone();
+                let channel = zone_channels(&topology)
+                    .get(zone as usize)
+                    .copied()
+                    .ok_or_else(|| anyhow!("Zone {zone:} is not present"))?;
+                Lighting::Zone(Zone::Channel(Fan::try_from(channel as u8)?), colors)
+            }
+            OpenRgbRequest::UpdateSingleLed(led, color) => {
+                Lighting::Zone(Zone::Led(led as u16), vec![color])
+            }
+        };
+
+        controller(controllers, device)?
+            .set_lighting_tx
+            .send(LayerUpdate::Draw {
+                layer: Layer::default(),
+                owner: None,
+                lighting,
+            })
+            .await?;
+
+        Ok(None)
+    }
+}
+
+fn controller(controllers: &[ControllerHandle], device: u32) -> Result<&ControllerHandle> {
+    controllers
+        .get(device as usize)
+        .ok_or_else(|| anyhow!("Controller {device:} is not present"))
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio_util::codec::Decoder;
+
+    use super::*;
+    use crate::thread::openrgb::packet::{max_payload_length, OPENRGB_UPDATE_SINGLE_LED};
+
+    #[test]
+    fn splits_packets() {
+        let payload = [&3u32.to_le_bytes()[..], &[1, 2, 3, 0]].concat();
+        let bytes = [
+            reply(0, OPENRGB_UPDATE_SINGLE_LED, &payload),
+            reply(0, OPENRGB_REQUEST_CONTROLLER_COUNT, &[]),
+        ]
+        .concat();
+
+        let mut codec = OpenRgbCodec::new(max_payload_length(8));
+        let mut src = BytesMut::from(&bytes[..5]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+
+        let mut src = BytesMut::from(&bytes[5..]);
+        let packet = codec.decode(&mut src).unwrap().unwrap();
+        assert!(matches!(
+            packet.request,
+            Ok(OpenRgbRequest::UpdateSingleLed(3, [1, 2, 3]))
+        ));
+        let packet = codec.decode(&mut src).unwrap().unwrap();
+        assert!(matches!(
+            packet.request,
+            Ok(OpenRgbRequest::ControllerCount)
+        ));
+    }
+
+    #[test]
+    fn rejects_oversized_packets() {
+        let header = Header {
+            device: 0,
+            id: OPENRGB_UPDATE_SINGLE_LED,
+            length: u32::MAX,
+        };
+        let mut codec = OpenRgbCodec::new(max_payload_length(8));
+        let mut src = BytesMut::from(&header.encode()[..]);
+        assert!(codec.decode(&mut src).is_err());
+    }
+}
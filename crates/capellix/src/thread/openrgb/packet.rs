@@ -0,0 +1,376 @@
+use anyhow::{anyhow, Result};
+
+use crate::{
+    hid::{protocol::FirmwareVersion, topology::Topology},
+    thread::capellix::Colors,
+};
+
+/// Prefix of every OpenRGB SDK packet
+pub const OPENRGB_MAGIC: &[u8; 4] = b"ORGB";
+
+/// Newest SDK protocol version understood by this build
+pub const OPENRGB_PROTOCOL_VERSION: u32 = 1;
+
+/// Magic, device index, packet ID and payload length
+pub const OPENRGB_HEADER_LENGTH: usize = 4 + 4 + 4 + 4;
+
+/// Room in a request beyond its colors, for names and mode descriptions
+pub const OPENRGB_PAYLOAD_SLACK: usize = 1024;
+
+pub const OPENRGB_REQUEST_CONTROLLER_COUNT: u32 = 0;
+pub const OPENRGB_REQUEST_CONTROLLER_DATA: u32 = 1;
+pub const OPENRGB_REQUEST_PROTOCOL_VERSION: u32 = 40;
+pub const OPENRGB_SET_CLIENT_NAME: u32 = 50;
+pub const OPENRGB_RESIZE_ZONE: u32 = 1000;
+pub const OPENRGB_UPDATE_LEDS: u32 = 1050;
+pub const OPENRGB_UPDATE_ZONE_LEDS: u32 = 1051;
+pub const OPENRGB_UPDATE_SINGLE_LED: u32 = 1052;
+pub const OPENRGB_SET_CUSTOM_MODE: u32 = 1100;
+pub const OPENRGB_UPDATE_MODE: u32 = 1101;
+
+pub const OPENRGB_DEVICE_TYPE_COOLER: i32 = 3;
+pub const OPENRGB_ZONE_TYPE_LINEAR: i32 = 1;
+pub const OPENRGB_MODE_FLAG_HAS_PER_LED_COLOR: u32 = 1 << 5;
+pub const OPENRGB_COLOR_MODE_PER_LED: u32 = 1;
+
+/// Name of the only mode offered, in which clients set each LED directly
+pub const OPENRGB_MODE_DIRECT: &str = "Direct";
+
+/// Parsed packet header
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Header {
+    /// Index of the addressed controller
+    pub device: u32,
+    pub id: u32,
+    pub length: u32,
+}
+
+impl Header {
+    pub fn encode(&self) -> Vec<u8> {
+        [
+            &OPENRGB_MAGIC[..],
+            &self.device.to_le_bytes()[..],
+            &self.id.to_le_bytes()[..],
+            &self.length.to_le_bytes()[..],
+        ]
+        .concat()
+    }
+
+    /// Parse a header from the start of the given buffer,
+    /// returning None if it isn't complete yet
+    pub fn decode(buf: &[u8]) -> Result<Option<Self>> {
+        if buf.len() < OPENRGB_HEADER_LENGTH {
+            return Ok(None);
+        }
+
+        if &buf[..4] != OPENRGB_MAGIC {
+            return Err(anyhow!("Invalid OpenRGB magic {:02x?}", &buf[..4]));
+        }
+
+        let field = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+
+        Ok(Some(Header {
+            device: field(4),
+            id: field(8),
+            length: field(12),
+        }))
+    }
+}
+
+/// Largest payload a client needs to send to a controller with the given number of LEDs,
+/// which is an UpdateZoneLEDs packet covering all of them along with some slack
+pub fn max_payload_length(led_count: usize) -> usize {
+    // Data size, zone index and color count
+    4 + 4 + 2 + led_count * 4 + OPENRGB_PAYLOAD_SLACK
+}
+
+/// Request received from an OpenRGB client
+#[derive(Debug, Clone)]
+pub enum OpenRgbRequest {
+    ControllerCount,
+    /// Query a controller's description, encoded for the given protocol version
+    ControllerData(u32),
+    /// Announce the client's protocol version, expecting the server's in reply
+    ProtocolVersion(u32),
+    ClientName(String),
+    /// Resize a zone, which the fixed LED counts of a Commander Core don't allow
+    ResizeZone {
+        zone: u32,
+        size: u32,
+    },
+    /// Set every LED of a controller
+    UpdateLeds(Colors),
+    /// Set every LED of a zone
+    UpdateZoneLeds(u32, Colors),
+    UpdateSingleLed(u32, [u8; 3]),
+    /// Switch to the mode in which LEDs are set directly
+    SetCustomMode,
+    /// Switch to the mode at the given index
+    UpdateMode(u32),
+    /// Packet with an ID this server doesn't handle
+    Unsupported(u32),
+}
+
+/// Request along with the controller it addresses
+#[derive(Debug)]
+pub struct OpenRgbPacket {
+    pub device: u32,
+    /// Decoded request, or the reason its payload couldn't be parsed
+    pub request: Result<OpenRgbRequest>,
+}
+
+/// Encode a reply to the given controller and packet ID
+pub fn reply(device: u32, id: u32, payload: &[u8]) -> Vec<u8> {
+    let header = Header {
+        device,
+        id,
+        length: payload.len() as u32,
+    };
+    [&header.encode()[..], payload].concat()
+}
+
+/// Channels presented as zones, in zone index order
+///
+/// Channels without LEDs are left out, since OpenRGB zones can't be empty.
+pub fn zone_channels(topology: &Topology) -> Vec<usize> {
+    (0..topology.channel_count())
+        .filter(|channel| !topology.led_range(*channel).is_empty())
+        .collect()
+}
+
+/// Describe a controller as a cooler with a zone for each channel and a single Direct mode,
+/// encoded as an OpenRGB controller data block for the given protocol version
+pub fn controller_data(
+    index: usize,
+    topology: &Topology,
+    firmware: Option<FirmwareVersion>,
+    colors: &[[u8; 3]],
+    version: u32,
+) -> Vec<u8> {
+    let channels = zone_channels(topology);
+    let channel_name = |channel: usize| match channel {
+        0 => "Pump".to_string(),
+        channel => format!("Fan {channel:}"),
+    };
+
+    let mut data = vec![];
+    data.extend(OPENRGB_DEVICE_TYPE_COOLER.to_le_bytes());
+    data.extend(encode_string("Corsair Commander Core"));
+    if version >= 1 {
+        data.extend(encode_string("Corsair"));
+    }
+    data.extend(encode_string("Commander Core driven by capellix"));
+    data.extend(encode_string(
+        &firmware
+            .map(|firmware| firmware.to_string())
+            .unwrap_or_default(),
+    ));
+    data.extend(encode_string(""));
+    data.extend(encode_string(&format!("capellix controller {index:}")));
+
+    // Modes, with the active mode index
+    data.extend(1u16.to_le_bytes());
+    data.extend(0i32.to_le_bytes());
+    data.extend(encode_string(OPENRGB_MODE_DIRECT));
+    data.extend(0i32.to_le_bytes());
+    data.extend(OPENRGB_MODE_FLAG_HAS_PER_LED_COLOR.to_le_bytes());
+    for value in [0u32, 0, 0, 0, 0, 0, OPENRGB_COLOR_MODE_PER_LED] {
+        // Speed range, color count range, speed, direction and color mode
+        data.extend(value.to_le_bytes());
+    }
+    data.extend(0u16.to_le_bytes());
+
+    data.extend((channels.len() as u16).to_le_bytes());
+    for channel in &channels {
+        let led_count = topology.led_range(*channel).len() as u32;
+        data.extend(encode_string(&channel_name(*channel)));
+        data.extend(OPENRGB_ZONE_TYPE_LINEAR.to_le_bytes());
+        for value in [led_count, led_count, led_count] {
+            // Minimum, maximum and current LED count
+            data.extend(value.to_le_bytes());
+        }
+        // No matrix map
+        data.extend(0u16.to_le_bytes());
+    }
+
+    let led_count = topology.led_count();
+    data.extend((led_count as u16).to_le_bytes());
+    for channel in &channels {
+        for (i, led) in topology.led_range(*channel).enumerate() {
+            data.extend(encode_string(&format!(
+                "{} LED {}",
+                channel_name(*channel),
+                i + 1
+            )));
+            data.extend((led as u32).to_le_bytes());
+        }
+    }
+
+    data.extend((led_count as u16).to_le_bytes());
+    for i in 0..led_count {
+        data.extend(encode_color(colors.get(i).copied().unwrap_or_default()));
+    }
+
+    // The size is inclusive of its own field
+    [&((data.len() + 4) as u32).to_le_bytes()[..], &data[..]].concat()
+}
+
+/// Encode as `[length (LE u16), bytes..., 0]`, where the length counts the terminator
+fn encode_string(s: &str) -> Vec<u8> {
+    [
+        &((s.len() + 1) as u16).to_le_bytes()[..],
+        s.as_bytes(),
+        &[0][..],
+    ]
+    .concat()
+}
+
+/// Encode as `[r, g, b, 0]`
+fn encode_color([r, g, b]: [u8; 3]) -> [u8; 4] {
+    [r, g, b, 0]
+}
+
+/// Parse the payload of the packet with the given ID
+pub fn openrgb_request_bytes(id: u32, input: &[u8]) -> nom::IResult<&[u8], OpenRgbRequest> {
+    match id {
+        OPENRGB_REQUEST_CONTROLLER_COUNT => Ok((input, OpenRgbRequest::ControllerCount)),
+        // Clients older than protocol version 1 send no version
+        OPENRGB_REQUEST_CONTROLLER_DATA => nom::combinator::map(
+            nom::combinator::opt(nom::number::complete::le_u32),
+            |version| OpenRgbRequest::ControllerData(version.unwrap_or_default()),
+        )(input),
+        OPENRGB_REQUEST_PROTOCOL_VERSION => nom::combinator::map(
+            nom::number::complete::le_u32,
+            OpenRgbRequest::ProtocolVersion,
+        )(input),
+        OPENRGB_SET_CLIENT_NAME => {
+            nom::combinator::map(nom::bytes::complete::take_till(|b| b == 0), |name| {
+                OpenRgbRequest::ClientName(String::from_utf8_lossy(name).into_owned())
+            })(input)
+        }
+        OPENRGB_RESIZE_ZONE => nom::combinator::map(
+            nom::sequence::pair(nom::number::complete::le_u32, nom::number::complete::le_u32),
+            |(zone, size)| OpenRgbRequest::ResizeZone { zone, size },
+        )(input),
+        OPENRGB_UPDATE_LEDS => nom::combinator::map(
+            nom::sequence::preceded(nom::number::complete::le_u32, colors_bytes),
+            OpenRgbRequest::UpdateLeds,
+        )(input),
+        OPENRGB_UPDATE_ZONE_LEDS => nom::combinator::map(
+            nom::sequence::preceded(
+                nom::number::complete::le_u32,
+                nom::sequence::pair(nom::number::complete::le_u32, colors_bytes),
+            ),
+            |(zone, colors)| OpenRgbRequest::UpdateZoneLeds(zone, colors),
+        )(input),
+        OPENRGB_UPDATE_SINGLE_LED => nom::combinator::map(
+            nom::sequence::pair(nom::number::complete::le_u32, color_bytes),
+            |(led, color)| OpenRgbRequest::UpdateSingleLed(led, color),
+        )(input),
+        OPENRGB_SET_CUSTOM_MODE => Ok((input, OpenRgbRequest::SetCustomMode)),
+        // The mode description that follows the index is ignored, since Direct has no settings
+        OPENRGB_UPDATE_MODE => nom::combinator::map(
+            nom::sequence::preceded(nom::number::complete::le_u32, nom::number::complete::le_u32),
+            OpenRgbRequest::UpdateMode,
+        )(input),
+        id => Ok((input, OpenRgbRequest::Unsupported(id))),
+    }
+}
+
+fn color_bytes(input: &[u8]) -> nom::IResult<&[u8], [u8; 3]> {
+    let (input, color) = nom::bytes::complete::take(4usize)(input)?;
+    Ok((input, [color[0], color[1], color[2]]))
+}
+
+fn colors_bytes(input: &[u8]) -> nom::IResult<&[u8], Colors> {
+    nom::multi::length_count(nom::number::complete::le_u16, color_bytes)(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn colors(colors: &[[u8; 3]]) -> Vec<u8> {
+        [
+            &(colors.len() as u16).to_le_bytes()[..],
+            &colors
+                .iter()
+                .flat_map(|color| encode_color(*color))
+                .collect::<Vec<_>>()[..],
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn round_trips_header() {
+        let header = Header {
+            device: 1,
+            id: OPENRGB_UPDATE_LEDS,
+            length: 300,
+        };
+        assert_eq!(Header::decode(&header.encode()).unwrap(), Some(header));
+        assert_eq!(Header::decode(&header.encode()[..15]).unwrap(), None);
+        assert!(Header::decode(b"ORGX\0\0\0\0\0\0\0\0\0\0\0\0").is_err());
+    }
+
+    #[test]
+    fn decodes_update_leds() {
+        let payload = [&0u32.to_le_bytes()[..], &colors(&[[1, 2, 3], [4, 5, 6]])].concat();
+        let (rest, request) = openrgb_request_bytes(OPENRGB_UPDATE_LEDS, &payload).unwrap();
+        assert!(rest.is_empty());
+        assert!(matches!(
+            request,
+            OpenRgbRequest::UpdateLeds(colors) if colors == vec![[1, 2, 3], [4, 5, 6]]
+        ));
+    }
+
+    #[test]
+    fn decodes_update_zone_leds() {
+        let payload = [
+            &0u32.to_le_bytes()[..],
+            &2u32.to_le_bytes()[..],
+            &colors(&[[7, 8, 9]]),
+        ]
+        .concat();
+        let (_, request) = openrgb_request_bytes(OPENRGB_UPDATE_ZONE_LEDS, &payload).unwrap();
+        assert!(matches!(
+            request,
+            OpenRgbRequest::UpdateZoneLeds(2, colors) if colors == vec![[7, 8, 9]]
+        ));
+    }
+
+    #[test]
+    fn decodes_controller_data_version() {
+        let (_, request) = openrgb_request_bytes(OPENRGB_REQUEST_CONTROLLER_DATA, &[]).unwrap();
+        assert!(matches!(request, OpenRgbRequest::ControllerData(0)));
+
+        let (_, request) =
+            openrgb_request_bytes(OPENRGB_REQUEST_CONTROLLER_DATA, &1u32.to_le_bytes()).unwrap();
+        assert!(matches!(request, OpenRgbRequest::ControllerData(1)));
+    }
+
+    #[test]
+    fn rejects_truncated_colors() {
+        let payload = [&0u32.to_le_bytes()[..], &colors(&[[1, 2, 3], [4, 5, 6]])].concat();
+        assert!(openrgb_request_bytes(OPENRGB_UPDATE_LEDS, &payload[..payload.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn passes_through_unsupported_packets() {
+        let (_, request) = openrgb_request_bytes(9999, b"anything").unwrap();
+        assert!(matches!(request, OpenRgbRequest::Unsupported(9999)));
+    }
+
+    #[test]
+    fn leaves_out_channels_without_leds() {
+        let topology = Topology::with_led_counts(&[29, 0, 34]);
+        assert_eq!(zone_channels(&topology), vec![0, 2]);
+
+        // The size field at the start counts the whole block
+        let data = controller_data(0, &topology, None, &[], 1);
+        assert_eq!(
+            u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize,
+            data.len()
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use log::info;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    task::{spawn, JoinHandle},
+};
+use tokio_stream::{
+    wrappers::{TcpListenerStream, WatchStream},
+    StreamExt,
+};
+
+use crate::{
+    then::Then,
+    thread::{controller::ControllerHandle, openrgb::OpenRgbThread, print_thread_result},
+};
+
+/// Accepts OpenRGB SDK clients, so OpenRGB and its client libraries can set the LED colors
+#[derive(Debug)]
+pub struct OpenRgbServerThread {
+    controllers: Vec<ControllerHandle>,
+    exit_rx: watch::Receiver<bool>,
+    address: SocketAddr,
+    sockets: Vec<JoinHandle<()>>,
+}
+
+enum OpenRgbServerEvent {
+    Connection(tokio::io::Result<TcpStream>),
+    RunningChanged(bool),
+}
+
+impl OpenRgbServerThread {
+    pub fn new(
+        controllers: Vec<ControllerHandle>,
+        exit_rx: watch::Receiver<bool>,
+        address: SocketAddr,
+    ) -> Self {
+        OpenRgbServerThread {
+            controllers,
+            exit_rx,
+            address,
+            sockets: vec![],
+        }
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        let listener = TcpListenerStream::new(TcpListener::bind(&self.address).await?);
+
+        let exit_rx = self.exit_rx.clone();
+        let exit = WatchStream::new(self.exit_rx.clone());
+
+        info!("OpenRGB server listening on {:?}", self.address);
+
+        let mut events = futures::stream_select!(
+            listener.map(OpenRgbServerEvent::Connection),
+            exit.map(OpenRgbServerEvent::RunningChanged),
+        );
+
+        while let Some(event) = events.next().await {
+            match event {
+                OpenRgbServerEvent::Connection(stream) => {
+                    let stream = stream?;
+                    stream.set_nodelay(true)?;
+
+                    info!("Accepted OpenRGB connection");
+                    let controllers = self.controllers.clone();
+                    let exit_rx = exit_rx.clone();
+
+                    let join_handle = spawn(async move {
+                        OpenRgbThread::new(controllers, exit_rx, stream)
+                            .run()
+                            .await
+                            .then(print_thread_result("OpenRgbThread"))
+                            .ok();
+                    });
+
+                    self.sockets.push(join_handle);
+                }
+                OpenRgbServerEvent::RunningChanged(running) => {
+                    if !running {
+                        info!("OpenRgbServerThread received Exit event");
+                        break;
+                    }
+                }
+            }
+        }
+
+        for handle in self.sockets.into_iter() {
+            handle.await?;
+        }
+
+        Ok(())
+    }
+}
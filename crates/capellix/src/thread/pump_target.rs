@@ -12,7 +12,7 @@ use tokio::{
 
 use crate::hid::validate_fan_speed;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Fan {
     Pump,
     Fan(u8),
@@ -56,9 +56,20 @@ impl FromStr for Fan {
     }
 }
 
+/// Target requested for a fan channel by a target file or socket client
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FanTarget {
+    /// Fixed duty percentage
+    Duty(u16),
+    /// Closed-loop RPM setpoint
+    Rpm(u16),
+    /// Hand the channel back to its built-in curve
+    Curve,
+}
+
 #[derive(Debug)]
 pub struct FanTargetThread {
-    set_pump_speed_tx: mpsc::Sender<(Fan, u16)>,
+    set_pump_speed_tx: mpsc::Sender<(Fan, FanTarget)>,
     exit_rx: watch::Receiver<bool>,
     fan: Fan,
     path: PathBuf,
@@ -71,7 +82,7 @@ enum FanTargetEvent {
 
 impl FanTargetThread {
     pub fn new(
-        set_pump_speed_tx: mpsc::Sender<(Fan, u16)>,
+        set_pump_speed_tx: mpsc::Sender<(Fan, FanTarget)>,
         exit_tx: watch::Receiver<bool>,
         fan: Fan,
         path: PathBuf,
@@ -94,7 +105,7 @@ impl FanTargetThread {
                 let file_string = file_string.strip_suffix('\n').unwrap_or(&file_string);
                 if let Ok(speed) = file_string.parse::<u16>() {
                     let speed = validate_fan_speed(speed);
-                    set_pump_speed_tx.send((fan, speed)).await?;
+                    set_pump_speed_tx.send((fan, FanTarget::Duty(speed))).await?;
                 } else {
                     warn!("invalid pump speed, resetting file");
                     write(&path, "100\n").await?;
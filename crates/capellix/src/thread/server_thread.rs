@@ -1,10 +1,10 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::net::SocketAddr;
 
 use anyhow::Result;
-use log::{debug, info};
+use log::{debug, error, info};
 use tokio::{
     net::{TcpListener, TcpStream, UdpSocket},
-    sync::{watch, mpsc},
+    sync::watch,
     task::{spawn, JoinHandle},
 };
 use tokio_stream::{
@@ -13,21 +13,20 @@ use tokio_stream::{
 };
 
 use crate::{
+    compositor::Layer,
     then::Then,
     thread::{
-        capellix::SharedState,
+        controller::ControllerHandle,
         print_thread_result,
-        socket::{SocketCommandCodec, SocketThread},
+        socket::{legacy_led_count, SocketCommandCodec, SocketThread},
     },
 };
 
-use super::{capellix::Colors, pump_target::Fan, socket::socket_command::SocketCommand};
+use super::socket::frame::SocketRequest;
 
 #[derive(Debug)]
 pub struct ServerThread {
-    state: Arc<SharedState>,
-    set_pump_speed_tx: mpsc::Sender<(Fan, u16)>,
-    set_colors_tx: Arc<watch::Sender<Colors>>,
+    controllers: Vec<ControllerHandle>,
     exit_rx: watch::Receiver<bool>,
     address: SocketAddr,
     sockets: Vec<JoinHandle<()>>,
@@ -35,22 +34,18 @@ pub struct ServerThread {
 
 enum ServerEvent {
     TcpConnection(tokio::io::Result<TcpStream>),
-    UdpPacket(Result<(SocketCommand, SocketAddr)>),
+    UdpPacket(Result<(SocketRequest, SocketAddr)>),
     RunningChanged(bool),
 }
 
 impl ServerThread {
     pub fn new(
-        state: Arc<SharedState>,
-        set_pump_speed_tx: mpsc::Sender<(Fan, u16)>,
-        set_colors_tx: Arc<watch::Sender<Colors>>,
+        controllers: Vec<ControllerHandle>,
         exit_rx: watch::Receiver<bool>,
         address: SocketAddr,
     ) -> Self {
         ServerThread {
-            state,
-            set_pump_speed_tx,
-            set_colors_tx,
+            controllers,
             exit_rx,
             address,
             sockets: vec![],
@@ -62,7 +57,7 @@ impl ServerThread {
 
         let udp_listener = tokio_util::udp::UdpFramed::new(
             UdpSocket::bind(&self.address).await?,
-            SocketCommandCodec::default(),
+            SocketCommandCodec::new(legacy_led_count(&self.controllers)),
         );
 
         let exit_rx = self.exit_rx.clone();
@@ -80,15 +75,14 @@ impl ServerThread {
             match event {
                 ServerEvent::TcpConnection(stream) => {
                     let stream = stream?;
+                    stream.set_nodelay(true)?;
 
                     info!("Accepted TCP connection");
-                    let state = self.state.clone();
-                    let set_pump_speed_tx = self.set_pump_speed_tx.clone();
-                    let set_colors_tx = self.set_colors_tx.clone();
+                    let controllers = self.controllers.clone();
                     let exit_rx = exit_rx.clone();
 
                     let join_handle = spawn(async move {
-                        SocketThread::new(state, set_pump_speed_tx, set_colors_tx, exit_rx, stream)
+                        SocketThread::new(controllers, exit_rx, stream)
                             .run()
                             .await
                             .then(print_thread_result("SocketThread"))
@@ -98,16 +92,23 @@ impl ServerThread {
                     self.sockets.push(join_handle);
                 }
                 ServerEvent::UdpPacket(packet) => {
-                    let (command, _) = packet?;
+                    let (request, _) = packet?;
                     debug!("Received UDP packet");
-                    command
-                        .run(
-                            &self.state,
-                            &self.set_pump_speed_tx,
-                            &self.set_colors_tx,
-                            vec![],
-                        )
-                        .await?;
+
+                    // UDP commands are fire-and-forget, so responses are discarded,
+                    // and lighting is drawn on the default layer
+                    let result = match request.command {
+                        Ok(command) => {
+                            command
+                                .run(&self.controllers, &Layer::default(), None)
+                                .await
+                        }
+                        Err(e) => Err(e),
+                    };
+
+                    if let Err(e) = result {
+                        error!("UDP command error: {e:}");
+                    }
                 }
                 ServerEvent::RunningChanged(running) => {
                     if !running {
@@ -0,0 +1,146 @@
+use anyhow::{anyhow, Result};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::thread::socket::{socket_command::SocketCommand, socket_response::SocketResponse};
+
+/// Prefix of a legacy frame, which carries a bare command with no length or request ID
+pub const SOCKET_MAGIC_V1: &[u8; 4] = b"CPLX";
+
+/// Prefix of a versioned frame
+pub const SOCKET_MAGIC_V2: &[u8; 4] = b"CPX2";
+
+/// Newest protocol version understood by this build
+pub const SOCKET_PROTOCOL_VERSION: u8 = 2;
+
+/// Magic, version, request ID and payload length
+pub const SOCKET_HEADER_LENGTH: usize = 4 + 1 + 2 + 2;
+
+/// Framing a request arrived in, which its response is sent back in
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Framing {
+    /// Legacy `CPLX` frame, answered with a bare response
+    V1,
+    /// `CPX2` frame with a header carrying the negotiated version,
+    /// the request ID to echo, and the payload length
+    V2 { version: u8, id: u16 },
+    /// Newline-terminated text command, answered with a line in the given format
+    Text(TextFormat),
+}
+
+/// Response format for text connections
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TextFormat {
+    /// Human-readable line, as printed by capellixctl
+    Plain,
+    /// JSON object of the form `{"response": "get-coolant-temp", "value": 312}`
+    Json,
+}
+
+/// Command received from a client, along with how to frame its response
+#[derive(Debug)]
+pub struct SocketRequest {
+    pub framing: Framing,
+    /// Decoded command, or the reason its payload couldn't be parsed
+    pub command: Result<SocketCommand>,
+}
+
+/// Parsed versioned frame header
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub id: u16,
+    pub length: u16,
+}
+
+impl Header {
+    pub fn encode(&self) -> Vec<u8> {
+        [
+            &SOCKET_MAGIC_V2[..],
+            &[self.version],
+            &self.id.to_le_bytes()[..],
+            &self.length.to_le_bytes()[..],
+        ]
+        .concat()
+    }
+
+    /// Parse a header from the start of the given buffer,
+    /// returning None if it isn't complete yet
+    pub fn decode(buf: &[u8]) -> Result<Option<Self>> {
+        if buf.len() < SOCKET_HEADER_LENGTH {
+            return Ok(None);
+        }
+
+        if &buf[..4] != SOCKET_MAGIC_V2 {
+            return Err(anyhow!("Invalid frame magic {:02x?}", &buf[..4]));
+        }
+
+        Ok(Some(Header {
+            version: buf[4],
+            id: u16::from_le_bytes([buf[5], buf[6]]),
+            length: u16::from_le_bytes([buf[7], buf[8]]),
+        }))
+    }
+}
+
+impl Framing {
+    /// Framing for a response to a versioned request,
+    /// settling on the newest version both sides understand
+    pub fn negotiate(header: &Header) -> Self {
+        Framing::V2 {
+            version: header.version.clamp(2, SOCKET_PROTOCOL_VERSION),
+            id: header.id,
+        }
+    }
+
+    /// Encode a response in this framing
+    pub fn encode(&self, response: SocketResponse) -> Vec<u8> {
+        match self {
+            Framing::V1 => response.into_legacy_bytes(),
+            Framing::V2 { version, id } => {
+                let payload: Vec<u8> = response.into();
+                let header = Header {
+                    version: *version,
+                    id: *id,
+                    length: payload.len() as u16,
+                };
+                [header.encode(), payload].concat()
+            }
+            Framing::Text(TextFormat::Plain) => match response {
+                SocketResponse::Error(message) => format!("error: {message:}\n").into_bytes(),
+                response => format!("{response:}\n").into_bytes(),
+            },
+            Framing::Text(TextFormat::Json) => {
+                let mut line = serde_json::to_vec(&response).unwrap_or_else(|e| {
+                    serde_json::to_vec(&SocketResponse::Error(e.to_string())).unwrap_or_default()
+                });
+                line.push(b'\n');
+                line
+            }
+        }
+    }
+}
+
+/// Encode a versioned request frame
+pub fn encode_request(id: u16, command: SocketCommand) -> Vec<u8> {
+    let payload: Vec<u8> = command.into();
+    let header = Header {
+        version: SOCKET_PROTOCOL_VERSION,
+        id,
+        length: payload.len() as u16,
+    };
+    [header.encode(), payload].concat()
+}
+
+/// Read a single versioned response frame
+pub async fn read_response(
+    reader: &mut (impl AsyncRead + Unpin),
+) -> Result<(Header, SocketResponse)> {
+    let mut header = [0; SOCKET_HEADER_LENGTH];
+    reader.read_exact(&mut header).await?;
+    let header = Header::decode(&header)?.ok_or_else(|| anyhow!("Incomplete frame header"))?;
+
+    let mut payload = vec![0; header.length as usize];
+    reader.read_exact(&mut payload).await?;
+
+    Ok((header, SocketResponse::try_from(&payload[..])?))
+}
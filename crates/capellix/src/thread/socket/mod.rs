@@ -1,58 +1,137 @@
+pub mod frame;
 pub mod socket_command;
 pub mod socket_response;
+pub mod telemetry;
 
-use std::sync::Arc;
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use futures::StreamExt;
 use log::{debug, info};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::sync::watch;
-use tokio::{net::TcpStream, sync::mpsc};
+use tokio::task::spawn;
+use tokio::time::interval;
+use tokio_stream::wrappers::IntervalStream;
 use tokio_util::codec::FramedRead;
 
 use crate::{
-    hid::LED_COUNT_TOTAL,
-    thread::capellix::SharedState,
-    thread::socket::socket_command::{socket_command_bytes, SocketCommand},
+    compositor::{Layer, LayerUpdate},
+    thread::controller::ControllerHandle,
+    thread::socket::{
+        frame::{
+            Framing, Header, SocketRequest, TextFormat, SOCKET_HEADER_LENGTH, SOCKET_MAGIC_V1,
+            SOCKET_MAGIC_V2,
+        },
+        socket_command::{
+            socket_command_bytes, socket_command_legacy_bytes, socket_command_str, SocketCommand,
+            SOCKET_COMMAND_SET_COLORS,
+        },
+        socket_response::SocketResponse,
+        telemetry::Subscriptions,
+    },
 };
 
-use super::{capellix::Colors, pump_target::Fan};
+/// Rate at which subscriptions are checked for due updates
+const TELEMETRY_TICK: Duration = Duration::from_millis(50);
+
+/// Longest text command accepted, well beyond a `set-colors` line covering every LED
+const MAX_TEXT_LINE_LENGTH: usize = 64 * 1024;
+
+/// ID given to the next connection, identifying the lighting layers it holds
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Candidate Unix socket paths, in order of preference
+///
+/// The daemon binds the first, while clients connect to the first that exists,
+/// so a user session can reach a system daemon bound under /run.
+pub fn unix_socket_paths() -> Vec<PathBuf> {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(|dir| PathBuf::from(dir).join("capellix.sock"))
+        .into_iter()
+        .chain(std::iter::once(PathBuf::from("/run/capellix.sock")))
+        .collect()
+}
+
+/// LEDs covered by a legacy SetColors frame, which always addresses the first controller
+pub fn legacy_led_count(controllers: &[ControllerHandle]) -> usize {
+    controllers
+        .first()
+        .map(|controller| controller.state.topology.lock().led_count())
+        .unwrap_or_default()
+}
 
-pub struct SocketThread {
-    state: Arc<SharedState>,
-    set_fan_speed_tx: mpsc::Sender<(Fan, u16)>,
-    set_colors_tx: Arc<watch::Sender<Colors>>,
+/// Serves a single client connection over any byte stream, such as TCP or a Unix socket
+pub struct SocketThread<S> {
+    controllers: Vec<ControllerHandle>,
     exit_rx: watch::Receiver<bool>,
-    stream: TcpStream,
+    stream: S,
 }
 
 enum SocketEvent {
-    Read(Result<Box<SocketCommand>>),
+    Read(Result<Box<SocketRequest>>),
+    /// The client closed its end of the connection
+    Closed,
+    TelemetryTick,
     RunningChanged(bool),
 }
 
-#[derive(Debug, Default)]
+/// Protocol a connection speaks, settled by its first bytes
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum CodecMode {
+    /// `CPLX` or `CPX2` frames
+    Binary,
+    /// Newline-terminated text commands
+    Text,
+    /// Text line exceeded the maximum length, so nothing more is decoded
+    Overflowed,
+}
+
+#[derive(Debug)]
 pub struct SocketCommandCodec {
     buf: Vec<u8>,
+    mode: Option<CodecMode>,
+    /// LEDs covered by a legacy SetColors frame, which carries no count of its own
+    legacy_led_count: usize,
 }
 
-impl<'a> tokio_util::codec::Decoder for SocketCommandCodec {
-    type Item = SocketCommand;
-
-    type Error = anyhow::Error;
+impl SocketCommandCodec {
+    pub fn new(legacy_led_count: usize) -> Self {
+        SocketCommandCodec {
+            buf: vec![],
+            mode: None,
+            legacy_led_count,
+        }
+    }
 
-    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        debug!("Read {} bytes", src.len());
+    /// Pick a protocol from the start of the buffer,
+    /// returning None until enough bytes have arrived to tell
+    fn negotiate(&self) -> Option<CodecMode> {
+        let len = self.buf.len().min(4);
+        let magic_prefix = |magic: &[u8; 4]| self.buf[..len] == magic[..len];
 
-        // Move received bytes into data buffer
-        self.buf.extend(src.split_to(src.len()));
+        if !magic_prefix(SOCKET_MAGIC_V1) && !magic_prefix(SOCKET_MAGIC_V2) {
+            Some(CodecMode::Text)
+        } else if len == 4 {
+            Some(CodecMode::Binary)
+        } else {
+            None
+        }
+    }
 
+    /// Decode the next frame, where `eof` marks the end of the stream or datagram,
+    /// completing a legacy frame that no further magic will follow
+    fn decode_binary(&mut self, eof: bool) -> Result<Option<SocketRequest>> {
         let mut next_commands = self
             .buf
             .windows(4)
             .enumerate()
             .filter_map(|(i, window)| {
-                if window == [b'C', b'P', b'L', b'X'] {
+                if window == SOCKET_MAGIC_V1 || window == SOCKET_MAGIC_V2 {
                     Some(i)
                 } else {
                     None
@@ -67,6 +146,35 @@ impl<'a> tokio_util::codec::Decoder for SocketCommandCodec {
         let next_command = next_commands.remove(0);
         debug!("Next command at {next_command:}");
 
+        // Versioned frames are length-prefixed, so their payloads may contain either magic
+        if &self.buf[next_command..next_command + 4] == SOCKET_MAGIC_V2 {
+            let header = match Header::decode(&self.buf[next_command..])? {
+                Some(header) => header,
+                None => return Ok(None),
+            };
+
+            let start = next_command + SOCKET_HEADER_LENGTH;
+            let end = start + header.length as usize;
+            if self.buf.len() < end {
+                return Ok(None);
+            }
+
+            let command = match socket_command_bytes(&self.buf[start..end]) {
+                Ok(([], command)) => Ok(command),
+                Ok((rest, _)) => Err(anyhow!("{} trailing bytes in command", rest.len())),
+                Err(_) => Err(anyhow!("Invalid socket command")),
+            };
+
+            debug!("Splitting off {end:} bytes");
+            self.buf = self.buf.split_off(end);
+
+            return Ok(Some(SocketRequest {
+                framing: Framing::negotiate(&header),
+                command,
+            }));
+        }
+
+        // Legacy frames run until the next magic
         let end = if !next_commands.is_empty() {
             next_commands[0]
         } else {
@@ -74,32 +182,118 @@ impl<'a> tokio_util::codec::Decoder for SocketCommandCodec {
         };
 
         let next_command_bytes = &self.buf[(next_command + 4)..end];
-        if let Ok((input, command)) = socket_command_bytes(next_command_bytes) {
-            let len = self.buf.len() - input.len();
+
+        // A SetColors without a count may still be arriving,
+        // until the next frame starts or it has a color for every LED
+        if next_commands.is_empty()
+            && !eof
+            && next_command_bytes.first() == Some(&SOCKET_COMMAND_SET_COLORS)
+            && next_command_bytes.len() - 1 < self.legacy_led_count * 3
+        {
+            return Ok(None);
+        }
+
+        if let Ok((input, command)) = socket_command_legacy_bytes(next_command_bytes) {
+            let len = end - input.len();
             debug!("Splitting off {len:} bytes");
             self.buf = self.buf.split_off(len);
-            Ok(Some(command))
+            Ok(Some(SocketRequest {
+                framing: Framing::V1,
+                command: Ok(command),
+            }))
         } else {
             Ok(None)
         }
     }
 
+    fn decode_text(&mut self) -> Result<Option<SocketRequest>> {
+        while let Some(end) = self.buf.iter().position(|b| *b == b'\n') {
+            let line = self.buf.drain(..=end).collect::<Vec<_>>();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let command = match socket_command_str(line) {
+                Ok((rest, command)) if rest.trim().is_empty() => Ok(command),
+                _ => Err(anyhow!("Invalid socket command {line:?}")),
+            };
+
+            return Ok(Some(SocketRequest {
+                framing: Framing::Text(TextFormat::Plain),
+                command,
+            }));
+        }
+
+        // Answer an overlong line with an error, then drop the connection
+        if self.buf.len() > MAX_TEXT_LINE_LENGTH {
+            self.buf.clear();
+            self.mode = Some(CodecMode::Overflowed);
+            return Ok(Some(SocketRequest {
+                framing: Framing::Text(TextFormat::Plain),
+                command: Err(anyhow!(
+                    "Socket command longer than {MAX_TEXT_LINE_LENGTH:} bytes"
+                )),
+            }));
+        }
+
+        Ok(None)
+    }
+}
+
+impl tokio_util::codec::Decoder for SocketCommandCodec {
+    type Item = SocketRequest;
+
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        debug!("Read {} bytes", src.len());
+
+        // Move received bytes into data buffer
+        self.buf.extend(src.split_to(src.len()));
+
+        if self.mode.is_none() {
+            self.mode = self.negotiate();
+            debug!("Negotiated {:?} mode", self.mode);
+        }
+
+        match self.mode {
+            Some(CodecMode::Binary) => self.decode_binary(false),
+            Some(CodecMode::Text) => self.decode_text(),
+            Some(CodecMode::Overflowed) => {
+                Err(anyhow!("Closing connection after an overlong line"))
+            }
+            None => Ok(None),
+        }
+    }
+
     fn decode_eof(&mut self, buf: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        match self.decode(buf)? {
-            Some(frame) => Ok(Some(frame)),
-            None => {
-                if buf.is_empty() {
-                    debug!("decode_eof, clearing buffer");
-                    self.buf.clear();
-                    Ok(None)
-                } else {
-                    Err(
-                        std::io::Error::new(std::io::ErrorKind::Other, "bytes remaining on stream")
-                            .into(),
-                    )
-                }
+        if let Some(frame) = self.decode(buf)? {
+            return Ok(Some(frame));
+        }
+
+        if self.mode == Some(CodecMode::Binary) && !self.buf.is_empty() {
+            if let Some(frame) = self.decode_binary(true)? {
+                return Ok(Some(frame));
             }
         }
+
+        // Accept a final text command without a trailing newline
+        if self.mode == Some(CodecMode::Text) && !self.buf.is_empty() {
+            self.buf.push(b'\n');
+            return self.decode_text();
+        }
+
+        if buf.is_empty() {
+            // Datagrams are decoded independently, so each negotiates its own mode
+            debug!("decode_eof, clearing buffer");
+            self.buf.clear();
+            self.mode = None;
+            Ok(None)
+        } else {
+            Err(std::io::Error::new(std::io::ErrorKind::Other, "bytes remaining on stream").into())
+        }
     }
 }
 
@@ -117,50 +311,123 @@ impl tokio_util::codec::Encoder<SocketCommand> for SocketCommandCodec {
     }
 }
 
-impl SocketThread {
+impl<S> SocketThread<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
     pub fn new(
-        state: Arc<SharedState>,
-        set_pump_speed_tx: mpsc::Sender<(Fan, u16)>,
-        set_colors_tx: Arc<watch::Sender<Colors>>,
+        controllers: Vec<ControllerHandle>,
         exit_rx: watch::Receiver<bool>,
-        stream: TcpStream,
+        stream: S,
     ) -> Self {
         SocketThread {
-            state,
-            set_fan_speed_tx: set_pump_speed_tx,
-            set_colors_tx,
+            controllers,
             exit_rx,
             stream,
         }
     }
 
-    pub async fn run(mut self) -> Result<()> {
-        self.stream.set_nodelay(true)?;
-        let (stream, mut sink) = self.stream.split();
+    pub async fn run(self) -> Result<()> {
+        let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        let controllers = self.controllers.clone();
+
+        let result = self.serve(id).await;
+
+        // Hand back the layers this connection drew on, leaving timed layers to expire.
+        // Sent from a task so a full channel can't hold up shutdown
+        for handle in controllers {
+            spawn(async move {
+                handle
+                    .set_lighting_tx
+                    .send(LayerUpdate::Release(id))
+                    .await
+                    .ok();
+            });
+        }
+
+        result
+    }
+
+    async fn serve(self, id: u64) -> Result<()> {
+        let (stream, mut sink) = tokio::io::split(self.stream);
+        let mut subscriptions = Subscriptions::default();
+        let mut text_format = TextFormat::Plain;
+        let mut layer = Layer::default();
 
-        let stream = FramedRead::new(stream, SocketCommandCodec::default());
-        let exit = tokio_stream::wrappers::WatchStream::new(self.exit_rx);
+        let stream = FramedRead::new(
+            stream,
+            SocketCommandCodec::new(legacy_led_count(&self.controllers)),
+        );
+        let exit = tokio_stream::wrappers::WatchStream::new(self.exit_rx.clone());
+        let telemetry_tick =
+            IntervalStream::new(interval(TELEMETRY_TICK)).map(|_| SocketEvent::TelemetryTick);
 
         let mut events = futures::stream_select!(
-            stream.map(|command| SocketEvent::Read(command.map(Box::new))),
+            stream
+                .map(|request| SocketEvent::Read(request.map(Box::new)))
+                .chain(futures::stream::iter([SocketEvent::Closed])),
+            telemetry_tick,
             exit.map(SocketEvent::RunningChanged)
         );
 
         while let Some(event) = events.next().await {
             match event {
-                SocketEvent::Read(command) => {
-                    let command = command?;
-
-                    debug!("Received socket command: {command:}");
-
-                    command
-                        .run(
-                            &self.state,
-                            &self.set_fan_speed_tx,
-                            &self.set_colors_tx,
-                            &mut sink,
-                        )
-                        .await?;
+                SocketEvent::Read(request) => {
+                    let SocketRequest { framing, command } = *request?;
+
+                    // Text responses use the format last chosen on this connection
+                    let framing = match (framing, &command) {
+                        (Framing::Text(_), Ok(SocketCommand::SetFormat(format))) => {
+                            text_format = *format;
+                            Framing::Text(text_format)
+                        }
+                        (Framing::Text(_), _) => Framing::Text(text_format),
+                        (framing, _) => framing,
+                    };
+
+                    let response = match command {
+                        Ok(SocketCommand::SetFormat(_)) if matches!(framing, Framing::Text(_)) => {
+                            Ok(SocketResponse::SetFormat(true))
+                        }
+                        Ok(command) if command.is_subscription() => {
+                            debug!("Received subscription command: {command:}");
+                            subscriptions.update(&self.controllers, framing, command)
+                        }
+                        Ok(command) => match command.layer() {
+                            // Layers apply to every controller, so a controller prefix is ignored
+                            Some(selected) => {
+                                debug!("Selected layer {selected:}");
+                                layer = selected.clone();
+                                Ok(SocketResponse::SetLayer(true))
+                            }
+                            None => {
+                                debug!("Received socket command: {command:}");
+                                command.run(&self.controllers, &layer, Some(id)).await
+                            }
+                        },
+                        Err(e) => Err(e),
+                    };
+
+                    let response = match (framing, response) {
+                        (_, Ok(response)) => response,
+                        // Legacy clients have no error response, so drop the connection instead
+                        (Framing::V1, Err(e)) => return Err(e),
+                        (Framing::V2 { .. } | Framing::Text(_), Err(e)) => {
+                            SocketResponse::Error(e.to_string())
+                        }
+                    };
+
+                    sink.write_all(&framing.encode(response)).await?;
+                }
+                SocketEvent::TelemetryTick => {
+                    let updates = subscriptions.poll(&self.controllers);
+                    if !updates.is_empty() {
+                        sink.write_all(&updates).await?;
+                    }
+                }
+                SocketEvent::Closed => {
+                    debug!("SocketThread client closed connection");
+                    break;
                 }
                 SocketEvent::RunningChanged(running) => {
                     if !running {
@@ -174,3 +441,192 @@ impl SocketThread {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio_util::codec::Decoder;
+
+    use super::*;
+    use crate::thread::socket::{
+        frame::{encode_request, SOCKET_PROTOCOL_VERSION},
+        socket_command::SOCKET_COMMAND_GET_FIRMWARE,
+    };
+
+    const LED_COUNT: usize = 4;
+
+    /// Feed bytes to the codec, collecting every request decoded from them
+    fn decode_all(codec: &mut SocketCommandCodec, bytes: &[u8]) -> Vec<SocketRequest> {
+        let mut src = BytesMut::from(bytes);
+        let mut requests = vec![];
+        while let Some(request) = codec.decode(&mut src).unwrap() {
+            requests.push(request);
+        }
+        requests
+    }
+
+    #[test]
+    fn decodes_text_lines() {
+        let mut codec = SocketCommandCodec::new(LED_COUNT);
+        let requests = decode_all(&mut codec, b"get-firmware\n\nget-nothing\n");
+
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].framing, Framing::Text(TextFormat::Plain));
+        assert!(matches!(
+            requests[0].command,
+            Ok(SocketCommand::GetFirmware)
+        ));
+        assert!(requests[1].command.is_err());
+    }
+
+    #[test]
+    fn rejects_overlong_text_lines() {
+        let mut codec = SocketCommandCodec::new(LED_COUNT);
+        let mut src = BytesMut::from(&vec![b'a'; MAX_TEXT_LINE_LENGTH + 1][..]);
+
+        let request = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(request.framing, Framing::Text(TextFormat::Plain));
+        assert!(request.command.is_err());
+
+        // The connection is dropped rather than decoding anything further
+        let mut src = BytesMut::from(&b"get-firmware\n"[..]);
+        assert!(codec.decode(&mut src).is_err());
+    }
+
+    #[test]
+    fn decodes_v2_frame_containing_magic() {
+        let colors = vec![*b"CPL", *b"XCP", *b"X2\0"];
+        let frame = encode_request(7, SocketCommand::SetColors(colors.clone()));
+
+        // Nothing is decoded until the whole payload has arrived
+        let mut codec = SocketCommandCodec::new(LED_COUNT);
+        assert!(decode_all(&mut codec, &frame[..SOCKET_HEADER_LENGTH + 2]).is_empty());
+        let requests = decode_all(&mut codec, &frame[SOCKET_HEADER_LENGTH + 2..]);
+
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            requests[0].framing,
+            Framing::V2 {
+                version: SOCKET_PROTOCOL_VERSION,
+                id: 7
+            }
+        );
+        assert!(matches!(
+            &requests[0].command,
+            Ok(SocketCommand::SetColors(decoded)) if *decoded == colors
+        ));
+    }
+
+    #[test]
+    fn negotiates_newer_versions_down() {
+        let mut frame = encode_request(1, SocketCommand::GetFirmware);
+        frame[4] = SOCKET_PROTOCOL_VERSION + 3;
+
+        let requests = decode_all(&mut SocketCommandCodec::new(LED_COUNT), &frame);
+        assert_eq!(
+            requests[0].framing,
+            Framing::V2 {
+                version: SOCKET_PROTOCOL_VERSION,
+                id: 1
+            }
+        );
+    }
+
+    #[test]
+    fn decodes_legacy_set_colors_without_count() {
+        let frame = [
+            &SOCKET_MAGIC_V1[..],
+            &[SOCKET_COMMAND_SET_COLORS],
+            &[255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255],
+        ]
+        .concat();
+
+        let requests = decode_all(&mut SocketCommandCodec::new(LED_COUNT), &frame);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].framing, Framing::V1);
+        assert!(matches!(
+            &requests[0].command,
+            Ok(SocketCommand::SetColors(colors)) if colors.len() == LED_COUNT
+        ));
+    }
+
+    #[test]
+    fn waits_for_split_legacy_set_colors() {
+        let frame = [
+            &SOCKET_MAGIC_V1[..],
+            &[SOCKET_COMMAND_SET_COLORS],
+            &[255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255],
+        ]
+        .concat();
+
+        // A read ending on a color boundary is not mistaken for a shorter frame
+        let mut codec = SocketCommandCodec::new(LED_COUNT);
+        assert!(decode_all(&mut codec, &frame[..SOCKET_MAGIC_V1.len() + 7]).is_empty());
+        let requests = decode_all(&mut codec, &frame[SOCKET_MAGIC_V1.len() + 7..]);
+
+        assert_eq!(requests.len(), 1);
+        assert!(matches!(
+            &requests[0].command,
+            Ok(SocketCommand::SetColors(colors))
+                if *colors == vec![[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 255]]
+        ));
+    }
+
+    #[test]
+    fn decodes_short_legacy_set_colors_at_eof() {
+        let frame = [
+            &SOCKET_MAGIC_V1[..],
+            &[SOCKET_COMMAND_SET_COLORS],
+            &[255, 0, 0, 0, 255, 0],
+        ]
+        .concat();
+
+        let mut codec = SocketCommandCodec::new(LED_COUNT);
+        let mut src = BytesMut::from(&frame[..]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        let request = codec.decode_eof(&mut src).unwrap().unwrap();
+        assert!(matches!(
+            &request.command,
+            Ok(SocketCommand::SetColors(colors)) if *colors == vec![[255, 0, 0], [0, 255, 0]]
+        ));
+    }
+
+    #[test]
+    fn decodes_consecutive_legacy_frames() {
+        let frames = [
+            &SOCKET_MAGIC_V1[..],
+            &[SOCKET_COMMAND_SET_COLORS, 0, 0, 255],
+            &SOCKET_MAGIC_V1[..],
+            &[SOCKET_COMMAND_GET_FIRMWARE],
+        ]
+        .concat();
+
+        let requests = decode_all(&mut SocketCommandCodec::new(LED_COUNT), &frames);
+        assert_eq!(requests.len(), 2);
+        assert!(matches!(
+            &requests[0].command,
+            Ok(SocketCommand::SetColors(colors)) if *colors == vec![[0, 0, 255]]
+        ));
+        assert!(matches!(
+            requests[1].command,
+            Ok(SocketCommand::GetFirmware)
+        ));
+    }
+
+    #[test]
+    fn encodes_v2_responses_with_request_id() {
+        let framing = Framing::V2 {
+            version: SOCKET_PROTOCOL_VERSION,
+            id: 9,
+        };
+        let frame = framing.encode(SocketResponse::Error("Failed".to_string()));
+
+        let header = Header::decode(&frame).unwrap().unwrap();
+        assert_eq!(header.id, 9);
+        assert_eq!(header.length as usize, frame.len() - SOCKET_HEADER_LENGTH);
+        assert!(matches!(
+            SocketResponse::try_from(&frame[SOCKET_HEADER_LENGTH..]),
+            Ok(SocketResponse::Error(message)) if message == "Failed"
+        ));
+    }
+}
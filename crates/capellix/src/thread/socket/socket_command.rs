@@ -2,32 +2,99 @@ use std::{fmt::Display, str::FromStr, sync::atomic::Ordering};
 
 use anyhow::{anyhow, Error, Result};
 use log::debug;
-use tokio::{
-    io::{AsyncWrite, AsyncWriteExt},
-    sync::{mpsc, watch},
-};
 
 use crate::{
+    compositor::{layer_bytes, layer_str, Layer, LayerUpdate, Lighting},
+    effect::{color_param_str, effect_bytes, effect_str, Effect},
     hid::validate_fan_speed,
+    output::{output_setting_bytes, output_setting_str, OutputSetting},
+    thermal::ThermalState,
     thread::{
-        capellix::{Colors, SharedState},
-        pump_target::Fan,
+        capellix::Colors,
+        controller::ControllerHandle,
+        pump_target::{Fan, FanTarget},
+        socket::{
+            frame::TextFormat,
+            socket_response::{ChannelStatus, FrameStats, SocketResponse},
+        },
     },
+    zone::{zone_bytes, zone_str, Zone},
 };
 
-use super::LED_COUNT_TOTAL;
-
 pub const SOCKET_COMMAND_GET_COOLANT_TEMP: u8 = 0;
 pub const SOCKET_COMMAND_GET_PUMP_SPEED: u8 = 1;
 pub const SOCKET_COMMAND_SET_PUMP_SPEED: u8 = 2;
 pub const SOCKET_COMMAND_SET_COLORS: u8 = 3;
+pub const SOCKET_COMMAND_CONTROLLER: u8 = 4;
+pub const SOCKET_COMMAND_GET_STATUS: u8 = 5;
+pub const SOCKET_COMMAND_CLEAR_FAN_TARGET: u8 = 6;
+pub const SOCKET_COMMAND_SET_RPM_TARGET: u8 = 7;
+pub const SOCKET_COMMAND_GET_ALARM: u8 = 8;
+pub const SOCKET_COMMAND_SUBSCRIBE: u8 = 9;
+pub const SOCKET_COMMAND_UNSUBSCRIBE: u8 = 10;
+pub const SOCKET_RESPONSE_TELEMETRY: u8 = 11;
+pub const SOCKET_COMMAND_GET_CHANNEL: u8 = 12;
+pub const SOCKET_COMMAND_GET_CHANNELS: u8 = 13;
+pub const SOCKET_COMMAND_GET_FIRMWARE: u8 = 14;
+pub const SOCKET_COMMAND_SET_FORMAT: u8 = 15;
+pub const SOCKET_COMMAND_GET_COLORS: u8 = 16;
+pub const SOCKET_COMMAND_SET_EFFECT: u8 = 17;
+pub const SOCKET_COMMAND_SET_ZONE: u8 = 18;
+pub const SOCKET_COMMAND_SET_LAYER: u8 = 19;
+pub const SOCKET_COMMAND_CLEAR_LAYER: u8 = 20;
+pub const SOCKET_COMMAND_SET_OUTPUT: u8 = 21;
+pub const SOCKET_COMMAND_GET_OUTPUT: u8 = 22;
+pub const SOCKET_COMMAND_GET_FRAMES: u8 = 23;
+
+/// Sent in place of a reading while the addressed controller is disconnected
+pub const SOCKET_RESPONSE_UNAVAILABLE: u8 = 0xfe;
+
+/// Sent to versioned clients when a command fails, followed by a UTF-8 message
+pub const SOCKET_RESPONSE_ERROR: u8 = 0xff;
 
 #[derive(Debug, Clone)]
 pub enum SocketCommand {
     GetCoolantTemp,
     GetPumpSpeed,
+    GetStatus,
+    /// Query which channels are stalled and the coolant temperature condition
+    GetAlarm,
+    /// Query the device type, speed and target of a single channel
+    GetChannel(Fan),
+    /// Query the device type, speed and target of every channel
+    GetChannels,
+    GetFirmware,
+    /// Query the LED colors most recently sent to the device
+    GetColors,
+    /// Query the correction applied to LED colors before they're sent
+    GetOutput,
+    /// Query counters and HID timings for the frames of LED colors sent to the device
+    GetFrames,
     SetFanTarget(Fan, u16),
+    /// Drop a fan's target override, handing it back to its built-in curve
+    ClearFanTarget(Fan),
+    /// Drive a fan's duty towards the given RPM
+    SetRpmTarget(Fan, u16),
     SetColors(Colors),
+    /// Repeat colors across a zone, leaving the rest of the frame as it is
+    SetZone(Zone, Colors),
+    /// Replace the LED colors with an effect rendered by the daemon
+    SetEffect(Effect),
+    /// Draw the connection's following lighting commands on the given layer,
+    /// creating it if needed
+    SetLayer(Layer),
+    /// Remove the connection's current layer, revealing the layers below
+    ClearLayer,
+    /// Change a setting of the device's output correction, or of a zone's if given
+    SetOutput(Option<Zone>, OutputSetting),
+    /// Push telemetry every given number of milliseconds, or on change if zero
+    Subscribe(u16),
+    Unsubscribe,
+    /// Switch the response format of a text connection
+    SetFormat(TextFormat),
+    /// Run the wrapped command against the controller at the given index,
+    /// instead of the first controller
+    Controller(u8, Box<SocketCommand>),
 }
 
 impl Display for SocketCommand {
@@ -35,10 +102,42 @@ impl Display for SocketCommand {
         match self {
             SocketCommand::GetCoolantTemp => f.write_fmt(format_args!("GetCoolantTemp")),
             SocketCommand::GetPumpSpeed => f.write_fmt(format_args!("GetPumpSpeed")),
+            SocketCommand::GetStatus => f.write_fmt(format_args!("GetStatus")),
+            SocketCommand::GetAlarm => f.write_fmt(format_args!("GetAlarm")),
+            SocketCommand::GetChannel(fan) => f.write_fmt(format_args!("GetChannel({fan:?})")),
+            SocketCommand::GetChannels => f.write_fmt(format_args!("GetChannels")),
+            SocketCommand::GetFirmware => f.write_fmt(format_args!("GetFirmware")),
+            SocketCommand::GetColors => f.write_fmt(format_args!("GetColors")),
+            SocketCommand::GetOutput => f.write_fmt(format_args!("GetOutput")),
+            SocketCommand::GetFrames => f.write_fmt(format_args!("GetFrames")),
             SocketCommand::SetFanTarget(fan, speed) => {
                 f.write_fmt(format_args!("SetPumpTarget({fan:?}, {speed:})"))
             }
+            SocketCommand::ClearFanTarget(fan) => {
+                f.write_fmt(format_args!("ClearFanTarget({fan:?})"))
+            }
+            SocketCommand::SetRpmTarget(fan, rpm) => {
+                f.write_fmt(format_args!("SetRpmTarget({fan:?}, {rpm:})"))
+            }
             SocketCommand::SetColors(_) => f.write_fmt(format_args!("SetColors(...)")),
+            SocketCommand::SetZone(zone, _) => f.write_fmt(format_args!("SetZone({zone:}, ...)")),
+            SocketCommand::SetEffect(effect) => f.write_fmt(format_args!("SetEffect({effect:})")),
+            SocketCommand::SetLayer(layer) => f.write_fmt(format_args!("SetLayer({layer:})")),
+            SocketCommand::ClearLayer => f.write_fmt(format_args!("ClearLayer")),
+            SocketCommand::SetOutput(Some(zone), setting) => {
+                f.write_fmt(format_args!("SetOutput({zone:}, {setting:})"))
+            }
+            SocketCommand::SetOutput(None, setting) => {
+                f.write_fmt(format_args!("SetOutput({setting:})"))
+            }
+            SocketCommand::Subscribe(interval) => {
+                f.write_fmt(format_args!("Subscribe({interval:})"))
+            }
+            SocketCommand::Unsubscribe => f.write_fmt(format_args!("Unsubscribe")),
+            SocketCommand::SetFormat(format) => f.write_fmt(format_args!("SetFormat({format:?})")),
+            SocketCommand::Controller(index, command) => {
+                f.write_fmt(format_args!("Controller({index:}, {command:})"))
+            }
         }
     }
 }
@@ -57,68 +156,289 @@ impl From<SocketCommand> for Vec<u8> {
         match value {
             SocketCommand::GetCoolantTemp => vec![SOCKET_COMMAND_GET_COOLANT_TEMP],
             SocketCommand::GetPumpSpeed => vec![SOCKET_COMMAND_GET_PUMP_SPEED],
+            SocketCommand::GetStatus => vec![SOCKET_COMMAND_GET_STATUS],
+            SocketCommand::GetAlarm => vec![SOCKET_COMMAND_GET_ALARM],
+            SocketCommand::GetChannel(fan) => vec![SOCKET_COMMAND_GET_CHANNEL, u8::from(fan)],
+            SocketCommand::GetChannels => vec![SOCKET_COMMAND_GET_CHANNELS],
+            SocketCommand::GetFirmware => vec![SOCKET_COMMAND_GET_FIRMWARE],
+            SocketCommand::GetColors => vec![SOCKET_COMMAND_GET_COLORS],
+            SocketCommand::GetOutput => vec![SOCKET_COMMAND_GET_OUTPUT],
+            SocketCommand::GetFrames => vec![SOCKET_COMMAND_GET_FRAMES],
             SocketCommand::SetFanTarget(fan, speed) => [
                 &[SOCKET_COMMAND_SET_PUMP_SPEED][..],
                 &[u8::from(fan)],
                 &speed.to_le_bytes()[..],
             ]
             .concat(),
+            SocketCommand::ClearFanTarget(fan) => {
+                vec![SOCKET_COMMAND_CLEAR_FAN_TARGET, u8::from(fan)]
+            }
+            SocketCommand::SetRpmTarget(fan, rpm) => [
+                &[SOCKET_COMMAND_SET_RPM_TARGET][..],
+                &[u8::from(fan)],
+                &rpm.to_le_bytes()[..],
+            ]
+            .concat(),
             SocketCommand::SetColors(colors) => [
                 &[SOCKET_COMMAND_SET_COLORS][..],
+                &(colors.len() as u16).to_le_bytes()[..],
                 &colors.into_iter().flatten().collect::<Vec<_>>()[..],
             ]
             .concat(),
+            SocketCommand::SetZone(zone, colors) => [
+                &[SOCKET_COMMAND_SET_ZONE][..],
+                &zone.encode()[..],
+                &(colors.len() as u16).to_le_bytes()[..],
+                &colors.into_iter().flatten().collect::<Vec<_>>()[..],
+            ]
+            .concat(),
+            SocketCommand::SetEffect(effect) => {
+                [&[SOCKET_COMMAND_SET_EFFECT][..], &effect.encode()[..]].concat()
+            }
+            SocketCommand::SetLayer(layer) => {
+                [&[SOCKET_COMMAND_SET_LAYER][..], &layer.encode()[..]].concat()
+            }
+            SocketCommand::ClearLayer => vec![SOCKET_COMMAND_CLEAR_LAYER],
+            SocketCommand::SetOutput(zone, setting) => [
+                &[SOCKET_COMMAND_SET_OUTPUT][..],
+                &match zone {
+                    Some(zone) => [&[1][..], &zone.encode()[..]].concat(),
+                    None => vec![0],
+                }[..],
+                &setting.encode()[..],
+            ]
+            .concat(),
+            SocketCommand::Subscribe(interval) => {
+                [&[SOCKET_COMMAND_SUBSCRIBE][..], &interval.to_le_bytes()[..]].concat()
+            }
+            SocketCommand::Unsubscribe => vec![SOCKET_COMMAND_UNSUBSCRIBE],
+            SocketCommand::SetFormat(format) => vec![
+                SOCKET_COMMAND_SET_FORMAT,
+                match format {
+                    TextFormat::Plain => 0,
+                    TextFormat::Json => 1,
+                },
+            ],
+            SocketCommand::Controller(index, command) => [
+                &[SOCKET_COMMAND_CONTROLLER, index][..],
+                &Vec::from(*command)[..],
+            ]
+            .concat(),
         }
     }
 }
 
 impl SocketCommand {
+    /// Whether this command manages the connection's telemetry subscriptions
+    pub fn is_subscription(&self) -> bool {
+        match self {
+            SocketCommand::Subscribe(_) | SocketCommand::Unsubscribe => true,
+            SocketCommand::Controller(_, command) => command.is_subscription(),
+            _ => false,
+        }
+    }
+
+    /// Layer selected by this command, if it's a SetLayer
+    pub fn layer(&self) -> Option<&Layer> {
+        match self {
+            SocketCommand::SetLayer(layer) => Some(layer),
+            SocketCommand::Controller(_, command) => command.layer(),
+            _ => None,
+        }
+    }
+
+    /// Run the command against the addressed controller, returning the response to send
+    ///
+    /// Lighting commands are drawn on `layer`, held by the connection with ID `owner` if any.
     pub async fn run(
         self,
-        state: &SharedState,
-        set_pump_speed_tx: &mpsc::Sender<(Fan, u16)>,
-        set_colors_tx: &watch::Sender<Colors>,
-        mut sink: impl Unpin + AsyncWrite,
-    ) -> Result<()> {
-        match self {
-            SocketCommand::GetCoolantTemp => {
-                let temp = state.coolant_temp.load(Ordering::Relaxed);
-                let temp = temp.to_le_bytes();
+        controllers: &[ControllerHandle],
+        layer: &Layer,
+        owner: Option<u64>,
+    ) -> Result<SocketResponse> {
+        let (index, command) = match self {
+            SocketCommand::Controller(index, command) => (index as usize, *command),
+            command => (0, command),
+        };
+
+        let ControllerHandle {
+            state,
+            set_fan_speed_tx,
+            set_lighting_tx,
+            set_output_tx,
+        } = controllers
+            .get(index)
+            .ok_or_else(|| anyhow!("No controller at index {index:}"))?;
 
-                sink.write(&[0, temp[0], temp[1]]).await?;
+        let available = state.available.load(Ordering::Relaxed);
+
+        let draw = |lighting| LayerUpdate::Draw {
+            layer: layer.clone(),
+            owner,
+            lighting,
+        };
+
+        let response = match command {
+            SocketCommand::GetCoolantTemp
+            | SocketCommand::GetPumpSpeed
+            | SocketCommand::GetAlarm
+            | SocketCommand::GetChannel(_)
+            | SocketCommand::GetChannels
+                if !available =>
+            {
+                SocketResponse::Unavailable
+            }
+            SocketCommand::GetStatus => SocketResponse::GetStatus(available),
+            SocketCommand::GetAlarm => SocketResponse::GetAlarm {
+                stalled: state.stalled.load(Ordering::Relaxed),
+                thermal: ThermalState::try_from(state.thermal.load(Ordering::Relaxed))?,
+            },
+            SocketCommand::GetCoolantTemp => {
+                SocketResponse::GetCoolantTemp(state.coolant_temp.load(Ordering::Relaxed))
             }
             SocketCommand::GetPumpSpeed => {
-                let speed = state.pump_speed.load(Ordering::Relaxed);
-                let speed = speed.to_le_bytes();
-
-                sink.write(&[1, speed[0], speed[1]]).await?;
+                SocketResponse::GetPumpSpeed(state.pump_speed.load(Ordering::Relaxed))
+            }
+            SocketCommand::GetChannel(fan) => {
+                let channel = u8::from(fan) as usize;
+                SocketResponse::GetChannel(
+                    ChannelStatus::capture(state, channel)
+                        .ok_or_else(|| anyhow!("{fan:?} is not present on controller {index:}"))?,
+                )
             }
+            SocketCommand::GetChannels => SocketResponse::GetChannels(
+                (0..state.speeds.len())
+                    .filter_map(|channel| ChannelStatus::capture(state, channel))
+                    .collect(),
+            ),
+            SocketCommand::GetFirmware => match *state.firmware.lock() {
+                Some(firmware) => SocketResponse::GetFirmware(firmware),
+                None => SocketResponse::Unavailable,
+            },
+            SocketCommand::GetColors => SocketResponse::GetColors(state.colors.lock().clone()),
+            SocketCommand::GetOutput => SocketResponse::GetOutput(state.output.lock().clone()),
+            SocketCommand::GetFrames => SocketResponse::GetFrames(FrameStats::capture(state)),
             SocketCommand::SetFanTarget(fan, speed) => {
                 debug!("SocketThread setting pump target");
                 let speed = validate_fan_speed(speed);
-                set_pump_speed_tx.send((fan, speed)).await?;
-                sink.write(&[1]).await?;
+                set_fan_speed_tx.send((fan, FanTarget::Duty(speed))).await?;
+                SocketResponse::SetPumpSpeed(true)
+            }
+            SocketCommand::ClearFanTarget(fan) => {
+                debug!("SocketThread clearing fan target");
+                set_fan_speed_tx.send((fan, FanTarget::Curve)).await?;
+                SocketResponse::ClearFanTarget(true)
+            }
+            SocketCommand::SetRpmTarget(fan, rpm) => {
+                debug!("SocketThread setting RPM target");
+                set_fan_speed_tx.send((fan, FanTarget::Rpm(rpm))).await?;
+                SocketResponse::SetRpmTarget(true)
             }
             SocketCommand::SetColors(in_colors) => {
                 debug!("SocketThread setting colors");
-                set_colors_tx.send(in_colors)?;
-                sink.write(&[1]).await?;
+                set_lighting_tx
+                    .send(draw(Lighting::Colors(in_colors)))
+                    .await?;
+                SocketResponse::SetColors(true)
             }
-        }
+            SocketCommand::SetZone(zone, colors) => {
+                debug!("SocketThread setting zone colors");
+                set_lighting_tx
+                    .send(draw(Lighting::Zone(zone, colors)))
+                    .await?;
+                SocketResponse::SetZone(true)
+            }
+            SocketCommand::SetEffect(effect) => {
+                debug!("SocketThread setting effect");
+                set_lighting_tx.send(draw(Lighting::Effect(effect))).await?;
+                SocketResponse::SetEffect(true)
+            }
+            SocketCommand::ClearLayer => {
+                debug!("SocketThread clearing layer");
+                set_lighting_tx
+                    .send(LayerUpdate::Clear(layer.name.clone()))
+                    .await?;
+                SocketResponse::ClearLayer(true)
+            }
+            SocketCommand::SetOutput(zone, setting) => {
+                debug!("SocketThread setting output correction");
+                state.output.lock().check(zone.as_ref(), &setting)?;
+                set_output_tx.send((zone, setting)).await?;
+                SocketResponse::SetOutput(true)
+            }
+            SocketCommand::Subscribe(_) | SocketCommand::Unsubscribe => {
+                return Err(anyhow!("Subscriptions require a stream connection"))
+            }
+            SocketCommand::SetFormat(_) => {
+                return Err(anyhow!(
+                    "Response format can only be set on text connections"
+                ))
+            }
+            SocketCommand::SetLayer(_) => {
+                return Err(anyhow!("Layers can only be selected on stream connections"))
+            }
+            SocketCommand::Controller(..) => {
+                return Err(anyhow!("Nested controller commands are not supported"))
+            }
+        };
 
-        Ok(())
+        Ok(response)
     }
 }
 
 pub fn socket_command_str(input: &str) -> nom::IResult<&str, SocketCommand> {
+    nom::branch::alt((socket_command_controller_str, socket_command_unprefixed_str))(input)
+}
+
+/// Parse any command but a controller prefix, so prefixes can't be nested
+pub fn socket_command_unprefixed_str(input: &str) -> nom::IResult<&str, SocketCommand> {
     nom::branch::alt((
-        socket_command_set_colors_str,
+        socket_command_lighting_str,
         socket_command_set_pump_speed_str,
+        socket_command_clear_fan_target_str,
+        socket_command_set_rpm_target_str,
         socket_command_get_coolant_temp_str,
         socket_command_get_pump_speed_str,
+        socket_command_get_status_str,
+        socket_command_get_alarm_str,
+        socket_command_get_channels_str,
+        socket_command_get_channel_str,
+        socket_command_get_firmware_str,
+        socket_command_get_colors_str,
+        socket_command_get_output_str,
+        socket_command_get_frames_str,
+        socket_command_subscribe_str,
+        socket_command_unsubscribe_str,
+        socket_command_set_format_str,
     ))(input)
 }
 
+/// Parse a command that changes the LED colors or the layer they're drawn on
+pub fn socket_command_lighting_str(input: &str) -> nom::IResult<&str, SocketCommand> {
+    nom::branch::alt((
+        socket_command_set_colors_str,
+        socket_command_set_effect_str,
+        socket_command_set_zone_str,
+        socket_command_set_layer_str,
+        socket_command_clear_layer_str,
+        socket_command_set_output_str,
+    ))(input)
+}
+
+pub fn socket_command_controller_str(input: &str) -> nom::IResult<&str, SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag("controller")(input)?;
+    let (input, index) = nom::combinator::map_res(
+        nom::sequence::preceded(
+            nom::character::complete::space1,
+            nom::character::complete::digit1,
+        ),
+        str::parse,
+    )(input)?;
+    let (input, _) = nom::character::complete::space1(input)?;
+    let (input, command) = socket_command_unprefixed_str(input)?;
+    Ok((input, SocketCommand::Controller(index, Box::new(command))))
+}
+
 pub fn socket_command_get_coolant_temp_str(input: &str) -> nom::IResult<&str, SocketCommand> {
     let (input, _) = nom::bytes::complete::tag("get-coolant-temp")(input)?;
     Ok((input, SocketCommand::GetCoolantTemp))
@@ -129,6 +449,84 @@ pub fn socket_command_get_pump_speed_str(input: &str) -> nom::IResult<&str, Sock
     Ok((input, SocketCommand::GetPumpSpeed))
 }
 
+pub fn socket_command_get_status_str(input: &str) -> nom::IResult<&str, SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag("get-status")(input)?;
+    Ok((input, SocketCommand::GetStatus))
+}
+
+pub fn socket_command_get_alarm_str(input: &str) -> nom::IResult<&str, SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag("get-alarm")(input)?;
+    Ok((input, SocketCommand::GetAlarm))
+}
+
+pub fn socket_command_get_channel_str(input: &str) -> nom::IResult<&str, SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag("get-channel")(input)?;
+    let (input, fan) = nom::combinator::map_res(
+        nom::sequence::preceded(
+            nom::character::complete::space1,
+            nom::character::complete::alphanumeric1,
+        ),
+        str::parse,
+    )(input)?;
+
+    Ok((input, SocketCommand::GetChannel(fan)))
+}
+
+pub fn socket_command_get_channels_str(input: &str) -> nom::IResult<&str, SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag("get-channels")(input)?;
+    Ok((input, SocketCommand::GetChannels))
+}
+
+pub fn socket_command_get_firmware_str(input: &str) -> nom::IResult<&str, SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag("get-firmware")(input)?;
+    Ok((input, SocketCommand::GetFirmware))
+}
+
+pub fn socket_command_get_colors_str(input: &str) -> nom::IResult<&str, SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag("get-colors")(input)?;
+    Ok((input, SocketCommand::GetColors))
+}
+
+pub fn socket_command_get_output_str(input: &str) -> nom::IResult<&str, SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag("get-output")(input)?;
+    Ok((input, SocketCommand::GetOutput))
+}
+
+pub fn socket_command_get_frames_str(input: &str) -> nom::IResult<&str, SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag("get-frames")(input)?;
+    Ok((input, SocketCommand::GetFrames))
+}
+
+pub fn socket_command_subscribe_str(input: &str) -> nom::IResult<&str, SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag("subscribe")(input)?;
+    let (input, interval) = nom::combinator::opt(nom::combinator::map_res(
+        nom::sequence::preceded(
+            nom::character::complete::space1,
+            nom::character::complete::digit1,
+        ),
+        str::parse,
+    ))(input)?;
+    Ok((
+        input,
+        SocketCommand::Subscribe(interval.unwrap_or_default()),
+    ))
+}
+
+pub fn socket_command_unsubscribe_str(input: &str) -> nom::IResult<&str, SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag("unsubscribe")(input)?;
+    Ok((input, SocketCommand::Unsubscribe))
+}
+
+pub fn socket_command_set_format_str(input: &str) -> nom::IResult<&str, SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag("format")(input)?;
+    let (input, _) = nom::character::complete::space1(input)?;
+    let (input, format) = nom::branch::alt((
+        nom::combinator::value(TextFormat::Plain, nom::bytes::complete::tag("text")),
+        nom::combinator::value(TextFormat::Json, nom::bytes::complete::tag("json")),
+    ))(input)?;
+    Ok((input, SocketCommand::SetFormat(format)))
+}
+
 pub fn socket_command_set_pump_speed_str(input: &str) -> nom::IResult<&str, SocketCommand> {
     let (input, _) = nom::bytes::complete::tag("set-fan-target")(input)?;
     let (input, fan) = nom::combinator::map_res(
@@ -152,6 +550,40 @@ pub fn socket_command_set_pump_speed_str(input: &str) -> nom::IResult<&str, Sock
     Ok((input, SocketCommand::SetFanTarget(fan, speed)))
 }
 
+pub fn socket_command_clear_fan_target_str(input: &str) -> nom::IResult<&str, SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag("clear-fan-target")(input)?;
+    let (input, fan) = nom::combinator::map_res(
+        nom::sequence::preceded(
+            nom::character::complete::space1,
+            nom::character::complete::alphanumeric1,
+        ),
+        str::parse,
+    )(input)?;
+
+    Ok((input, SocketCommand::ClearFanTarget(fan)))
+}
+
+pub fn socket_command_set_rpm_target_str(input: &str) -> nom::IResult<&str, SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag("set-rpm-target")(input)?;
+    let (input, fan) = nom::combinator::map_res(
+        nom::sequence::preceded(
+            nom::character::complete::space1,
+            nom::character::complete::alphanumeric1,
+        ),
+        str::parse,
+    )(input)?;
+
+    let (input, rpm) = nom::combinator::map_res(
+        nom::sequence::preceded(
+            nom::character::complete::space1,
+            nom::character::complete::digit1,
+        ),
+        str::parse,
+    )(input)?;
+
+    Ok((input, SocketCommand::SetRpmTarget(fan, rpm)))
+}
+
 pub fn socket_command_set_colors_str(input: &str) -> nom::IResult<&str, SocketCommand> {
     let (input, _) = nom::bytes::complete::tag("set-colors")(input)?;
     let (input, colors) = nom::multi::many0(nom::multi::count(
@@ -167,26 +599,100 @@ pub fn socket_command_set_colors_str(input: &str) -> nom::IResult<&str, SocketCo
         3,
     ))(input)?;
 
-    let buf: Vec<_> = colors
+    let colors = colors
         .into_iter()
         .map(|chunk| [chunk[0], chunk[1], chunk[2]])
         .collect();
 
-    let mut colors = [[0; 3]; LED_COUNT_TOTAL];
-    colors.copy_from_slice(&buf);
+    Ok((input, SocketCommand::SetColors(colors)))
+}
+
+pub fn socket_command_set_effect_str(input: &str) -> nom::IResult<&str, SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag("set-effect")(input)?;
+    let (input, _) = nom::character::complete::space1(input)?;
+    let (input, effect) = effect_str(input)?;
+    Ok((input, SocketCommand::SetEffect(effect)))
+}
+
+pub fn socket_command_set_zone_str(input: &str) -> nom::IResult<&str, SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag("set-zone")(input)?;
+    let (input, _) = nom::character::complete::space1(input)?;
+    let (input, zone) = zone_str(input)?;
+    let (input, colors) = nom::multi::many1(color_param_str)(input)?;
+    Ok((input, SocketCommand::SetZone(zone, colors)))
+}
+
+pub fn socket_command_set_layer_str(input: &str) -> nom::IResult<&str, SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag("layer")(input)?;
+    let (input, _) = nom::character::complete::space1(input)?;
+    let (input, layer) = layer_str(input)?;
+    Ok((input, SocketCommand::SetLayer(layer)))
+}
 
-    Ok((input, SocketCommand::SetColors(Box::new(colors))))
+pub fn socket_command_clear_layer_str(input: &str) -> nom::IResult<&str, SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag("clear-layer")(input)?;
+    Ok((input, SocketCommand::ClearLayer))
+}
+
+pub fn socket_command_set_output_str(input: &str) -> nom::IResult<&str, SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag("set-output")(input)?;
+    let (input, _) = nom::character::complete::space1(input)?;
+    let (input, zone) = nom::combinator::opt(nom::sequence::terminated(
+        zone_str,
+        nom::character::complete::space1,
+    ))(input)?;
+    let (input, setting) = output_setting_str(input)?;
+    Ok((input, SocketCommand::SetOutput(zone, setting)))
 }
 
 pub fn socket_command_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
     nom::branch::alt((
-        socket_command_set_colors_bytes,
+        socket_command_controller_bytes,
+        socket_command_unprefixed_bytes,
+    ))(input)
+}
+
+/// Parse any command but a controller prefix, so prefixes can't be nested
+pub fn socket_command_unprefixed_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
+    nom::branch::alt((
+        socket_command_lighting_bytes,
         socket_command_set_fan_speed_bytes,
+        socket_command_clear_fan_target_bytes,
+        socket_command_set_rpm_target_bytes,
         socket_command_get_coolant_temp_bytes,
         socket_command_get_pump_speed_bytes,
+        socket_command_get_status_bytes,
+        socket_command_get_alarm_bytes,
+        socket_command_get_channel_bytes,
+        socket_command_get_channels_bytes,
+        socket_command_get_firmware_bytes,
+        socket_command_get_colors_bytes,
+        socket_command_get_output_bytes,
+        socket_command_get_frames_bytes,
+        socket_command_subscribe_bytes,
+        socket_command_unsubscribe_bytes,
+        socket_command_set_format_bytes,
+    ))(input)
+}
+
+pub fn socket_command_lighting_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
+    nom::branch::alt((
+        socket_command_set_colors_bytes,
+        socket_command_set_effect_bytes,
+        socket_command_set_zone_bytes,
+        socket_command_set_layer_bytes,
+        socket_command_clear_layer_bytes,
+        socket_command_set_output_bytes,
     ))(input)
 }
 
+pub fn socket_command_controller_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_CONTROLLER])(input)?;
+    let (input, index) = nom::number::complete::u8(input)?;
+    let (input, command) = socket_command_unprefixed_bytes(input)?;
+    Ok((input, SocketCommand::Controller(index, Box::new(command))))
+}
+
 pub fn socket_command_get_coolant_temp_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
     let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_GET_COOLANT_TEMP])(input)?;
     Ok((input, SocketCommand::GetCoolantTemp))
@@ -197,6 +703,67 @@ pub fn socket_command_get_pump_speed_bytes(input: &[u8]) -> nom::IResult<&[u8],
     Ok((input, SocketCommand::GetPumpSpeed))
 }
 
+pub fn socket_command_get_status_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_GET_STATUS])(input)?;
+    Ok((input, SocketCommand::GetStatus))
+}
+
+pub fn socket_command_get_alarm_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_GET_ALARM])(input)?;
+    Ok((input, SocketCommand::GetAlarm))
+}
+
+pub fn socket_command_get_channel_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_GET_CHANNEL])(input)?;
+    let (input, fan) = nom::combinator::map_res(nom::number::complete::u8, Fan::try_from)(input)?;
+    Ok((input, SocketCommand::GetChannel(fan)))
+}
+
+pub fn socket_command_get_channels_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_GET_CHANNELS])(input)?;
+    Ok((input, SocketCommand::GetChannels))
+}
+
+pub fn socket_command_get_firmware_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_GET_FIRMWARE])(input)?;
+    Ok((input, SocketCommand::GetFirmware))
+}
+
+pub fn socket_command_get_colors_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_GET_COLORS])(input)?;
+    Ok((input, SocketCommand::GetColors))
+}
+
+pub fn socket_command_get_output_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_GET_OUTPUT])(input)?;
+    Ok((input, SocketCommand::GetOutput))
+}
+
+pub fn socket_command_get_frames_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_GET_FRAMES])(input)?;
+    Ok((input, SocketCommand::GetFrames))
+}
+
+pub fn socket_command_subscribe_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_SUBSCRIBE])(input)?;
+    let (input, interval) = nom::number::complete::le_u16(input)?;
+    Ok((input, SocketCommand::Subscribe(interval)))
+}
+
+pub fn socket_command_unsubscribe_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_UNSUBSCRIBE])(input)?;
+    Ok((input, SocketCommand::Unsubscribe))
+}
+
+pub fn socket_command_set_format_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_SET_FORMAT])(input)?;
+    let (input, format) = nom::branch::alt((
+        nom::combinator::value(TextFormat::Plain, nom::bytes::complete::tag([0])),
+        nom::combinator::value(TextFormat::Json, nom::bytes::complete::tag([1])),
+    ))(input)?;
+    Ok((input, SocketCommand::SetFormat(format)))
+}
+
 pub fn socket_command_set_fan_speed_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
     let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_SET_PUMP_SPEED])(input)?;
     let (input, fan) = nom::number::complete::u8(input)?;
@@ -211,17 +778,137 @@ pub fn socket_command_set_fan_speed_bytes(input: &[u8]) -> nom::IResult<&[u8], S
     Ok((input, SocketCommand::SetFanTarget(fan, speed)))
 }
 
+pub fn socket_command_clear_fan_target_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_CLEAR_FAN_TARGET])(input)?;
+    let (input, fan) = nom::number::complete::u8(input)?;
+    let fan = Fan::try_from(fan).map_err(|_| {
+        nom::Err::Error(nom::error::Error {
+            input,
+            code: nom::error::ErrorKind::AlphaNumeric,
+        })
+    })?;
+
+    Ok((input, SocketCommand::ClearFanTarget(fan)))
+}
+
+pub fn socket_command_set_rpm_target_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_SET_RPM_TARGET])(input)?;
+    let (input, fan) = nom::number::complete::u8(input)?;
+    let fan = Fan::try_from(fan).map_err(|_| {
+        nom::Err::Error(nom::error::Error {
+            input,
+            code: nom::error::ErrorKind::AlphaNumeric,
+        })
+    })?;
+
+    let (input, rpm) = nom::number::complete::le_u16(input)?;
+    Ok((input, SocketCommand::SetRpmTarget(fan, rpm)))
+}
+
+/// Parse the payload of a legacy `CPLX` frame
+///
+/// Legacy clients send SetColors without a count, as triples running to the end of the frame.
+pub fn socket_command_legacy_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
+    nom::branch::alt((socket_command_legacy_set_colors_bytes, socket_command_bytes))(input)
+}
+
+pub fn socket_command_legacy_set_colors_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_SET_COLORS])(input)?;
+    let (input, colors) = nom::combinator::all_consuming(nom::multi::many0(nom::combinator::map(
+        nom::bytes::complete::take(3usize),
+        |color: &[u8]| [color[0], color[1], color[2]],
+    )))(input)?;
+
+    Ok((input, SocketCommand::SetColors(colors)))
+}
+
 pub fn socket_command_set_colors_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
     let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_SET_COLORS])(input)?;
-    let (input, buf) = nom::multi::count(
+    let (input, count) = nom::number::complete::le_u16(input)?;
+    let (input, colors) = nom::multi::count(
+        nom::combinator::map(nom::multi::count(nom::number::complete::u8, 3), |color| {
+            [color[0], color[1], color[2]]
+        }),
+        count as usize,
+    )(input)?;
+
+    Ok((input, SocketCommand::SetColors(colors)))
+}
+
+pub fn socket_command_set_effect_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_SET_EFFECT])(input)?;
+    let (input, effect) = effect_bytes(input)?;
+    Ok((input, SocketCommand::SetEffect(effect)))
+}
+
+pub fn socket_command_set_zone_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_SET_ZONE])(input)?;
+    let (input, zone) = zone_bytes(input)?;
+    let (input, colors) = nom::multi::length_count(
+        nom::number::complete::le_u16,
         nom::combinator::map(nom::multi::count(nom::number::complete::u8, 3), |color| {
             [color[0], color[1], color[2]]
         }),
-        LED_COUNT_TOTAL,
     )(input)?;
 
-    let mut colors = [[0; 3]; LED_COUNT_TOTAL];
-    colors.copy_from_slice(&buf);
+    Ok((input, SocketCommand::SetZone(zone, colors)))
+}
 
-    Ok((input, SocketCommand::SetColors(Box::new(colors))))
+pub fn socket_command_set_layer_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_SET_LAYER])(input)?;
+    let (input, layer) = layer_bytes(input)?;
+    Ok((input, SocketCommand::SetLayer(layer)))
+}
+
+pub fn socket_command_clear_layer_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_CLEAR_LAYER])(input)?;
+    Ok((input, SocketCommand::ClearLayer))
+}
+
+pub fn socket_command_set_output_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketCommand> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_SET_OUTPUT])(input)?;
+    let (input, zone) = nom::branch::alt((
+        nom::combinator::value(None, nom::bytes::complete::tag([0])),
+        nom::combinator::map(
+            nom::sequence::preceded(nom::bytes::complete::tag([1]), zone_bytes),
+            Some,
+        ),
+    ))(input)?;
+    let (input, setting) = output_setting_bytes(input)?;
+    Ok((input, SocketCommand::SetOutput(zone, setting)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_controller_prefix() {
+        assert!(matches!(
+            socket_command_str("controller 1 get-firmware"),
+            Ok(("", SocketCommand::Controller(1, command)))
+                if matches!(*command, SocketCommand::GetFirmware)
+        ));
+        assert!(matches!(
+            socket_command_bytes(&[SOCKET_COMMAND_CONTROLLER, 1, SOCKET_COMMAND_GET_FIRMWARE]),
+            Ok(([], SocketCommand::Controller(1, command)))
+                if matches!(*command, SocketCommand::GetFirmware)
+        ));
+    }
+
+    #[test]
+    fn rejects_nested_controller_prefixes() {
+        assert!(socket_command_str("controller 0 controller 1 get-firmware").is_err());
+
+        // Deep enough to overflow the stack if each prefix recursed
+        let line = "controller 0 ".repeat(30000) + "get-firmware";
+        assert!(socket_command_str(&line).is_err());
+
+        let bytes = [
+            [SOCKET_COMMAND_CONTROLLER, 0].repeat(30000),
+            vec![SOCKET_COMMAND_GET_FIRMWARE],
+        ]
+        .concat();
+        assert!(socket_command_bytes(&bytes).is_err());
+    }
 }
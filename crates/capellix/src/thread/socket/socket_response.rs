@@ -1,18 +1,182 @@
-use std::fmt::Display;
+use std::{fmt::Display, sync::atomic::Ordering};
 
 use anyhow::{anyhow, Error};
+use serde::Serialize;
 
-use crate::thread::socket::socket_command::{
-    SOCKET_COMMAND_GET_COOLANT_TEMP, SOCKET_COMMAND_GET_PUMP_SPEED, SOCKET_COMMAND_SET_COLORS,
-    SOCKET_COMMAND_SET_PUMP_SPEED,
+use crate::{
+    hid::{protocol::FirmwareVersion, topology::FanType},
+    output::{output_stage_bytes, OutputStage},
+    thermal::ThermalState,
+    thread::socket::socket_command::{
+        SOCKET_COMMAND_CLEAR_FAN_TARGET, SOCKET_COMMAND_CLEAR_LAYER, SOCKET_COMMAND_GET_ALARM,
+        SOCKET_COMMAND_GET_CHANNEL, SOCKET_COMMAND_GET_CHANNELS, SOCKET_COMMAND_GET_COLORS,
+        SOCKET_COMMAND_GET_COOLANT_TEMP, SOCKET_COMMAND_GET_FIRMWARE, SOCKET_COMMAND_GET_FRAMES,
+        SOCKET_COMMAND_GET_OUTPUT, SOCKET_COMMAND_GET_PUMP_SPEED, SOCKET_COMMAND_GET_STATUS,
+        SOCKET_COMMAND_SET_COLORS, SOCKET_COMMAND_SET_EFFECT, SOCKET_COMMAND_SET_FORMAT,
+        SOCKET_COMMAND_SET_LAYER, SOCKET_COMMAND_SET_OUTPUT, SOCKET_COMMAND_SET_PUMP_SPEED,
+        SOCKET_COMMAND_SET_RPM_TARGET, SOCKET_COMMAND_SET_ZONE, SOCKET_COMMAND_SUBSCRIBE,
+        SOCKET_COMMAND_UNSUBSCRIBE, SOCKET_RESPONSE_ERROR, SOCKET_RESPONSE_TELEMETRY,
+        SOCKET_RESPONSE_UNAVAILABLE,
+    },
+    thread::{
+        capellix::{Colors, SharedState},
+        socket::telemetry::{telemetry_bytes, Telemetry},
+    },
 };
 
-#[derive(Debug)]
+/// Device type, speed and target of a single channel
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub struct ChannelStatus {
+    pub channel: u8,
+    /// Connected device, or None if the channel is empty
+    pub fan_type: Option<FanType>,
+    /// Speed in RPM
+    pub speed: u16,
+    /// Duty target
+    pub target: u16,
+}
+
+impl ChannelStatus {
+    pub fn capture(state: &SharedState, channel: usize) -> Option<Self> {
+        Some(ChannelStatus {
+            channel: channel as u8,
+            fan_type: state.fan_type(channel),
+            speed: state.speeds.get(channel)?.load(Ordering::Relaxed),
+            target: state.fan_targets.get(channel)?.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Encode as `[channel, fan type, speed (LE u16), target (LE u16)]`,
+    /// where a fan type of 0 is an empty channel
+    fn encode(&self) -> Vec<u8> {
+        [
+            &[
+                self.channel,
+                self.fan_type.map(u8::from).unwrap_or_default(),
+            ][..],
+            &self.speed.to_le_bytes()[..],
+            &self.target.to_le_bytes()[..],
+        ]
+        .concat()
+    }
+}
+
+impl Display for ChannelStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.channel {
+            0 => f.write_str("pump: ")?,
+            channel => f.write_fmt(format_args!("fan{channel:}: "))?,
+        }
+
+        match self.fan_type {
+            Some(fan_type) => f.write_fmt(format_args!(
+                "{fan_type:} {} rpm, target {}",
+                self.speed, self.target
+            )),
+            None => f.write_str("disconnected"),
+        }
+    }
+}
+
+/// Counters and HID timings for the frames of LED colors sent to a controller
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub struct FrameStats {
+    /// Frames sent to the device
+    pub sent: u32,
+    /// Frames skipped because they matched the last frame sent
+    pub skipped: u32,
+    /// Lighting changes replaced by a later one before the next frame was rendered
+    pub coalesced: u32,
+    /// Time spent sending the last frame, in microseconds
+    pub last: u32,
+    /// Moving average of the time spent sending a frame, in microseconds
+    pub average: u32,
+    /// Longest time spent sending a frame, in microseconds
+    pub max: u32,
+}
+
+impl FrameStats {
+    pub fn capture(state: &SharedState) -> Self {
+        FrameStats {
+            sent: state.frames_sent.load(Ordering::Relaxed),
+            skipped: state.frames_skipped.load(Ordering::Relaxed),
+            coalesced: state.frames_coalesced.load(Ordering::Relaxed),
+            last: state.frame_time.load(Ordering::Relaxed),
+            average: state.frame_time_average.load(Ordering::Relaxed),
+            max: state.frame_time_max.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Encode as `[sent, skipped, coalesced, last, average, max]`, each as LE u32
+    fn encode(&self) -> Vec<u8> {
+        [
+            self.sent,
+            self.skipped,
+            self.coalesced,
+            self.last,
+            self.average,
+            self.max,
+        ]
+        .into_iter()
+        .flat_map(u32::to_le_bytes)
+        .collect()
+    }
+}
+
+impl Display for FrameStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{} sent, {} skipped, {} coalesced, {:.2} ms last, {:.2} ms average, {:.2} ms max",
+            self.sent,
+            self.skipped,
+            self.coalesced,
+            self.last as f32 / 1000.0,
+            self.average as f32 / 1000.0,
+            self.max as f32 / 1000.0,
+        ))
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "response", content = "value", rename_all = "kebab-case")]
 pub enum SocketResponse {
     GetCoolantTemp(u16),
     GetPumpSpeed(u16),
     SetPumpSpeed(bool),
+    ClearFanTarget(bool),
+    SetRpmTarget(bool),
     SetColors(bool),
+    SetZone(bool),
+    SetEffect(bool),
+    SetLayer(bool),
+    /// Whether the connection's current layer was removed
+    ClearLayer(bool),
+    SetOutput(bool),
+    /// Whether the addressed controller is connected
+    GetStatus(bool),
+    GetChannel(ChannelStatus),
+    GetChannels(Vec<ChannelStatus>),
+    GetFirmware(FirmwareVersion),
+    GetColors(Colors),
+    GetOutput(OutputStage),
+    GetFrames(FrameStats),
+    GetAlarm {
+        /// Bitmask of stalled channels, where bit 0 is the pump
+        stalled: u8,
+        thermal: ThermalState,
+    },
+    Subscribe(bool),
+    /// Whether a subscription to the addressed controller was removed
+    Unsubscribe(bool),
+    SetFormat(bool),
+    /// Update pushed to a subscribed client
+    Telemetry(Telemetry),
+    /// The addressed controller is disconnected, so no reading is available
+    Unavailable,
+    /// The command failed, with the given reason
+    ///
+    /// Only sent to versioned clients.
+    Error(String),
 }
 
 impl Display for SocketResponse {
@@ -21,7 +185,61 @@ impl Display for SocketResponse {
             SocketResponse::GetCoolantTemp(temp) => temp.fmt(f),
             SocketResponse::GetPumpSpeed(speed) => speed.fmt(f),
             SocketResponse::SetPumpSpeed(success) => success.fmt(f),
+            SocketResponse::ClearFanTarget(success) => success.fmt(f),
+            SocketResponse::SetRpmTarget(success) => success.fmt(f),
             SocketResponse::SetColors(success) => success.fmt(f),
+            SocketResponse::SetZone(success) => success.fmt(f),
+            SocketResponse::SetEffect(success) => success.fmt(f),
+            SocketResponse::SetLayer(success) => success.fmt(f),
+            SocketResponse::ClearLayer(success) => success.fmt(f),
+            SocketResponse::SetOutput(success) => success.fmt(f),
+            SocketResponse::GetStatus(true) => f.write_str("connected"),
+            SocketResponse::GetStatus(false) => f.write_str("unavailable"),
+            SocketResponse::GetChannel(status) => status.fmt(f),
+            SocketResponse::GetChannels(statuses) => {
+                for (i, status) in statuses.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str("\n")?;
+                    }
+                    status.fmt(f)?;
+                }
+                Ok(())
+            }
+            SocketResponse::GetFirmware(firmware) => firmware.fmt(f),
+            SocketResponse::GetColors(colors) => {
+                for (i, [r, g, b]) in colors.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(" ")?;
+                    }
+                    f.write_fmt(format_args!("#{r:02x}{g:02x}{b:02x}"))?;
+                }
+                Ok(())
+            }
+            SocketResponse::GetOutput(output) => output.fmt(f),
+            SocketResponse::GetFrames(stats) => stats.fmt(f),
+            SocketResponse::GetAlarm {
+                stalled: 0,
+                thermal: ThermalState::Normal,
+            } => f.write_str("ok"),
+            SocketResponse::GetAlarm { stalled, thermal } => {
+                f.write_fmt(format_args!("coolant {thermal:}"))?;
+                if *stalled != 0 {
+                    f.write_str(", stalled:")?;
+                    for channel in (0..8).filter(|channel| stalled & 1 << channel != 0) {
+                        match channel {
+                            0 => f.write_str(" pump")?,
+                            channel => f.write_fmt(format_args!(" fan{channel:}"))?,
+                        }
+                    }
+                }
+                Ok(())
+            }
+            SocketResponse::Subscribe(success) => success.fmt(f),
+            SocketResponse::Unsubscribe(success) => success.fmt(f),
+            SocketResponse::SetFormat(success) => success.fmt(f),
+            SocketResponse::Telemetry(telemetry) => telemetry.fmt(f),
+            SocketResponse::Unavailable => f.write_str("device unavailable"),
+            SocketResponse::Error(message) => f.write_str(message),
         }
     }
 }
@@ -45,9 +263,106 @@ impl From<SocketResponse> for Vec<u8> {
                     if success { 0x01 } else { 0x00 },
                 ]
             }
+            SocketResponse::ClearFanTarget(success) => {
+                vec![
+                    SOCKET_COMMAND_CLEAR_FAN_TARGET,
+                    if success { 0x01 } else { 0x00 },
+                ]
+            }
+            SocketResponse::SetRpmTarget(success) => {
+                vec![
+                    SOCKET_COMMAND_SET_RPM_TARGET,
+                    if success { 0x01 } else { 0x00 },
+                ]
+            }
             SocketResponse::SetColors(success) => {
                 vec![SOCKET_COMMAND_SET_COLORS, if success { 0x01 } else { 0x00 }]
             }
+            SocketResponse::SetZone(success) => {
+                vec![SOCKET_COMMAND_SET_ZONE, if success { 0x01 } else { 0x00 }]
+            }
+            SocketResponse::SetEffect(success) => {
+                vec![SOCKET_COMMAND_SET_EFFECT, if success { 0x01 } else { 0x00 }]
+            }
+            SocketResponse::SetLayer(success) => {
+                vec![SOCKET_COMMAND_SET_LAYER, if success { 0x01 } else { 0x00 }]
+            }
+            SocketResponse::ClearLayer(success) => {
+                vec![
+                    SOCKET_COMMAND_CLEAR_LAYER,
+                    if success { 0x01 } else { 0x00 },
+                ]
+            }
+            SocketResponse::SetOutput(success) => {
+                vec![SOCKET_COMMAND_SET_OUTPUT, if success { 0x01 } else { 0x00 }]
+            }
+            SocketResponse::GetStatus(connected) => {
+                vec![
+                    SOCKET_COMMAND_GET_STATUS,
+                    if connected { 0x01 } else { 0x00 },
+                ]
+            }
+            SocketResponse::GetAlarm { stalled, thermal } => {
+                vec![SOCKET_COMMAND_GET_ALARM, stalled, thermal.into()]
+            }
+            SocketResponse::GetChannel(status) => {
+                [&[SOCKET_COMMAND_GET_CHANNEL][..], &status.encode()[..]].concat()
+            }
+            SocketResponse::GetChannels(statuses) => [
+                &[SOCKET_COMMAND_GET_CHANNELS, statuses.len() as u8][..],
+                &statuses
+                    .iter()
+                    .flat_map(ChannelStatus::encode)
+                    .collect::<Vec<_>>()[..],
+            ]
+            .concat(),
+            SocketResponse::GetFirmware(firmware) => vec![
+                SOCKET_COMMAND_GET_FIRMWARE,
+                firmware.major,
+                firmware.minor,
+                firmware.patch,
+            ],
+            SocketResponse::GetColors(colors) => [
+                &[SOCKET_COMMAND_GET_COLORS][..],
+                &(colors.len() as u16).to_le_bytes()[..],
+                &colors.into_iter().flatten().collect::<Vec<_>>()[..],
+            ]
+            .concat(),
+            SocketResponse::GetOutput(output) => {
+                [&[SOCKET_COMMAND_GET_OUTPUT][..], &output.encode()[..]].concat()
+            }
+            SocketResponse::GetFrames(stats) => {
+                [&[SOCKET_COMMAND_GET_FRAMES][..], &stats.encode()[..]].concat()
+            }
+            SocketResponse::Subscribe(success) => {
+                vec![SOCKET_COMMAND_SUBSCRIBE, if success { 0x01 } else { 0x00 }]
+            }
+            SocketResponse::Unsubscribe(success) => {
+                vec![
+                    SOCKET_COMMAND_UNSUBSCRIBE,
+                    if success { 0x01 } else { 0x00 },
+                ]
+            }
+            SocketResponse::SetFormat(success) => {
+                vec![SOCKET_COMMAND_SET_FORMAT, if success { 0x01 } else { 0x00 }]
+            }
+            SocketResponse::Telemetry(telemetry) => {
+                [&[SOCKET_RESPONSE_TELEMETRY][..], &telemetry.encode()[..]].concat()
+            }
+            SocketResponse::Unavailable => vec![SOCKET_RESPONSE_UNAVAILABLE],
+            SocketResponse::Error(message) => {
+                [&[SOCKET_RESPONSE_ERROR][..], message.as_bytes()].concat()
+            }
+        }
+    }
+}
+
+impl SocketResponse {
+    /// Encode for a legacy client, which expects setters to be acknowledged with a bare `[1]`
+    pub fn into_legacy_bytes(self) -> Vec<u8> {
+        match self {
+            SocketResponse::SetPumpSpeed(_) | SocketResponse::SetColors(_) => vec![1],
+            response => response.into(),
         }
     }
 }
@@ -55,7 +370,7 @@ impl From<SocketResponse> for Vec<u8> {
 impl TryFrom<&[u8]> for SocketResponse {
     type Error = Error;
 
-    fn try_from(s: &[u8]) -> Result<Self, Self::Error> {
+    fn try_from(s: &[u8]) -> Result<Self, Error> {
         let (_, output) =
             socket_response_bytes(s).map_err(|_| anyhow!("Invalid socket response"))?;
         Ok(output)
@@ -67,7 +382,23 @@ fn socket_response_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketResponse> {
         socket_response_get_coolant_temp_bytes,
         socket_response_get_pump_speed_bytes,
         socket_response_set_pump_speed_bytes,
-        socket_response_set_colors_bytes,
+        socket_response_clear_fan_target_bytes,
+        socket_response_set_rpm_target_bytes,
+        socket_response_lighting_bytes,
+        socket_response_get_status_bytes,
+        socket_response_get_alarm_bytes,
+        socket_response_get_channel_bytes,
+        socket_response_get_channels_bytes,
+        socket_response_get_firmware_bytes,
+        socket_response_get_colors_bytes,
+        socket_response_get_output_bytes,
+        socket_response_get_frames_bytes,
+        socket_response_subscribe_bytes,
+        socket_response_unsubscribe_bytes,
+        socket_response_set_format_bytes,
+        socket_response_telemetry_bytes,
+        socket_response_unavailable_bytes,
+        socket_response_error_bytes,
     ))(input)
 }
 
@@ -89,8 +420,197 @@ fn socket_response_set_pump_speed_bytes(input: &[u8]) -> nom::IResult<&[u8], Soc
     Ok((input, SocketResponse::SetPumpSpeed(success == 1)))
 }
 
+fn socket_response_clear_fan_target_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketResponse> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_CLEAR_FAN_TARGET])(input)?;
+    let (input, success) = nom::number::complete::u8(input)?;
+    Ok((input, SocketResponse::ClearFanTarget(success == 1)))
+}
+
+fn socket_response_set_rpm_target_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketResponse> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_SET_RPM_TARGET])(input)?;
+    let (input, success) = nom::number::complete::u8(input)?;
+    Ok((input, SocketResponse::SetRpmTarget(success == 1)))
+}
+
+fn socket_response_lighting_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketResponse> {
+    nom::branch::alt((
+        socket_response_set_colors_bytes,
+        socket_response_set_effect_bytes,
+        socket_response_set_zone_bytes,
+        socket_response_set_layer_bytes,
+        socket_response_clear_layer_bytes,
+        socket_response_set_output_bytes,
+    ))(input)
+}
+
 fn socket_response_set_colors_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketResponse> {
     let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_SET_COLORS])(input)?;
     let (input, success) = nom::number::complete::u8(input)?;
     Ok((input, SocketResponse::SetColors(success == 1)))
 }
+
+fn socket_response_set_effect_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketResponse> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_SET_EFFECT])(input)?;
+    let (input, success) = nom::number::complete::u8(input)?;
+    Ok((input, SocketResponse::SetEffect(success == 1)))
+}
+
+fn socket_response_set_zone_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketResponse> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_SET_ZONE])(input)?;
+    let (input, success) = nom::number::complete::u8(input)?;
+    Ok((input, SocketResponse::SetZone(success == 1)))
+}
+
+fn socket_response_set_layer_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketResponse> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_SET_LAYER])(input)?;
+    let (input, success) = nom::number::complete::u8(input)?;
+    Ok((input, SocketResponse::SetLayer(success == 1)))
+}
+
+fn socket_response_clear_layer_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketResponse> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_CLEAR_LAYER])(input)?;
+    let (input, success) = nom::number::complete::u8(input)?;
+    Ok((input, SocketResponse::ClearLayer(success == 1)))
+}
+
+fn socket_response_set_output_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketResponse> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_SET_OUTPUT])(input)?;
+    let (input, success) = nom::number::complete::u8(input)?;
+    Ok((input, SocketResponse::SetOutput(success == 1)))
+}
+
+fn socket_response_get_output_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketResponse> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_GET_OUTPUT])(input)?;
+    let (input, output) = output_stage_bytes(input)?;
+    Ok((input, SocketResponse::GetOutput(output)))
+}
+
+fn socket_response_get_frames_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketResponse> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_GET_FRAMES])(input)?;
+    let (input, (sent, skipped, coalesced, last, average, max)) = nom::sequence::tuple((
+        nom::number::complete::le_u32,
+        nom::number::complete::le_u32,
+        nom::number::complete::le_u32,
+        nom::number::complete::le_u32,
+        nom::number::complete::le_u32,
+        nom::number::complete::le_u32,
+    ))(input)?;
+    Ok((
+        input,
+        SocketResponse::GetFrames(FrameStats {
+            sent,
+            skipped,
+            coalesced,
+            last,
+            average,
+            max,
+        }),
+    ))
+}
+
+fn socket_response_get_status_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketResponse> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_GET_STATUS])(input)?;
+    let (input, connected) = nom::number::complete::u8(input)?;
+    Ok((input, SocketResponse::GetStatus(connected == 1)))
+}
+
+fn socket_response_get_alarm_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketResponse> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_GET_ALARM])(input)?;
+    let (input, stalled) = nom::number::complete::u8(input)?;
+    let (input, thermal) =
+        nom::combinator::map_res(nom::number::complete::u8, ThermalState::try_from)(input)?;
+    Ok((input, SocketResponse::GetAlarm { stalled, thermal }))
+}
+
+fn channel_status_bytes(input: &[u8]) -> nom::IResult<&[u8], ChannelStatus> {
+    let (input, channel) = nom::number::complete::u8(input)?;
+    let (input, fan_type) = nom::number::complete::u8(input)?;
+    let (input, speed) = nom::number::complete::le_u16(input)?;
+    let (input, target) = nom::number::complete::le_u16(input)?;
+    Ok((
+        input,
+        ChannelStatus {
+            channel,
+            fan_type: FanType::try_from(fan_type).ok(),
+            speed,
+            target,
+        },
+    ))
+}
+
+fn socket_response_get_channel_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketResponse> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_GET_CHANNEL])(input)?;
+    let (input, status) = channel_status_bytes(input)?;
+    Ok((input, SocketResponse::GetChannel(status)))
+}
+
+fn socket_response_get_channels_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketResponse> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_GET_CHANNELS])(input)?;
+    let (input, statuses) =
+        nom::multi::length_count(nom::number::complete::u8, channel_status_bytes)(input)?;
+    Ok((input, SocketResponse::GetChannels(statuses)))
+}
+
+fn socket_response_get_firmware_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketResponse> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_GET_FIRMWARE])(input)?;
+    let (input, major) = nom::number::complete::u8(input)?;
+    let (input, minor) = nom::number::complete::u8(input)?;
+    let (input, patch) = nom::number::complete::u8(input)?;
+    Ok((
+        input,
+        SocketResponse::GetFirmware(FirmwareVersion {
+            major,
+            minor,
+            patch,
+        }),
+    ))
+}
+
+fn socket_response_get_colors_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketResponse> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_GET_COLORS])(input)?;
+    let (input, colors) = nom::multi::length_count(
+        nom::number::complete::le_u16,
+        nom::combinator::map(nom::multi::count(nom::number::complete::u8, 3), |color| {
+            [color[0], color[1], color[2]]
+        }),
+    )(input)?;
+    Ok((input, SocketResponse::GetColors(colors)))
+}
+
+fn socket_response_subscribe_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketResponse> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_SUBSCRIBE])(input)?;
+    let (input, success) = nom::number::complete::u8(input)?;
+    Ok((input, SocketResponse::Subscribe(success == 1)))
+}
+
+fn socket_response_unsubscribe_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketResponse> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_UNSUBSCRIBE])(input)?;
+    let (input, success) = nom::number::complete::u8(input)?;
+    Ok((input, SocketResponse::Unsubscribe(success == 1)))
+}
+
+fn socket_response_set_format_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketResponse> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_COMMAND_SET_FORMAT])(input)?;
+    let (input, success) = nom::number::complete::u8(input)?;
+    Ok((input, SocketResponse::SetFormat(success == 1)))
+}
+
+fn socket_response_telemetry_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketResponse> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_RESPONSE_TELEMETRY])(input)?;
+    let (input, telemetry) = telemetry_bytes(input)?;
+    Ok((input, SocketResponse::Telemetry(telemetry)))
+}
+
+fn socket_response_unavailable_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketResponse> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_RESPONSE_UNAVAILABLE])(input)?;
+    Ok((input, SocketResponse::Unavailable))
+}
+
+fn socket_response_error_bytes(input: &[u8]) -> nom::IResult<&[u8], SocketResponse> {
+    let (input, _) = nom::bytes::complete::tag([SOCKET_RESPONSE_ERROR])(input)?;
+    let (input, message) = nom::combinator::rest(input)?;
+    Ok((
+        input,
+        SocketResponse::Error(String::from_utf8_lossy(message).into_owned()),
+    ))
+}
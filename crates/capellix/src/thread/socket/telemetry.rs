@@ -0,0 +1,229 @@
+use std::{
+    fmt::Display,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+
+use crate::{
+    thermal::ThermalState,
+    thread::{
+        capellix::SharedState,
+        controller::ControllerHandle,
+        socket::{frame::Framing, socket_command::SocketCommand, socket_response::SocketResponse},
+    },
+};
+
+/// Snapshot of a controller's readings, targets and alarms
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Telemetry {
+    pub available: bool,
+    /// Coolant temperature in tenths of a degree celsius
+    pub coolant_temp: u16,
+    /// Speed of each channel in RPM
+    pub speeds: Vec<u16>,
+    /// Duty target of each channel
+    pub targets: Vec<u16>,
+    /// Bitmask of stalled channels, where bit 0 is the pump
+    pub stalled: u8,
+    pub thermal: ThermalState,
+}
+
+impl Telemetry {
+    pub fn capture(state: &SharedState) -> Self {
+        let load = |values: &[std::sync::atomic::AtomicU16]| {
+            values
+                .iter()
+                .map(|value| value.load(Ordering::Relaxed))
+                .collect()
+        };
+
+        Telemetry {
+            available: state.available.load(Ordering::Relaxed),
+            coolant_temp: state.coolant_temp.load(Ordering::Relaxed),
+            speeds: load(&state.speeds),
+            targets: load(&state.fan_targets),
+            stalled: state.stalled.load(Ordering::Relaxed),
+            thermal: ThermalState::try_from(state.thermal.load(Ordering::Relaxed))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Encode as `[available, temp (LE u16), stalled, thermal, count, speeds (LE u16)..., targets (LE u16)...]`
+    pub fn encode(&self) -> Vec<u8> {
+        [
+            &[self.available as u8][..],
+            &self.coolant_temp.to_le_bytes()[..],
+            &[self.stalled, self.thermal.into(), self.speeds.len() as u8],
+            &self
+                .speeds
+                .iter()
+                .chain(&self.targets)
+                .flat_map(|value| value.to_le_bytes())
+                .collect::<Vec<_>>()[..],
+        ]
+        .concat()
+    }
+}
+
+impl Display for Telemetry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.available {
+            return f.write_str("device unavailable");
+        }
+
+        let join = |values: &[u16]| {
+            values
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+
+        f.write_fmt(format_args!(
+            "coolant {:.1} rpm {} targets {} thermal {}",
+            self.coolant_temp as f32 / 10.0,
+            join(&self.speeds),
+            join(&self.targets),
+            self.thermal
+        ))?;
+
+        if self.stalled != 0 {
+            f.write_fmt(format_args!(" stalled {:#04x}", self.stalled))?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn telemetry_bytes(input: &[u8]) -> nom::IResult<&[u8], Telemetry> {
+    let (input, available) = nom::number::complete::u8(input)?;
+    let (input, coolant_temp) = nom::number::complete::le_u16(input)?;
+    let (input, stalled) = nom::number::complete::u8(input)?;
+    let (input, thermal) =
+        nom::combinator::map_res(nom::number::complete::u8, ThermalState::try_from)(input)?;
+    let (input, count) = nom::number::complete::u8(input)?;
+    let (input, speeds) = nom::multi::count(nom::number::complete::le_u16, count as usize)(input)?;
+    let (input, targets) = nom::multi::count(nom::number::complete::le_u16, count as usize)(input)?;
+
+    Ok((
+        input,
+        Telemetry {
+            available: available == 1,
+            coolant_temp,
+            speeds,
+            targets,
+            stalled,
+            thermal,
+        },
+    ))
+}
+
+/// Telemetry pushed to a socket client for a single controller
+#[derive(Debug)]
+pub struct Subscription {
+    pub index: usize,
+    /// Framing of the subscribe request, reused for each update
+    pub framing: Framing,
+    /// Time between updates, or None to send only when the telemetry changes
+    pub interval: Option<Duration>,
+    last_sent: Option<(Instant, Telemetry)>,
+}
+
+impl Subscription {
+    pub fn new(index: usize, framing: Framing, interval: Option<Duration>) -> Self {
+        Subscription {
+            index,
+            framing,
+            interval,
+            last_sent: None,
+        }
+    }
+
+    /// Return the given snapshot if it's due to be sent
+    pub fn poll(&mut self, telemetry: Telemetry) -> Option<Telemetry> {
+        let due = match (&self.last_sent, self.interval) {
+            (None, _) => true,
+            (Some((sent_at, _)), Some(interval)) => sent_at.elapsed() >= interval,
+            (Some((_, last)), None) => *last != telemetry,
+        };
+
+        if !due {
+            return None;
+        }
+
+        self.last_sent = Some((Instant::now(), telemetry.clone()));
+        Some(telemetry)
+    }
+}
+
+/// Telemetry subscriptions held by a single connection, at most one per controller
+#[derive(Debug, Default)]
+pub struct Subscriptions(Vec<Subscription>);
+
+impl Subscriptions {
+    /// Apply a subscribe or unsubscribe command, returning the response to send
+    pub fn update(
+        &mut self,
+        controllers: &[ControllerHandle],
+        framing: Framing,
+        command: SocketCommand,
+    ) -> Result<SocketResponse> {
+        let (index, command) = match command {
+            SocketCommand::Controller(index, command) => (index as usize, *command),
+            command => (0, command),
+        };
+
+        if index >= controllers.len() {
+            return Err(anyhow!("No controller at index {index:}"));
+        }
+
+        let existing = self
+            .0
+            .iter()
+            .position(|subscription| subscription.index == index);
+
+        match command {
+            SocketCommand::Subscribe(interval) => {
+                let interval = match interval {
+                    0 => None,
+                    interval => Some(Duration::from_millis(interval as u64)),
+                };
+
+                // Subscribing again replaces the existing rate
+                let subscription = Subscription::new(index, framing, interval);
+                match existing {
+                    Some(existing) => self.0[existing] = subscription,
+                    None => self.0.push(subscription),
+                }
+
+                Ok(SocketResponse::Subscribe(true))
+            }
+            SocketCommand::Unsubscribe => {
+                if let Some(existing) = existing {
+                    self.0.remove(existing);
+                }
+                Ok(SocketResponse::Unsubscribe(existing.is_some()))
+            }
+            command => Err(anyhow!("{command:} is not a subscription command")),
+        }
+    }
+
+    /// Encode every update that's due to be sent
+    pub fn poll(&mut self, controllers: &[ControllerHandle]) -> Vec<u8> {
+        let mut updates = vec![];
+        for subscription in self.0.iter_mut() {
+            let telemetry = Telemetry::capture(&controllers[subscription.index].state);
+            if let Some(telemetry) = subscription.poll(telemetry) {
+                updates.extend(
+                    subscription
+                        .framing
+                        .encode(SocketResponse::Telemetry(telemetry)),
+                );
+            }
+        }
+        updates
+    }
+}
@@ -0,0 +1,211 @@
+use std::{
+    ffi::CString,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
+use nix::unistd::{chown, getgrouplist, Gid, Group, Uid, User};
+use tokio::{
+    net::{unix::UCred, UnixListener, UnixStream},
+    sync::watch,
+    task::{spawn, JoinHandle},
+};
+use tokio_stream::{
+    wrappers::{UnixListenerStream, WatchStream},
+    StreamExt,
+};
+
+use crate::{
+    then::Then,
+    thread::{controller::ControllerHandle, print_thread_result, socket::SocketThread},
+};
+
+/// Parse a user name or numeric user ID
+pub fn uid_from_str(s: &str) -> Result<Uid> {
+    if let Ok(uid) = s.parse() {
+        return Ok(Uid::from_raw(uid));
+    }
+
+    User::from_name(s)?
+        .map(|user| user.uid)
+        .ok_or_else(|| anyhow!("No such user {s:}"))
+}
+
+/// Parse a group name or numeric group ID
+pub fn gid_from_str(s: &str) -> Result<Gid> {
+    if let Ok(gid) = s.parse() {
+        return Ok(Gid::from_raw(gid));
+    }
+
+    Group::from_name(s)?
+        .map(|group| group.gid)
+        .ok_or_else(|| anyhow!("No such group {s:}"))
+}
+
+/// Parse an octal file mode, ex. 0660
+pub fn mode_from_str(s: &str) -> Result<u32> {
+    Ok(u32::from_str_radix(s, 8)?)
+}
+
+/// Ownership and access control for the Unix socket
+#[derive(Debug, Clone)]
+pub struct UnixSocketOptions {
+    pub path: PathBuf,
+    pub owner: Option<Uid>,
+    pub group: Option<Gid>,
+    pub mode: u32,
+    /// Users permitted to connect, checked against the peer's credentials
+    pub allowed_users: Vec<Uid>,
+    /// Groups permitted to connect, matching the peer's primary or supplementary groups
+    pub allowed_groups: Vec<Gid>,
+}
+
+impl UnixSocketOptions {
+    /// Whether the connecting process may issue commands
+    ///
+    /// Root and the daemon's own user are always allowed.
+    /// If no allow lists are set, access is governed by the socket's file permissions alone.
+    pub fn allows(&self, cred: &UCred) -> bool {
+        let uid = Uid::from_raw(cred.uid());
+        if uid.is_root() || uid == Uid::effective() {
+            return true;
+        }
+
+        if self.allowed_users.is_empty() && self.allowed_groups.is_empty() {
+            return true;
+        }
+
+        if self.allowed_users.contains(&uid) {
+            return true;
+        }
+
+        let gid = Gid::from_raw(cred.gid());
+        if self.allowed_groups.contains(&gid) {
+            return true;
+        }
+
+        // Fall back to supplementary groups, which SO_PEERCRED doesn't carry
+        let groups = User::from_uid(uid)
+            .ok()
+            .flatten()
+            .and_then(|user| CString::new(user.name).ok())
+            .and_then(|name| getgrouplist(&name, gid).ok())
+            .unwrap_or_default();
+
+        groups.iter().any(|gid| self.allowed_groups.contains(gid))
+    }
+
+    fn bind(&self) -> Result<UnixListener> {
+        // Clear a socket left behind by a previous run that didn't exit cleanly
+        if let Ok(metadata) = std::fs::symlink_metadata(&self.path) {
+            if !metadata.file_type().is_socket() {
+                return Err(anyhow!("{:?} exists and is not a socket", self.path));
+            }
+            std::fs::remove_file(&self.path)?;
+        }
+
+        let listener = UnixListener::bind(&self.path)
+            .with_context(|| format!("Failed to bind {:?}", self.path))?;
+
+        if self.owner.is_some() || self.group.is_some() {
+            chown(&self.path, self.owner, self.group)?;
+        }
+
+        std::fs::set_permissions(&self.path, std::fs::Permissions::from_mode(self.mode))?;
+
+        Ok(listener)
+    }
+}
+
+#[derive(Debug)]
+pub struct UnixServerThread {
+    controllers: Vec<ControllerHandle>,
+    exit_rx: watch::Receiver<bool>,
+    options: UnixSocketOptions,
+    sockets: Vec<JoinHandle<()>>,
+}
+
+enum UnixServerEvent {
+    Connection(tokio::io::Result<UnixStream>),
+    RunningChanged(bool),
+}
+
+impl UnixServerThread {
+    pub fn new(
+        controllers: Vec<ControllerHandle>,
+        exit_rx: watch::Receiver<bool>,
+        options: UnixSocketOptions,
+    ) -> Self {
+        UnixServerThread {
+            controllers,
+            exit_rx,
+            options,
+            sockets: vec![],
+        }
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        let listener = UnixListenerStream::new(self.options.bind()?);
+
+        let exit_rx = self.exit_rx.clone();
+        let exit = WatchStream::new(self.exit_rx.clone());
+
+        info!("Server listening on {:?}", self.options.path);
+
+        let mut events = futures::stream_select!(
+            listener.map(UnixServerEvent::Connection),
+            exit.map(UnixServerEvent::RunningChanged),
+        );
+
+        while let Some(event) = events.next().await {
+            match event {
+                UnixServerEvent::Connection(stream) => {
+                    let stream = stream?;
+
+                    let cred = stream.peer_cred()?;
+                    if !self.options.allows(&cred) {
+                        warn!(
+                            "Rejected Unix connection from uid {} gid {} pid {:?}",
+                            cred.uid(),
+                            cred.gid(),
+                            cred.pid()
+                        );
+                        continue;
+                    }
+
+                    info!("Accepted Unix connection from uid {}", cred.uid());
+                    let controllers = self.controllers.clone();
+                    let exit_rx = exit_rx.clone();
+
+                    let join_handle = spawn(async move {
+                        SocketThread::new(controllers, exit_rx, stream)
+                            .run()
+                            .await
+                            .then(print_thread_result("SocketThread"))
+                            .ok();
+                    });
+
+                    self.sockets.push(join_handle);
+                }
+                UnixServerEvent::RunningChanged(running) => {
+                    if !running {
+                        info!("UnixServerThread received Exit event");
+                        break;
+                    }
+                }
+            }
+        }
+
+        for handle in self.sockets.into_iter() {
+            handle.await?;
+        }
+
+        if let Err(e) = std::fs::remove_file(&self.options.path) {
+            warn!("Failed to remove {:?}: {e:}", self.options.path);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use log::{error, info, warn};
+use parking_lot::Mutex;
+use sd_notify::NotifyState;
+use tokio::sync::watch;
+
+use crate::hid::{protocol::Request, state::HARDWARE, SharedHid, READ_TIMEOUT_MS};
+
+/// Time the main loop last handled an event
+#[derive(Debug)]
+pub struct Heartbeat(Mutex<Instant>);
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat(Mutex::new(Instant::now()))
+    }
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        *self.0.lock() = Instant::now();
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.0.lock().elapsed()
+    }
+}
+
+/// Time to wait for the main loop to finish a request before giving up on a restore,
+/// long enough for a read from an unresponsive device to time out and release the handle
+const RESTORE_LOCK_TIMEOUT: Duration = Duration::from_millis(2 * READ_TIMEOUT_MS as u64);
+
+/// Shared handles to each controller,
+/// used to restore hardware mode when the main loop can't
+#[derive(Clone, Default)]
+pub struct HardwareRestore {
+    hids: Arc<Mutex<Vec<Option<SharedHid>>>>,
+}
+
+impl HardwareRestore {
+    /// Register the primary handle of the given controller
+    pub fn set(&self, index: usize, hid: SharedHid) {
+        let mut hids = self.hids.lock();
+        if hids.len() <= index {
+            hids.resize_with(index + 1, || None);
+        }
+        hids[index] = Some(hid);
+    }
+
+    /// Put every controller back into hardware mode
+    ///
+    /// Each handle is only used once the main loop has finished its request in progress.
+    /// Requests are sent without waiting for a response,
+    /// since a stalled device may never answer.
+    ///
+    /// Returns false if any handle stayed busy, leaving its controller in software mode.
+    pub fn restore(&self) -> bool {
+        let hids = match self.hids.try_lock_for(RESTORE_LOCK_TIMEOUT) {
+            Some(hids) => hids,
+            None => {
+                error!("Failed to lock controller handles, hardware mode not restored");
+                return false;
+            }
+        };
+
+        let mut restored = true;
+
+        for (index, hid) in hids.iter().enumerate() {
+            let hid = match hid {
+                Some(hid) => hid,
+                None => {
+                    warn!("No handle for controller {index:}, hardware mode not restored");
+                    continue;
+                }
+            };
+
+            let mut hid = match hid.try_lock_for(RESTORE_LOCK_TIMEOUT) {
+                Some(hid) => hid,
+                None => {
+                    error!(
+                        "Controller {index:} is busy with a request, hardware mode not restored"
+                    );
+                    restored = false;
+                    continue;
+                }
+            };
+
+            match hid.send_unacknowledged(&Request::SetControllerState(HARDWARE)) {
+                Ok(()) => info!("Restored controller {index:} to hardware mode"),
+                Err(e) => error!("Failed to restore controller {index:} to hardware mode: {e:}"),
+            }
+        }
+
+        restored
+    }
+
+    /// Restore hardware mode and abort if any thread panics
+    ///
+    /// Aborting keeps a panicked task from leaving the daemon running
+    /// against controllers it no longer drives.
+    /// A handle held by the panicking thread itself is only released by unwinding,
+    /// so in that case the panic is left to unwind to [`Capellix::run`](crate::thread::capellix::Capellix::run),
+    /// which restores and aborts once it catches it.
+    pub fn install_panic_hook(&self) {
+        let restore = self.clone();
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            default_hook(info);
+            if restore.restore() {
+                std::process::abort();
+            }
+            warn!("Retrying hardware restore once the panic has unwound");
+        }));
+    }
+}
+
+/// Supervises the main loop from outside the async runtime,
+/// restoring hardware mode and exiting if it stops handling events
+pub struct WatchdogThread {
+    heartbeat: Arc<Heartbeat>,
+    restore: HardwareRestore,
+    exit_rx: watch::Receiver<bool>,
+    timeout: Duration,
+    systemd_notify: bool,
+}
+
+impl WatchdogThread {
+    pub fn new(
+        heartbeat: Arc<Heartbeat>,
+        restore: HardwareRestore,
+        exit_rx: watch::Receiver<bool>,
+        timeout: Duration,
+        systemd_notify: bool,
+    ) -> Self {
+        WatchdogThread {
+            heartbeat,
+            restore,
+            exit_rx,
+            timeout,
+            systemd_notify,
+        }
+    }
+
+    pub fn run(self) -> Result<()> {
+        let mut watchdog_usec = 0;
+        let systemd_interval =
+            if self.systemd_notify && sd_notify::watchdog_enabled(false, &mut watchdog_usec) {
+                let interval = Duration::from_micros(watchdog_usec) / 2;
+                info!("Pinging systemd watchdog every {interval:?}");
+                Some(interval)
+            } else {
+                None
+            };
+
+        let poll = systemd_interval
+            .unwrap_or(self.timeout)
+            .min(self.timeout / 4)
+            .min(Duration::from_secs(1));
+
+        while *self.exit_rx.borrow() {
+            let elapsed = self.heartbeat.elapsed();
+            if elapsed > self.timeout {
+                error!("Main loop stalled for {elapsed:?}, restoring hardware mode and exiting");
+                self.restore.restore();
+                std::process::exit(1);
+            }
+
+            // Only ping while the main loop is responsive, so systemd restarts a stalled daemon
+            if systemd_interval.is_some() {
+                sd_notify::notify(false, &[NotifyState::Watchdog])?;
+            }
+
+            std::thread::sleep(poll);
+        }
+
+        info!("WatchdogThread got exit event");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hid::{simulator::Simulator, state::SOFTWARE, Hid};
+
+    #[test]
+    fn restores_while_device_is_unresponsive() {
+        let simulator = Simulator::default();
+        let device = simulator.state();
+        let hid = Arc::new(Mutex::new(Hid::simulated(simulator)));
+        hid.lock()
+            .send(&Request::SetControllerState(SOFTWARE))
+            .unwrap();
+
+        let restore = HardwareRestore::default();
+        restore.set(0, hid.clone());
+
+        // The main loop blocks in a read the device never answers
+        device.lock().unresponsive = true;
+        let main_loop = {
+            let hid = hid.clone();
+            std::thread::spawn(move || hid.lock().send(&Request::GetSpeeds))
+        };
+        while !hid.is_locked() {
+            std::thread::yield_now();
+        }
+
+        assert!(restore.restore());
+        assert_eq!(device.lock().controller_state, HARDWARE);
+        assert!(main_loop.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn skips_missing_handles() {
+        let restore = HardwareRestore::default();
+        restore.set(
+            1,
+            Arc::new(Mutex::new(Hid::simulated(Simulator::default()))),
+        );
+        assert!(restore.restore());
+    }
+}
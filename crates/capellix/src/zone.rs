@@ -0,0 +1,257 @@
+use std::{fmt::Display, ops::Range, str::FromStr};
+
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+
+use crate::{hid::topology::Topology, thread::pump_target::Fan};
+
+pub const ZONE_CHANNEL: u8 = 0;
+pub const ZONE_RANGE: u8 = 1;
+pub const ZONE_LED: u8 = 2;
+
+/// Group of LEDs addressed by a zone command,
+/// with indices counted across the full frame in topology order
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Zone {
+    /// Every LED on a channel, ex. the pump ring or a single fan
+    Channel(Fan),
+    /// LEDs from the first to the last index, inclusive
+    Range { first: u16, last: u16 },
+    /// A single LED
+    Led(u16),
+}
+
+impl Zone {
+    /// Indices of the zone's LEDs, or None if it lies outside the topology
+    pub fn led_range(&self, topology: &Topology) -> Option<Range<usize>> {
+        let range = match *self {
+            Zone::Channel(fan) => {
+                let channel = u8::from(fan) as usize;
+                if channel >= topology.channel_count() {
+                    return None;
+                }
+                topology.led_range(channel)
+            }
+            Zone::Range { first, last } => first as usize..last as usize + 1,
+            Zone::Led(index) => index as usize..index as usize + 1,
+        };
+
+        if range.end > topology.led_count() {
+            return None;
+        }
+
+        Some(range)
+    }
+
+    /// Encode as `[kind, parameters...]`, with LED indices as LE u16
+    pub fn encode(&self) -> Vec<u8> {
+        match *self {
+            Zone::Channel(fan) => vec![ZONE_CHANNEL, u8::from(fan)],
+            Zone::Range { first, last } => [
+                &[ZONE_RANGE][..],
+                &first.to_le_bytes()[..],
+                &last.to_le_bytes()[..],
+            ]
+            .concat(),
+            Zone::Led(index) => [&[ZONE_LED][..], &index.to_le_bytes()[..]].concat(),
+        }
+    }
+}
+
+impl Display for Zone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Zone::Channel(Fan::Pump) => f.write_str("pump"),
+            Zone::Channel(Fan::Fan(idx)) => f.write_fmt(format_args!("fan{}", idx + 1)),
+            Zone::Range { first, last } => f.write_fmt(format_args!("{first:}-{last:}")),
+            Zone::Led(index) => index.fmt(f),
+        }
+    }
+}
+
+impl Serialize for Zone {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl FromStr for Zone {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match zone_str(s.trim()) {
+            Ok(("", zone)) => Ok(zone),
+            _ => Err(anyhow!("Invalid zone {s:?}")),
+        }
+    }
+}
+
+/// Parse a zone in the form `pump`, `fan<n>`, `<first>-<last>` or `<index>`
+pub fn zone_str(input: &str) -> nom::IResult<&str, Zone> {
+    nom::branch::alt((zone_channel_str, zone_range_str, zone_led_str))(input)
+}
+
+fn zone_channel_str(input: &str) -> nom::IResult<&str, Zone> {
+    nom::combinator::map(
+        nom::combinator::map_res(nom::character::complete::alphanumeric1, str::parse),
+        Zone::Channel,
+    )(input)
+}
+
+fn zone_range_str(input: &str) -> nom::IResult<&str, Zone> {
+    nom::combinator::map_res(
+        nom::sequence::separated_pair(
+            nom::character::complete::u16,
+            nom::character::complete::char('-'),
+            nom::character::complete::u16,
+        ),
+        |(first, last)| {
+            if first > last {
+                return Err(anyhow!("Zone range {first:}-{last:} is reversed"));
+            }
+            Ok(Zone::Range { first, last })
+        },
+    )(input)
+}
+
+fn zone_led_str(input: &str) -> nom::IResult<&str, Zone> {
+    nom::combinator::map(nom::character::complete::u16, Zone::Led)(input)
+}
+
+pub fn zone_bytes(input: &[u8]) -> nom::IResult<&[u8], Zone> {
+    let (input, kind) = nom::number::complete::u8(input)?;
+    match kind {
+        ZONE_CHANNEL => nom::combinator::map(
+            nom::combinator::map_res(nom::number::complete::u8, Fan::try_from),
+            Zone::Channel,
+        )(input),
+        ZONE_RANGE => nom::combinator::map_res(
+            nom::sequence::pair(nom::number::complete::le_u16, nom::number::complete::le_u16),
+            |(first, last)| {
+                if first > last {
+                    return Err(anyhow!("Zone range {first:}-{last:} is reversed"));
+                }
+                Ok(Zone::Range { first, last })
+            },
+        )(input),
+        ZONE_LED => nom::combinator::map(nom::number::complete::le_u16, Zone::Led)(input),
+        _ => Err(nom::Err::Error(nom::error::Error {
+            input,
+            code: nom::error::ErrorKind::Tag,
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compositor::{Compositor, Layer, LayerUpdate, Lighting};
+
+    const RED: [u8; 3] = [255, 0, 0];
+    const GREEN: [u8; 3] = [0, 255, 0];
+    const BLUE: [u8; 3] = [0, 0, 255];
+    const OFF: [u8; 3] = [0; 3];
+
+    /// A pump with 4 LEDs, a fan with 2 and a fan with 3
+    fn topology() -> Topology {
+        Topology::with_led_counts(&[4, 2, 3])
+    }
+
+    fn draw(compositor: &mut Compositor, lighting: Lighting) -> anyhow::Result<bool> {
+        compositor.apply(
+            LayerUpdate::Draw {
+                layer: Layer::default(),
+                owner: None,
+                lighting,
+            },
+            &topology(),
+            30.0,
+        )
+    }
+
+    #[test]
+    fn parses_zones() {
+        assert_eq!("pump".parse::<Zone>().unwrap(), Zone::Channel(Fan::Pump));
+        assert_eq!(
+            "3-7".parse::<Zone>().unwrap(),
+            Zone::Range { first: 3, last: 7 }
+        );
+        assert_eq!("5".parse::<Zone>().unwrap(), Zone::Led(5));
+        assert!("7-3".parse::<Zone>().is_err());
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        for zone in [
+            Zone::Channel(Fan::Pump),
+            Zone::Range { first: 3, last: 7 },
+            Zone::Led(5),
+        ] {
+            assert_eq!(zone_bytes(&zone.encode()).unwrap(), (&[][..], zone));
+        }
+    }
+
+    #[test]
+    fn maps_zones_to_leds() {
+        let topology = topology();
+        assert_eq!(Zone::Channel(Fan::Pump).led_range(&topology), Some(0..4));
+        assert_eq!(
+            Zone::Channel(Fan::try_from(2).unwrap()).led_range(&topology),
+            Some(6..9)
+        );
+        assert_eq!(
+            Zone::Range { first: 3, last: 5 }.led_range(&topology),
+            Some(3..6)
+        );
+        assert_eq!(Zone::Led(8).led_range(&topology), Some(8..9));
+    }
+
+    #[test]
+    fn rejects_zones_outside_topology() {
+        let topology = topology();
+        assert_eq!(
+            Zone::Channel(Fan::try_from(3).unwrap()).led_range(&topology),
+            None
+        );
+        assert_eq!(Zone::Range { first: 5, last: 9 }.led_range(&topology), None);
+        assert_eq!(Zone::Led(9).led_range(&topology), None);
+    }
+
+    #[test]
+    fn merges_zones_into_frame() {
+        let mut compositor = Compositor::default();
+        draw(&mut compositor, Lighting::Colors(vec![RED; 9])).unwrap();
+        draw(
+            &mut compositor,
+            Lighting::Zone(Zone::Channel(Fan::try_from(1).unwrap()), vec![GREEN]),
+        )
+        .unwrap();
+        draw(
+            &mut compositor,
+            Lighting::Zone(Zone::Range { first: 1, last: 3 }, vec![BLUE, GREEN]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            compositor.render(&topology(), 30.0),
+            vec![RED, BLUE, GREEN, BLUE, GREEN, GREEN, RED, RED, RED]
+        );
+    }
+
+    #[test]
+    fn leaves_undrawn_leds_unlit() {
+        let mut compositor = Compositor::default();
+        draw(&mut compositor, Lighting::Zone(Zone::Led(2), vec![RED])).unwrap();
+
+        assert_eq!(
+            compositor.render(&topology(), 30.0),
+            vec![OFF, OFF, RED, OFF, OFF, OFF, OFF, OFF, OFF]
+        );
+    }
+
+    #[test]
+    fn rejects_drawing_outside_topology() {
+        let mut compositor = Compositor::default();
+        assert!(draw(&mut compositor, Lighting::Zone(Zone::Led(9), vec![RED])).is_err());
+    }
+}
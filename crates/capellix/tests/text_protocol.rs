@@ -0,0 +1,104 @@
+//! Drives the text protocol of a simulated daemon through its Unix socket
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use capellix::thread::capellix::Capellix;
+use clap::StructOpt;
+
+/// Start the daemon on a background thread, returning the path of its socket once it's listening
+fn start_daemon() -> PathBuf {
+    let path = std::env::temp_dir().join(format!("capellix-test-{}.sock", std::process::id()));
+    std::fs::remove_file(&path).ok();
+
+    let capellix = Capellix::parse_from([
+        "capellix",
+        "--simulate",
+        "--listen-unix",
+        "--unix-socket-path",
+        path.to_str().unwrap(),
+    ]);
+    std::thread::spawn(move || capellix.run());
+
+    let start = Instant::now();
+    while !path.exists() {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "Daemon didn't start listening"
+        );
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    path
+}
+
+/// Text protocol client, sending a command per line and reading a line in reply
+struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl Client {
+    fn connect(path: &PathBuf) -> Self {
+        let writer = UnixStream::connect(path).unwrap();
+        writer
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        Client {
+            reader: BufReader::new(writer.try_clone().unwrap()),
+            writer,
+        }
+    }
+
+    fn request(&mut self, command: &str) -> String {
+        writeln!(self.writer, "{command:}").unwrap();
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        line.trim().to_string()
+    }
+
+    /// Repeat a request until its reply satisfies the predicate,
+    /// for state that's only updated on the next tick
+    fn poll(&mut self, command: &str, predicate: impl Fn(&str) -> bool) -> String {
+        let start = Instant::now();
+        loop {
+            let response = self.request(command);
+            if predicate(&response) || start.elapsed() > Duration::from_secs(5) {
+                return response;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+#[test]
+fn text_protocol() {
+    let path = start_daemon();
+    let mut client = Client::connect(&path);
+
+    assert_eq!(client.request("get-firmware"), "2.10.219");
+
+    assert_eq!(client.request("set-colors 255 0 0"), "true");
+    let colors = client.poll("get-colors", |colors| colors.starts_with("#ff0000"));
+    assert!(colors.starts_with("#ff0000 #000000"), "{colors:}");
+
+    assert_eq!(client.request("set-fan-target pump 80"), "true");
+    let channel = client.poll("get-channel pump", |channel| channel.ends_with("target 80"));
+    assert!(channel.ends_with("target 80"), "{channel:}");
+
+    assert_eq!(
+        client.request("get-nothing"),
+        "error: Invalid socket command \"get-nothing\""
+    );
+
+    // Connections are independent, so a second client is served alongside the first
+    let mut second = Client::connect(&path);
+    assert_eq!(second.request("get-firmware"), "2.10.219");
+    assert_eq!(client.request("get-firmware"), "2.10.219");
+
+    std::fs::remove_file(&path).ok();
+}